@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use actix_web::HttpRequest;
+use log::error;
+use serde::{Serialize, Deserialize};
+
+use crate::logging;
+use crate::util::{lock, now, rfc3339};
+use crate::Options;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Push,
+    /// A tag was created or moved to another manifest
+    Tag,
+    Delete,
+}
+
+/// A change to the manifests or tags of a repository
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub time: String,
+    pub request_id: String,
+    pub remote: String,
+    pub user: Option<String>,
+    pub action: Action,
+    pub repository: String,
+    pub reference: String,
+    pub old_digest: Option<String>,
+    pub new_digest: Option<String>,
+}
+
+impl Entry {
+    fn new(action: Action, repository: &str, reference: &str) -> Entry {
+        Entry {
+            time: rfc3339(now()),
+            request_id: String::new(),
+            remote: String::new(),
+            user: None,
+            action,
+            repository: repository.to_string(),
+            reference: reference.to_string(),
+            old_digest: None,
+            new_digest: None,
+        }
+    }
+
+    /// A change made through the registry API
+    pub fn request(req: &HttpRequest, opts: &Options, action: Action, repository: &str, reference: &str) -> Entry {
+        Entry {
+            request_id: logging::request_id(req),
+            remote: logging::remote(req),
+            user: logging::user(req, opts),
+            ..Entry::new(action, repository, reference)
+        }
+    }
+
+    /// A change made with the command line tools, by whoever is logged in
+    pub fn cli(action: Action, repository: &str, reference: &str) -> Entry {
+        Entry {
+            request_id: uuid::Uuid::new_v4().to_string(),
+            remote: String::from("cli"),
+            user: std::env::var("USER").ok(),
+            ..Entry::new(action, repository, reference)
+        }
+    }
+
+    pub fn digests(self, old: Option<String>, new: Option<String>) -> Entry {
+        Entry { old_digest: old, new_digest: new, ..self }
+    }
+}
+
+/// When each tag and digest of each repository was last pushed or moved, as
+/// far as the log has been read
+#[derive(Default)]
+struct Index {
+    /// Bytes of the log read so far
+    read: u64,
+    changes: HashMap<String, HashMap<String, String>>,
+}
+
+/// Append-only log of every push, tag move and delete, one JSON object per
+/// line. Unlike the access log it isn't subject to the log level.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Mutex<Index>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> std::io::Result<AuditLog> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { path: path.to_path_buf(), file: Mutex::new(file), index: Mutex::default() })
+    }
+
+    /// Opens `--audit-log`, or `audit.log` in the data directory
+    pub fn from_options(opts: &Options) -> std::io::Result<AuditLog> {
+        match &opts.audit_log {
+            Some(path) => AuditLog::open(Path::new(path)),
+            None => AuditLog::open(&Path::new(&opts.data_dir).join("audit.log")),
+        }
+    }
+
+    /// Appends an entry. The change it records has already happened, so
+    /// failing to write it is logged rather than reported to the client.
+    pub fn record(&self, entry: &Entry) {
+        let result = serde_json::to_string(entry)
+            .map_err(std::io::Error::other)
+            .and_then(|line| lock(&self.file).write_all(format!("{}\n", line).as_bytes()));
+        if let Err(e) = result {
+            error!("Writing to the audit log failed: {}: {:?}", e, entry);
+        }
+    }
+
+    /// When each tag and digest of a repository was last pushed or moved,
+    /// read back from the log. Only lines appended since the last call are
+    /// read, including those written by the CLI. Lines that don't parse are
+    /// skipped.
+    pub fn last_changes(&self, repository: &str) -> std::io::Result<HashMap<String, String>> {
+        let mut index = lock(&self.index);
+        let mut file = File::open(&self.path)?;
+        // Start over if the log was truncated or replaced by a shorter one
+        if file.metadata()?.len() < index.read {
+            *index = Index::default();
+        }
+        file.seek(SeekFrom::Start(index.read))?;
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            // A line without its newline is still being written
+            if read == 0 || !line.ends_with('\n') {
+                break
+            }
+            index.read += read as u64;
+            if let Ok(entry) = serde_json::from_str::<Entry>(&line) {
+                if entry.action != Action::Delete {
+                    index.changes.entry(entry.repository).or_default().insert(entry.reference, entry.time);
+                }
+            }
+        }
+        Ok(index.changes.get(repository).cloned().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_appends_json_lines() {
+        let path = std::path::PathBuf::from(format!("/tmp/blobert-test/{}/audit.log", uuid::Uuid::new_v4()));
+        let push = Entry::cli(Action::Push, "app", "v1").digests(None, Some(String::from("sha256:aa")));
+        let tag = Entry::cli(Action::Tag, "app", "v1")
+            .digests(Some(String::from("sha256:aa")), Some(String::from("sha256:bb")));
+        AuditLog::open(&path).unwrap().record(&push);
+        // Reopening appends rather than truncating
+        AuditLog::open(&path).unwrap().record(&tag);
+
+        let entries: Vec<Entry> = std::fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries, vec![push, tag.clone()]);
+        assert!(std::fs::read_to_string(&path).unwrap().contains("\"old_digest\":\"sha256:aa\""));
+        let log = AuditLog::open(&path).unwrap();
+        let changes = log.last_changes("app").unwrap();
+        assert_eq!(changes.get("v1"), Some(&tag.time));
+
+        // Entries appended later, by this process or another, are picked up
+        let push = Entry::cli(Action::Push, "app", "v2");
+        AuditLog::open(&path).unwrap().record(&push);
+        log.record(&Entry::cli(Action::Push, "other", "v1"));
+        let changes = log.last_changes("app").unwrap();
+        assert_eq!((changes.get("v1"), changes.get("v2")), (Some(&tag.time), Some(&push.time)));
+    }
+}
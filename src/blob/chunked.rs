@@ -0,0 +1,410 @@
+use crate::blob::fs::{check_uploads, files, unknown, FileUpload};
+use crate::blob::{BlobStore, BlobInfo, BlobStream, StorageStats, UploadWriter};
+use crate::error;
+use crate::error::RegistryError;
+use crate::fsck::{self, Kind, Problem};
+use crate::types::{Digest, Hasher, UploadId};
+
+use fastcdc::v2020::StreamCDC;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Chunk sizes for FastCDC. Smaller chunks find more duplicates between
+/// layers, but every chunk is a file of its own.
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// Locked shared while chunks are added and exclusively while they are
+/// swept, so commits run side by side but a sweep can't remove the chunks of
+/// a blob being committed. `gc` sweeps from a process of its own, so this is
+/// a lock on a file in the data dir rather than in memory.
+const SWEEP_LOCK: &str = "chunks.lock";
+
+/// A piece of a blob, named after the SHA-256 of its content
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Chunk {
+    sha256: String,
+    size: u64,
+}
+
+/// The chunks a blob is put together from, in order
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Recipe {
+    size: u64,
+    chunks: Vec<Chunk>,
+}
+
+impl Recipe {
+    /// The part of each chunk needed to read `len` bytes from `start`, as
+    /// the chunk, the offset into it and the number of bytes to read
+    fn pieces(&self, start: u64, len: u64) -> VecDeque<(String, u64, u64)> {
+        let (end, mut pos) = (start + len, 0);
+        let mut pieces = VecDeque::new();
+        for chunk in &self.chunks {
+            let (from, to) = (start.max(pos), end.min(pos + chunk.size));
+            if from < to {
+                pieces.push_back((chunk.sha256.clone(), from - pos, to - from));
+            }
+            pos += chunk.size;
+        }
+        pieces
+    }
+}
+
+/// Stores blobs split into content-defined chunks, so layers that differ in
+/// a few files share the chunks of everything else. Blobs are kept as
+/// recipes listing their chunks under
+/// `recipes/<algorithm>/<first two hex digits>/<hex>`, chunks under
+/// `chunks/<first two hex digits>/<sha256>`. Deleting a blob only removes
+/// its recipe, `sweep` removes the chunks nothing refers to anymore.
+pub struct Chunked {
+    dir: PathBuf,
+    buf_size: usize,
+}
+
+impl Chunked {
+    pub fn new(dir: &str, buf_size: usize) -> Result<Chunked, RegistryError> {
+        let dir = PathBuf::from(dir);
+        debug!("Creating data directory: {}", dir.display());
+        for sub in ["upload", "recipes", "chunks"] {
+            std::fs::create_dir_all(dir.join(sub)).map_err(unknown)?;
+        }
+        Ok(Chunked { dir, buf_size })
+    }
+
+    fn get_upload_path(&self, id: &UploadId) -> PathBuf {
+        self.dir.join("upload").join(id.as_str())
+    }
+
+    /// Opens the [`SWEEP_LOCK`] file. Its lock is released when it is closed.
+    fn sweep_lock(&self) -> Result<File, RegistryError> {
+        OpenOptions::new().create(true).truncate(false).write(true).open(self.dir.join(SWEEP_LOCK)).map_err(unknown)
+    }
+
+    fn get_recipe_path(&self, digest: &Digest) -> PathBuf {
+        let encoded = digest.encoded();
+        self.dir.join("recipes").join(digest.algorithm().name()).join(&encoded[..2]).join(encoded)
+    }
+
+    fn read_recipe(&self, digest: &Digest) -> Result<Recipe, RegistryError> {
+        let data = std::fs::read(self.get_recipe_path(digest)).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => RegistryError::from_err(error::BLOB_UNKNOWN, Box::new(e)),
+            _ => unknown(e),
+        })?;
+        serde_json::from_slice(&data).map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))
+    }
+
+    /// Every recipe and the digest it belongs to. A recipe that can't be read
+    /// is an error, as the chunks it refers to would otherwise be swept.
+    fn recipes(&self) -> Result<Vec<(Digest, Recipe)>, RegistryError> {
+        let mut recipes = Vec::new();
+        for digest in self.list_blobs()? {
+            let recipe = self.read_recipe(&digest)?;
+            recipes.push((digest, recipe));
+        }
+        Ok(recipes)
+    }
+
+    /// Stores a chunk unless an identical one is stored already
+    fn put_chunk(&self, sha256: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = chunk_path(&self.dir, sha256);
+        if path.exists() {
+            return Ok(())
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_atomically(&path, data)
+    }
+}
+
+fn chunk_path(dir: &Path, sha256: &str) -> PathBuf {
+    dir.join("chunks").join(&sha256[..2]).join(sha256)
+}
+
+/// Whether a file under `chunks` is named like a chunk, rather than being a
+/// temporary file of `write_atomically` or something else
+fn is_chunk(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Writes to a temporary file next to `path` first, so readers never see a
+/// partly written file
+fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Reads the pieces of a range of a blob one chunk file after the other
+struct ChunkReader {
+    dir: PathBuf,
+    pieces: VecDeque<(String, u64, u64)>,
+    current: Option<(String, std::io::Take<File>)>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some((sha256, chunk)) = &mut self.current {
+                let read = chunk.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read)
+                }
+                if chunk.limit() > 0 {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof,
+                        format!("chunk {} is shorter than its recipe says", sha256)))
+                }
+                self.current = None;
+            }
+            match self.pieces.pop_front() {
+                None => return Ok(0),
+                Some((sha256, offset, len)) => {
+                    let mut file = File::open(chunk_path(&self.dir, &sha256))?;
+                    file.seek(SeekFrom::Start(offset))?;
+                    self.current = Some((sha256, file.take(len)));
+                },
+            }
+        }
+    }
+}
+
+impl BlobStore for Chunked {
+    fn get_blob(&self, digest: &Digest) -> Result<BlobStream, RegistryError> {
+        let size = self.read_recipe(digest)?.size;
+        self.get_blob_range(digest, 0, size)
+    }
+
+    fn get_blob_range(&self, digest: &Digest, start: u64, len: u64) -> Result<BlobStream, RegistryError> {
+        let recipe = self.read_recipe(digest)?;
+        let reader = ChunkReader { dir: self.dir.clone(), pieces: recipe.pieces(start, len), current: None };
+        Ok(BlobStream::new(Box::new(reader), self.buf_size))
+    }
+
+    fn stat_blob(&self, digest: &Digest) -> Result<BlobInfo, RegistryError> {
+        Ok(BlobInfo { digest: digest.clone(), size: self.read_recipe(digest)?.size })
+    }
+
+    fn upload_writer(&self, id: &UploadId) -> Result<Box<dyn UploadWriter>, RegistryError> {
+        let path = self.get_upload_path(id);
+        debug!("Opening upload temp file at {}", path.display());
+        let file = OpenOptions::new().create(true).append(true).open(&path).map_err(unknown)?;
+        Ok(Box::new(FileUpload { file }))
+    }
+
+    fn commit(&self, id: &UploadId, digest: &Digest) -> Result<(), RegistryError> {
+        let src = self.get_upload_path(id);
+        let file = File::open(&src).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => RegistryError::from_err(error::BLOB_UPLOAD_UNKNOWN, Box::new(e)),
+            _ => unknown(e),
+        })?;
+        debug!("Splitting {} into chunks", src.display());
+        let lock = self.sweep_lock()?;
+        lock.lock_shared().map_err(unknown)?;
+        let mut recipe = Recipe::default();
+        for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let chunk = chunk.map_err(|e| unknown(e.into()))?;
+            let sha256 = Digest::sha256(&chunk.data).encoded().to_string();
+            self.put_chunk(&sha256, &chunk.data).map_err(unknown)?;
+            recipe.size += chunk.length as u64;
+            recipe.chunks.push(Chunk { sha256, size: chunk.length as u64 });
+        }
+        let path = self.get_recipe_path(digest);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(unknown)?;
+        }
+        let data = serde_json::to_vec(&recipe).map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))?;
+        write_atomically(&path, &data).map_err(unknown)?;
+        std::fs::remove_file(&src).map_err(unknown)
+    }
+
+    fn cancel_upload(&self, id: &UploadId) -> Result<(), RegistryError> {
+        match std::fs::remove_file(self.get_upload_path(id)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(unknown(e)),
+            _ => Ok(())
+        }
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> Result<(), RegistryError> {
+        std::fs::remove_file(self.get_recipe_path(digest)).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => RegistryError::from_err(error::BLOB_UNKNOWN, Box::new(e)),
+            _ => unknown(e),
+        })
+    }
+
+    fn list_blobs(&self) -> Result<Vec<Digest>, RegistryError> {
+        let recipes = self.dir.join("recipes");
+        let mut blobs: Vec<Digest> = files(&recipes, 3)?.iter()
+            .filter_map(|path| {
+                let relative = path.strip_prefix(&recipes).ok()?;
+                let name = |i| relative.iter().nth(i).unwrap_or_default().to_string_lossy();
+                Digest::parse(&format!("{}:{}", name(0), name(2))).ok()
+            })
+            .collect();
+        blobs.sort();
+        Ok(blobs)
+    }
+
+    fn sweep(&self) -> Result<u64, RegistryError> {
+        let lock = self.sweep_lock()?;
+        lock.lock().map_err(unknown)?;
+        let referenced: HashSet<String> = self.recipes()?.into_iter()
+            .flat_map(|(_, recipe)| recipe.chunks.into_iter().map(|c| c.sha256))
+            .collect();
+        let mut freed = 0;
+        for path in files(&self.dir.join("chunks"), 2)? {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            // Leaves anything that isn't a chunk for fsck to report
+            if !is_chunk(&path) || referenced.contains(&name) {
+                continue
+            }
+            debug!("Removing unused chunk {}", name);
+            freed += std::fs::metadata(&path).map_err(unknown)?.len();
+            std::fs::remove_file(&path).map_err(unknown)?;
+        }
+        Ok(freed)
+    }
+
+    fn stats(&self) -> Result<StorageStats, RegistryError> {
+        let mut stats = StorageStats::default();
+        for (_, recipe) in self.recipes()? {
+            stats.blobs += 1;
+            stats.logical_bytes += recipe.size;
+        }
+        // Chunks being written are left out until they are renamed into place
+        for path in files(&self.dir.join("chunks"), 2)?.into_iter().filter(|path| is_chunk(path)) {
+            stats.chunks += 1;
+            stats.stored_bytes += std::fs::metadata(&path).map_err(unknown)?.len();
+        }
+        Ok(stats)
+    }
+
+    fn check(&self, repair: bool) -> Result<Vec<Problem>, RegistryError> {
+        let mut problems = Vec::new();
+        for digest in self.list_blobs()? {
+            let mut hasher = Hasher::new(digest.algorithm());
+            let hashed = self.get_blob(&digest)
+                .and_then(|blob| fsck::hash_reader(blob.into_reader(), &mut hasher).map_err(unknown));
+            let mut problem = match hashed {
+                Err(e) => Problem::new(Kind::CorruptBlob, &digest, format!("can't be read: {}", e)),
+                Ok(()) => match hasher.finish() {
+                    actual if actual == digest => continue,
+                    actual => Problem::new(Kind::CorruptBlob, &digest, format!("hashes to {}", actual)),
+                },
+            };
+            // The chunks are probably shared, so only the recipe is set aside
+            if repair {
+                fsck::quarantine(&self.dir, &self.get_recipe_path(&digest), &mut problem)?;
+            }
+            problems.push(problem);
+        }
+        problems.extend(check_uploads(&self.dir, repair)?);
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(store: &Chunked, data: &[u8]) -> Digest {
+        let digest = Digest::sha256(data);
+        let id = UploadId::new();
+        let mut writer = store.upload_writer(&id).unwrap();
+        writer.write(data).unwrap();
+        writer.finish().unwrap();
+        store.commit(&id, &digest).unwrap();
+        digest
+    }
+
+    fn read(stream: BlobStream) -> Vec<u8> {
+        let mut data = Vec::new();
+        stream.into_reader().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    /// Bytes that don't repeat, so chunk boundaries depend on the content
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn it_shares_chunks_between_similar_blobs() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let store = Chunked::new(&test_path, 1024).unwrap();
+        let base = noise(2 * 1024 * 1024, 1);
+        // The same data with a few bytes changed in the middle
+        let mut changed = base.clone();
+        changed.splice(1024 * 1024..1024 * 1024 + 10, noise(100, 2));
+        let (first, second) = (put(&store, &base), put(&store, &changed));
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.blobs, 2);
+        assert_eq!(stats.logical_bytes, (base.len() + changed.len()) as u64);
+        assert!(stats.stored_bytes < base.len() as u64 * 6 / 5);
+        assert!(stats.dedup_ratio() > 1.6);
+
+        assert_eq!(read(store.get_blob(&second).unwrap()), changed);
+        assert_eq!(store.stat_blob(&second).unwrap().size, changed.len() as u64);
+        let (start, len) = (300 * 1024 + 7, 500 * 1024);
+        let range = read(store.get_blob_range(&first, start, len).unwrap());
+        assert_eq!(range, &base[start as usize..(start + len) as usize]);
+        assert!(store.check(false).unwrap().is_empty());
+
+        // Only the chunks nothing else refers to go away with a blob
+        store.delete_blob(&second).unwrap();
+        assert!(store.sweep().unwrap() > 0);
+        assert_eq!(store.sweep().unwrap(), 0);
+        assert_eq!(read(store.get_blob(&first).unwrap()), base);
+        store.delete_blob(&first).unwrap();
+        store.sweep().unwrap();
+        assert_eq!(store.stats().unwrap().chunks, 0);
+    }
+
+    #[test]
+    fn it_waits_for_sweeps_of_other_processes() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let store = Chunked::new(&test_path, 1024).unwrap();
+        let (id, digest) = (UploadId::new(), Digest::sha256(b"data"));
+        let mut writer = store.upload_writer(&id).unwrap();
+        writer.write(b"data").unwrap();
+        writer.finish().unwrap();
+
+        // What `blobert gc` holds while it sweeps
+        let gc = store.sweep_lock().unwrap();
+        gc.lock().unwrap();
+        let committing = std::thread::spawn(move || store.commit(&id, &digest).map(|_| store));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(!committing.is_finished());
+        gc.unlock().unwrap();
+        let store = committing.join().unwrap().unwrap();
+        assert_eq!(read(store.get_blob(&Digest::sha256(b"data")).unwrap()), b"data");
+    }
+
+    #[test]
+    fn it_leaves_chunks_being_written_out_of_the_stats() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let store = Chunked::new(&test_path, 1024).unwrap();
+        put(&store, b"data");
+        let chunk = Digest::sha256(b"data").encoded().to_string();
+        let tmp = chunk_path(&store.dir, &chunk).with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        std::fs::write(&tmp, b"partly written").unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.chunks, 1);
+        assert_eq!(stats.stored_bytes, 4);
+    }
+}
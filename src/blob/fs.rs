@@ -0,0 +1,444 @@
+use crate::blob::{BlobStore, BlobInfo, BlobStream, UploadWriter};
+use crate::error;
+use crate::error::RegistryError;
+use crate::fsck::{self, Kind, Problem};
+use crate::types::{Digest, Hasher, UploadId};
+
+use log::debug;
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// Stores blobs as files in a directory on the local filesystem, laid out
+/// as `blobs/<algorithm>/<first two hex digits>/<hex>` so no single directory
+/// grows too large. Compressed layers get a `.zst` extension.
+pub struct Filesystem {
+    dir: PathBuf,
+    buf_size: usize,
+    compress_layers: bool,
+}
+
+/// Extension of blobs stored compressed with zstd
+const COMPRESSED_EXTENSION: &str = "zst";
+
+/// Trades a little speed for size, layers are compressed once but kept for long
+const COMPRESSION_LEVEL: i32 = 9;
+
+impl Filesystem {
+    pub fn new(dir: &str, buf_size: usize) -> Result<Filesystem, RegistryError> {
+        let dir = PathBuf::from(dir);
+        debug!("Creating data directory: {}", dir.display());
+        for sub in ["upload", "blobs"] {
+            if let Err(e) = std::fs::create_dir_all(dir.join(sub)) {
+                return Err(unknown(e))
+            }
+        }
+        if let Some(old) = flat_blobs(&dir)?.first() {
+            return Err(RegistryError::from_err(error::UNKNOWN_ERROR, format!(
+                "{} uses the old flat blob layout (found {}), run `blobert migrate blobs` first",
+                dir.display(), old.display()).into()))
+        }
+        Ok(Filesystem { dir, buf_size, compress_layers: false })
+    }
+
+    /// Stores uploads that are uncompressed tar archives compressed with
+    /// zstd, and decompresses them again when they are read
+    pub fn compress_layers(mut self, compress_layers: bool) -> Filesystem {
+        self.compress_layers = compress_layers;
+        self
+    }
+
+    fn get_upload_path(&self, id: &UploadId) -> PathBuf {
+        let mut path = PathBuf::from(&self.dir);
+        path.push("upload");
+        path.push(id.as_str());
+        path
+    }
+
+    fn get_blob_path(&self, digest: &Digest) -> PathBuf {
+        let encoded = digest.encoded();
+        let mut path = PathBuf::from(&self.dir);
+        path.push("blobs");
+        path.push(digest.algorithm().name());
+        path.push(&encoded[..2]);
+        path.push(encoded);
+        path
+    }
+
+    fn get_compressed_path(&self, digest: &Digest) -> PathBuf {
+        self.get_blob_path(digest).with_extension(COMPRESSED_EXTENSION)
+    }
+
+    /// Compresses an upload into the blob path of `digest`, replacing an
+    /// uncompressed copy of the blob if there is one
+    fn commit_compressed(&self, src: &Path, digest: &Digest) -> Result<(), RegistryError> {
+        let dest = self.get_compressed_path(digest);
+        let tmp = dest.with_extension("zst.tmp");
+        debug!("Compressing {} to {}", src.display(), dest.display());
+        let mut input = File::open(src).map_err(unknown)?;
+        let size = input.metadata().map_err(unknown)?.len();
+        let mut encoder = zstd::Encoder::new(File::create(&tmp).map_err(unknown)?, COMPRESSION_LEVEL)
+            .map_err(unknown)?;
+        // Lets stat_blob read the size from the frame header
+        encoder.set_pledged_src_size(Some(size)).map_err(unknown)?;
+        encoder.include_contentsize(true).map_err(unknown)?;
+        std::io::copy(&mut input, &mut encoder).map_err(unknown)?;
+        encoder.finish().and_then(|file| file.sync_all()).map_err(unknown)?;
+        std::fs::rename(&tmp, &dest).map_err(unknown)?;
+        remove_if_exists(&self.get_blob_path(digest)).map_err(unknown)?;
+        std::fs::remove_file(src).map_err(unknown)
+    }
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(())
+    }
+}
+
+/// Whether the file starts with a POSIX tar header. Compressed layers start
+/// with their compression format's magic bytes instead.
+fn is_tar(path: &Path) -> std::io::Result<bool> {
+    let mut header = [0; 512];
+    let mut file = File::open(path)?;
+    match file.read_exact(&mut header) {
+        Ok(()) => Ok(&header[257..262] == b"ustar"),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The original size of a blob stored compressed, from the frame header
+fn decompressed_size(path: &Path) -> std::io::Result<u64> {
+    // The longest a zstd frame header can be
+    let mut header = Vec::with_capacity(18);
+    File::open(path)?.take(18).read_to_end(&mut header)?;
+    match zstd::zstd_safe::get_frame_content_size(&header) {
+        Ok(Some(size)) => Ok(size),
+        // Only frames written without a size get here, count the bytes
+        _ => std::io::copy(&mut zstd::Decoder::new(File::open(path)?)?, &mut std::io::sink()),
+    }
+}
+
+/// Opens a blob file, decompressing it if it was stored compressed
+fn open_blob(path: &Path) -> std::io::Result<Box<dyn Read + Send>> {
+    match path.extension().is_some_and(|e| e == COMPRESSED_EXTENSION) {
+        true => Ok(Box::new(zstd::Decoder::new(File::open(path)?)?)),
+        false => Ok(Box::new(File::open(path)?)),
+    }
+}
+
+/// Blobs stored directly in `blobs/` under their full digest, the way they
+/// were before blobs were sharded by algorithm and prefix
+fn flat_blobs(dir: &Path) -> Result<Vec<PathBuf>, RegistryError> {
+    let entries = std::fs::read_dir(dir.join("blobs"))
+        .map_err(unknown)?;
+    Ok(entries.flatten()
+        .filter(|e| Digest::parse(&e.file_name().to_string_lossy()).is_ok())
+        .map(|e| e.path())
+        .collect())
+}
+
+/// Moves blobs from the old flat layout into the sharded one. Each blob is
+/// renamed on its own, so an interrupted migration can simply be run again.
+/// Returns the number of blobs moved.
+pub fn migrate_layout(dir: &str) -> Result<usize, RegistryError> {
+    let dir = PathBuf::from(dir);
+    std::fs::create_dir_all(dir.join("blobs")).map_err(unknown)?;
+    let old = flat_blobs(&dir)?;
+    let store = Filesystem { dir, buf_size: 0, compress_layers: false };
+    for path in &old {
+        let digest = Digest::parse(&path.file_name().unwrap_or_default().to_string_lossy())?;
+        let dest = store.get_blob_path(&digest);
+        debug!("Moving {} to {}", path.display(), dest.display());
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent).map_err(unknown)?;
+        }
+        std::fs::rename(path, dest).map_err(unknown)?;
+    }
+    Ok(old.len())
+}
+
+pub(super) fn unknown(e: std::io::Error) -> RegistryError {
+    RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e))
+}
+
+/// Maps a failed filesystem operation on a blob to a registry error
+fn blob_error(e: std::io::Error) -> RegistryError {
+    match e.kind() {
+        std::io::ErrorKind::NotFound =>
+            RegistryError::from_err(error::BLOB_UNKNOWN, Box::new(e)),
+        _ => unknown(e)
+    }
+}
+
+/// An upload appended to a file, also used by the chunked store
+pub(super) struct FileUpload {
+    pub(super) file: File,
+}
+
+impl UploadWriter for FileUpload {
+    fn write(&mut self, data: &[u8]) -> Result<(), RegistryError> {
+        let len = self.file.metadata().map_err(unknown)?.len();
+        self.file.write_all(data).map_err(|e| {
+            // Drop whatever part of the data made it to disk
+            let _ = self.file.set_len(len);
+            unknown(e)
+        })
+    }
+
+    fn finish(self: Box<Self>) -> Result<u64, RegistryError> {
+        self.file.sync_data().map_err(unknown)?;
+        Ok(self.file.metadata().map_err(unknown)?.len())
+    }
+}
+
+impl BlobStore for Filesystem {
+    fn get_blob(&self, digest: &Digest) -> Result<BlobStream, RegistryError> {
+        let path = self.get_blob_path(digest);
+        debug!("Opening blob file {}", path.display());
+        let reader = match open_blob(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+                open_blob(&self.get_compressed_path(digest)).map_err(blob_error)?,
+            other => other.map_err(blob_error)?,
+        };
+        Ok(BlobStream::new(reader, self.buf_size))
+    }
+
+    fn get_blob_range(&self, digest: &Digest, start: u64, len: u64) -> Result<BlobStream, RegistryError> {
+        // Compressed blobs can't seek, they are read up to `start` instead
+        let mut file = match File::open(self.get_blob_path(digest)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+                return self.get_blob(digest)?.slice(start, len).map_err(blob_error),
+            other => other.map_err(blob_error)?,
+        };
+        file.seek(SeekFrom::Start(start)).map_err(blob_error)?;
+        Ok(BlobStream::new(Box::new(file.take(len)), self.buf_size))
+    }
+
+    fn stat_blob(&self, digest: &Digest) -> Result<BlobInfo, RegistryError> {
+        let size = match std::fs::metadata(self.get_blob_path(digest)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+                decompressed_size(&self.get_compressed_path(digest)).map_err(blob_error)?,
+            other => other.map_err(blob_error)?.len(),
+        };
+        Ok(BlobInfo { digest: digest.clone(), size })
+    }
+
+    fn upload_writer(&self, id: &UploadId) -> Result<Box<dyn UploadWriter>, RegistryError> {
+        let path = self.get_upload_path(id);
+        debug!("Opening upload temp file at {}", path.display());
+        match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => Ok(Box::new(FileUpload { file })),
+            Err(e) => Err(unknown(e))
+        }
+    }
+
+    fn commit(&self, id: &UploadId, digest: &Digest) -> Result<(), RegistryError> {
+        let src = self.get_upload_path(id);
+        let dest = self.get_blob_path(digest);
+        debug!("Moving {} to {}", src.display(), dest.display());
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(unknown)?;
+        }
+        let upload_error = |e: std::io::Error| match e.kind() {
+            std::io::ErrorKind::NotFound =>
+                RegistryError::from_err(error::BLOB_UPLOAD_UNKNOWN, Box::new(e)),
+            _ => unknown(e)
+        };
+        if self.compress_layers && is_tar(&src).map_err(upload_error)? {
+            return self.commit_compressed(&src, digest)
+        }
+        std::fs::rename(src, dest).map_err(upload_error)?;
+        remove_if_exists(&self.get_compressed_path(digest))
+            .map_err(unknown)
+    }
+
+    fn cancel_upload(&self, id: &UploadId) -> Result<(), RegistryError> {
+        remove_if_exists(&self.get_upload_path(id))
+            .map_err(unknown)
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> Result<(), RegistryError> {
+        match std::fs::remove_file(self.get_blob_path(digest)) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound =>
+                std::fs::remove_file(self.get_compressed_path(digest)).map_err(blob_error),
+            other => other.map_err(blob_error),
+        }
+    }
+
+    fn list_blobs(&self) -> Result<Vec<Digest>, RegistryError> {
+        let read_dir = |path: &Path| std::fs::read_dir(path)
+            .map(|dir| dir.flatten().collect::<Vec<_>>())
+            .map_err(unknown);
+        let mut blobs = Vec::new();
+        for algorithm in read_dir(&self.dir.join("blobs"))? {
+            if !algorithm.path().is_dir() {
+                continue
+            }
+            for shard in read_dir(&algorithm.path())? {
+                for blob in read_dir(&shard.path())? {
+                    let name = blob.file_name().to_string_lossy().to_string();
+                    let encoded = name.strip_suffix(".zst").unwrap_or(&name);
+                    let digest = format!("{}:{}", algorithm.file_name().to_string_lossy(), encoded);
+                    if let Ok(digest) = Digest::parse(&digest) {
+                        blobs.push(digest);
+                    }
+                }
+            }
+        }
+        blobs.sort();
+        Ok(blobs)
+    }
+
+    fn check(&self, repair: bool) -> Result<Vec<Problem>, RegistryError> {
+        let mut problems = Vec::new();
+        for path in files(&self.dir.join("blobs"), 3)? {
+            let relative = path.strip_prefix(&self.dir).unwrap_or(&path);
+            let name = |i| relative.iter().nth(i).unwrap_or_default().to_string_lossy();
+            let file_name = name(3);
+            let encoded = file_name.strip_suffix(".zst").unwrap_or(&file_name);
+            let digest = Digest::parse(&format!("{}:{}", name(1), encoded)).ok()
+                .filter(|d| d.encoded().starts_with(name(2).as_ref()));
+            let mut problem = match digest {
+                Some(digest) => {
+                    let mut hasher = Hasher::new(digest.algorithm());
+                    // A compressed blob that fails to decompress is corrupt too
+                    match open_blob(&path).and_then(|blob| fsck::hash_reader(blob, &mut hasher)) {
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound
+                            || e.kind() == std::io::ErrorKind::PermissionDenied => return Err(unknown(e)),
+                        Err(e) => Problem::new(Kind::CorruptBlob, digest, format!("can't be read: {}", e)),
+                        Ok(()) => {
+                            let actual = hasher.finish();
+                            if actual == digest {
+                                continue
+                            }
+                            Problem::new(Kind::CorruptBlob, digest, format!("hashes to {}", actual))
+                        },
+                    }
+                },
+                None => Problem::new(Kind::UnknownFile, relative.display(), "not named after a digest"),
+            };
+            if repair {
+                fsck::quarantine(&self.dir, &path, &mut problem)?;
+            }
+            problems.push(problem);
+        }
+        problems.extend(check_uploads(&self.dir, repair)?);
+        Ok(problems)
+    }
+}
+
+/// Reports uploads in `<dir>/upload` nobody has written to in a long time
+pub(super) fn check_uploads(dir: &Path, repair: bool) -> Result<Vec<Problem>, RegistryError> {
+    let mut problems = Vec::new();
+    for path in files(&dir.join("upload"), 1)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let age = std::fs::metadata(&path).and_then(|m| m.modified()).map_err(unknown)?
+            .elapsed().unwrap_or_default();
+        let mut problem = match UploadId::parse(&name) {
+            Ok(_) if age < fsck::STALE_UPLOAD_AGE => continue,
+            Ok(id) => Problem::new(Kind::StaleUpload, id,
+                format!("last written {} hours ago", age.as_secs() / 3600)),
+            Err(_) => Problem::new(Kind::UnknownFile, format!("upload/{}", name), "not named after an upload"),
+        };
+        if repair {
+            fsck::quarantine(dir, &path, &mut problem)?;
+        }
+        problems.push(problem);
+    }
+    Ok(problems)
+}
+
+/// Files exactly `depth` directories below `dir`, sorted. Anything at a
+/// different depth is returned as well, as it can't be a blob either.
+pub(super) fn files(dir: &Path, depth: usize) -> Result<Vec<PathBuf>, RegistryError> {
+    let mut entries: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(unknown)?
+        .flatten()
+        .map(|e| e.path())
+        .collect();
+    entries.sort();
+    let mut found = Vec::new();
+    for entry in entries {
+        match entry.is_dir() && depth > 1 {
+            true => found.extend(files(&entry, depth - 1)?),
+            false => found.push(entry),
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_shards_blobs_by_algorithm_and_prefix() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let store = Filesystem::new(&test_path, 1024).unwrap();
+        let digest = Digest::sha256(b"sharded");
+        let path = store.get_blob_path(&digest);
+        assert_eq!(path, PathBuf::from(&test_path).join("blobs/sha256")
+            .join(&digest.encoded()[..2]).join(digest.encoded()));
+    }
+
+    #[test]
+    fn it_migrates_the_flat_layout() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let digest = Digest::sha256(b"flat");
+        std::fs::create_dir_all(format!("{}/blobs", test_path)).unwrap();
+        std::fs::write(format!("{}/blobs/{}", test_path, digest), b"flat").unwrap();
+        assert!(Filesystem::new(&test_path, 1024).is_err());
+
+        assert_eq!(migrate_layout(&test_path).unwrap(), 1);
+        assert_eq!(migrate_layout(&test_path).unwrap(), 0);
+        let store = Filesystem::new(&test_path, 1024).unwrap();
+        assert_eq!(store.stat_blob(&digest).unwrap().size, 4);
+        assert_eq!(store.list_blobs().unwrap(), vec![digest]);
+    }
+
+    #[test]
+    fn it_stores_tar_layers_compressed() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let store = Filesystem::new(&test_path, 1024).unwrap().compress_layers(true);
+        let mut layer = tar::Builder::new(Vec::new());
+        let content = "compressible ".repeat(10_000);
+        let mut header = tar::Header::new_ustar();
+        header.set_size(content.len() as u64);
+        header.set_cksum();
+        layer.append_data(&mut header, "file.txt", content.as_bytes()).unwrap();
+        let layer = layer.into_inner().unwrap();
+        let digest = Digest::sha256(&layer);
+        let id = UploadId::new();
+        let mut writer = store.upload_writer(&id).unwrap();
+        writer.write(&layer).unwrap();
+        writer.finish().unwrap();
+        store.commit(&id, &digest).unwrap();
+
+        let on_disk = std::fs::metadata(store.get_compressed_path(&digest)).unwrap().len();
+        assert!(on_disk < layer.len() as u64 / 10);
+        assert!(!store.get_blob_path(&digest).exists());
+        assert_eq!(store.stat_blob(&digest).unwrap().size, layer.len() as u64);
+        let mut read = Vec::new();
+        store.get_blob(&digest).unwrap().into_reader().read_to_end(&mut read).unwrap();
+        assert_eq!(read, layer);
+        assert_eq!(store.list_blobs().unwrap(), vec![digest.clone()]);
+        assert!(store.check(false).unwrap().is_empty());
+
+        // Anything else, like a config or a gzipped layer, is stored as is
+        let config = b"{}";
+        let id = UploadId::new();
+        let mut writer = store.upload_writer(&id).unwrap();
+        writer.write(config).unwrap();
+        writer.finish().unwrap();
+        store.commit(&id, &Digest::sha256(config)).unwrap();
+        assert!(store.get_blob_path(&Digest::sha256(config)).exists());
+
+        store.delete_blob(&digest).unwrap();
+        assert!(store.get_blob(&digest).is_err());
+    }
+}
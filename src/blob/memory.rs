@@ -0,0 +1,157 @@
+use crate::blob::{BlobStore, BlobInfo, BlobStream, UploadWriter};
+use crate::error;
+use crate::error::RegistryError;
+use crate::types::{Digest, UploadId};
+use crate::util::lock;
+
+use bytes::{Buf, Bytes};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Upload {
+    data: Vec<u8>,
+    touched: Instant,
+}
+
+#[derive(Default)]
+struct Inner {
+    blobs: HashMap<Digest, Bytes>,
+    uploads: HashMap<UploadId, Upload>,
+    size: usize,
+}
+
+impl Inner {
+    /// Drops the uploads nothing was written to for `ttl`, so abandoned ones
+    /// don't hold on to memory
+    fn reap(&mut self, ttl: Duration) {
+        let size = &mut self.size;
+        self.uploads.retain(|_, upload| {
+            let keep = upload.touched.elapsed() < ttl;
+            if !keep {
+                *size -= upload.data.len();
+            }
+            keep
+        });
+    }
+}
+
+/// Keeps blobs and upload sessions in memory, refusing writes once they would
+/// take more than `limit` bytes. Everything is lost on restart.
+pub struct Memory {
+    limit: usize,
+    buf_size: usize,
+    upload_ttl: Option<Duration>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Memory {
+    pub fn new(limit: usize, buf_size: usize) -> Memory {
+        Memory { limit, buf_size, upload_ttl: None, inner: Arc::new(Mutex::new(Inner::default())) }
+    }
+
+    /// Lets writes that would go over the limit first free uploads idle for
+    /// longer than `ttl`
+    pub fn upload_ttl(mut self, ttl: Duration) -> Memory {
+        self.upload_ttl = Some(ttl);
+        self
+    }
+}
+
+struct MemoryUpload {
+    id: UploadId,
+    limit: usize,
+    upload_ttl: Option<Duration>,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl UploadWriter for MemoryUpload {
+    fn write(&mut self, data: &[u8]) -> Result<(), RegistryError> {
+        let mut inner = lock(&self.inner);
+        let over = |inner: &Inner| inner.size + data.len() > self.limit;
+        if let (true, Some(ttl)) = (over(&inner), self.upload_ttl) {
+            inner.reap(ttl);
+        }
+        if over(&inner) {
+            return Err(RegistryError::from_err(error::DENIED,
+                format!("memory limit of {} bytes exceeded", self.limit).into()))
+        }
+        inner.size += data.len();
+        let upload = inner.uploads.entry(self.id.clone())
+            .or_insert_with(|| Upload { data: Vec::new(), touched: Instant::now() });
+        upload.data.extend_from_slice(data);
+        upload.touched = Instant::now();
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<u64, RegistryError> {
+        let inner = lock(&self.inner);
+        Ok(inner.uploads.get(&self.id).map(|u| u.data.len()).unwrap_or(0) as u64)
+    }
+}
+
+impl BlobStore for Memory {
+    fn get_blob(&self, digest: &Digest) -> Result<BlobStream, RegistryError> {
+        match lock(&self.inner).blobs.get(digest) {
+            Some(data) => Ok(BlobStream::new(Box::new(data.clone().reader()), self.buf_size)),
+            None => Err(RegistryError::from(error::BLOB_UNKNOWN))
+        }
+    }
+
+    fn stat_blob(&self, digest: &Digest) -> Result<BlobInfo, RegistryError> {
+        match lock(&self.inner).blobs.get(digest) {
+            Some(data) => Ok(BlobInfo { digest: digest.clone(), size: data.len() as u64 }),
+            None => Err(RegistryError::from(error::BLOB_UNKNOWN))
+        }
+    }
+
+    fn upload_writer(&self, id: &UploadId) -> Result<Box<dyn UploadWriter>, RegistryError> {
+        lock(&self.inner).uploads.entry(id.clone())
+            .or_insert_with(|| Upload { data: Vec::new(), touched: Instant::now() })
+            .touched = Instant::now();
+        Ok(Box::new(MemoryUpload {
+            id: id.clone(),
+            limit: self.limit,
+            upload_ttl: self.upload_ttl,
+            inner: self.inner.clone(),
+        }))
+    }
+
+    fn commit(&self, id: &UploadId, digest: &Digest) -> Result<(), RegistryError> {
+        let mut inner = lock(&self.inner);
+        let data = match inner.uploads.remove(id) {
+            Some(upload) => upload.data,
+            None => return Err(RegistryError::from(error::BLOB_UPLOAD_UNKNOWN))
+        };
+        if let Some(old) = inner.blobs.insert(digest.clone(), Bytes::from(data)) {
+            inner.size -= old.len();
+        }
+        Ok(())
+    }
+
+    fn cancel_upload(&self, id: &UploadId) -> Result<(), RegistryError> {
+        let mut inner = lock(&self.inner);
+        if let Some(upload) = inner.uploads.remove(id) {
+            inner.size -= upload.data.len();
+        }
+        Ok(())
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> Result<(), RegistryError> {
+        let mut inner = lock(&self.inner);
+        match inner.blobs.remove(digest) {
+            Some(data) => {
+                inner.size -= data.len();
+                Ok(())
+            },
+            None => Err(RegistryError::from(error::BLOB_UNKNOWN))
+        }
+    }
+
+    fn list_blobs(&self) -> Result<Vec<Digest>, RegistryError> {
+        let mut blobs: Vec<Digest> = lock(&self.inner).blobs.keys().cloned().collect();
+        blobs.sort();
+        Ok(blobs)
+    }
+}
@@ -0,0 +1,353 @@
+use crate::error;
+use crate::error::RegistryError;
+use crate::fsck::{self, Kind, Problem};
+use crate::types::{Digest, Hasher, UploadId};
+use crate::Options;
+
+use actix_web::rt::task::{spawn_blocking, JoinHandle};
+use log::debug;
+use futures::Stream;
+
+use std::future::Future;
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Poll, Context};
+
+pub mod chunked;
+pub mod fs;
+pub mod memory;
+pub mod s3;
+mod traced;
+
+/// Names accepted by --blob-backend
+pub const BACKENDS: &[&str] = &["fs", "chunked", "memory", "s3"];
+
+/// Information about a stored blob
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobInfo {
+    pub digest: Digest,
+    pub size: u64,
+}
+
+/// How much the stored blobs take up, before and after deduplication
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageStats {
+    pub blobs: usize,
+    /// Pieces the blobs are stored as. Without chunking, one per blob.
+    pub chunks: usize,
+    /// What the blobs add up to
+    pub logical_bytes: u64,
+    /// What storing them takes
+    pub stored_bytes: u64,
+}
+
+impl StorageStats {
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.stored_bytes {
+            0 => 1.0,
+            stored => self.logical_bytes as f64 / stored as f64,
+        }
+    }
+}
+
+pub trait BlobStore: Send + Sync {
+    /// Opens a blob for streaming to a client
+    fn get_blob(&self, digest: &Digest) -> Result<BlobStream, RegistryError>;
+    /// Opens `len` bytes of a blob starting at `start`, which have to lie
+    /// within the blob. Backends that can't seek read up to `start`.
+    fn get_blob_range(&self, digest: &Digest, start: u64, len: u64) -> Result<BlobStream, RegistryError> {
+        self.get_blob(digest)?.slice(start, len)
+            .map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))
+    }
+    fn stat_blob(&self, digest: &Digest) -> Result<BlobInfo, RegistryError>;
+    /// Opens the upload session with the given ID for appending, creating it
+    /// if it doesn't exist yet
+    fn upload_writer(&self, id: &UploadId) -> Result<Box<dyn UploadWriter>, RegistryError>;
+    /// Moves a finished upload session to its final digest
+    fn commit(&self, id: &UploadId, digest: &Digest) -> Result<(), RegistryError>;
+    /// Throws away the data of an upload session. Sessions nothing was
+    /// written to yet have nothing to throw away, which isn't an error.
+    fn cancel_upload(&self, id: &UploadId) -> Result<(), RegistryError>;
+    fn delete_blob(&self, digest: &Digest) -> Result<(), RegistryError>;
+    fn list_blobs(&self) -> Result<Vec<Digest>, RegistryError>;
+
+    fn blob_exists(&self, digest: &Digest) -> bool {
+        self.stat_blob(digest).is_ok()
+    }
+
+    /// A URL clients can fetch the blob from directly, for backends that
+    /// would rather not have us proxy the bytes
+    fn redirect_url(&self, _digest: &Digest) -> Option<String> {
+        None
+    }
+
+    /// Frees what deleted blobs leave behind, for backends that share storage
+    /// between blobs. Returns the number of bytes freed.
+    fn sweep(&self) -> Result<u64, RegistryError> {
+        Ok(0)
+    }
+
+    fn stats(&self) -> Result<StorageStats, RegistryError> {
+        let mut stats = StorageStats::default();
+        for digest in self.list_blobs()? {
+            let size = self.stat_blob(&digest)?.size;
+            stats.blobs += 1;
+            stats.chunks += 1;
+            stats.logical_bytes += size;
+            stats.stored_bytes += size;
+        }
+        Ok(stats)
+    }
+
+    /// Re-hashes every blob. Backends that can set damaged blobs aside do so
+    /// when asked to repair, the others only report.
+    fn check(&self, _repair: bool) -> Result<Vec<Problem>, RegistryError> {
+        let mut problems = Vec::new();
+        for digest in self.list_blobs()? {
+            let mut hasher = Hasher::new(digest.algorithm());
+            fsck::hash_reader(self.get_blob(&digest)?.into_reader(), &mut hasher)
+                .map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))?;
+            let actual = hasher.finish();
+            if actual != digest {
+                problems.push(Problem::new(Kind::CorruptBlob, &digest, format!("hashes to {}", actual)));
+            }
+        }
+        Ok(problems)
+    }
+}
+
+/// Receives the data of an upload session
+pub trait UploadWriter: Send {
+    /// Appends to the upload. Takes either all of the data or, on error, none
+    /// of it, so the session can go on counting what the store holds.
+    fn write(&mut self, data: &[u8]) -> Result<(), RegistryError>;
+    /// Persists everything written so far and returns the total size of the
+    /// upload session
+    fn finish(self: Box<Self>) -> Result<u64, RegistryError>;
+}
+
+/// Opens the blob store selected in the options
+pub fn open(opts: &Options) -> Result<Box<dyn BlobStore>, RegistryError> {
+    let buf_size = opts.get_buf_size_bytes();
+    if opts.compress_layers && opts.blob_backend != "fs" {
+        return Err(RegistryError::from_err(error::UNKNOWN_ERROR,
+            format!("--compress-layers isn't supported by the {} blob backend", opts.blob_backend).into()))
+    }
+    let store: Box<dyn BlobStore> = match opts.blob_backend.as_str() {
+        "fs" => Box::new(fs::Filesystem::new(&opts.data_dir, buf_size)?.compress_layers(opts.compress_layers)),
+        "chunked" => Box::new(chunked::Chunked::new(&opts.data_dir, buf_size)?),
+        "memory" => Box::new(memory::Memory::new(opts.get_memory_limit_bytes(), buf_size)
+            .upload_ttl(std::time::Duration::from_secs(opts.upload_ttl))),
+        "s3" => Box::new(s3::S3::new(opts.get_s3_config(), buf_size)?),
+        other => return Err(RegistryError::from_err(error::UNKNOWN_ERROR,
+            format!("unknown blob backend {}", other).into())),
+    };
+    Ok(Box::new(traced::Traced::new(store, &opts.blob_backend)))
+}
+
+type Reader = Box<dyn Read + Send>;
+
+/// Streams a blob out of any reader in chunks of `buf_size`
+pub struct BlobStream {
+    /// Taken while a read is in progress on the blocking thread pool
+    reader: Option<Reader>,
+    buf_size: usize,
+    /// Whether reads happen off the worker thread
+    blocking: bool,
+    reading: Option<JoinHandle<(Reader, std::io::Result<Option<bytes::Bytes>>)>>,
+}
+
+impl BlobStream {
+    pub fn new(reader: Box<dyn Read + Send>, buf_size: usize) -> BlobStream {
+        BlobStream { reader: Some(reader), buf_size, blocking: false, reading: None }
+    }
+
+    /// Streams from a reader that may wait a long time, e.g. on the network,
+    /// by reading every chunk on the blocking thread pool
+    pub fn blocking(reader: Box<dyn Read + Send>, buf_size: usize) -> BlobStream {
+        BlobStream { blocking: true, ..BlobStream::new(reader, buf_size) }
+    }
+
+    /// Skips the first `start` bytes and ends after `len` more
+    pub fn slice(self, start: u64, len: u64) -> std::io::Result<BlobStream> {
+        let (buf_size, blocking) = (self.buf_size, self.blocking);
+        let mut reader = self.into_reader();
+        std::io::copy(&mut (&mut reader).take(start), &mut std::io::sink())?;
+        Ok(BlobStream { blocking, ..BlobStream::new(Box::new(reader.take(len)), buf_size) })
+    }
+
+    /// Reads the blob synchronously instead of streaming it. Meant for
+    /// streams that weren't polled yet.
+    pub fn into_reader(self) -> Box<dyn Read + Send> {
+        self.reader.unwrap_or_else(|| Box::new(std::io::empty()))
+    }
+}
+
+fn read_chunk(reader: &mut dyn Read, buf_size: usize) -> std::io::Result<Option<bytes::Bytes>> {
+    let mut buf = bytes::BytesMut::with_capacity(buf_size);
+    buf.resize(buf_size, 0);
+    let read = reader.read(&mut buf)?;
+    debug!("Read {} bytes", read);
+    if read == 0 {
+        return Ok(None)
+    }
+    buf.truncate(read);
+    Ok(Some(buf.freeze()))
+}
+
+impl Stream for BlobStream {
+    type Item = Result<bytes::Bytes, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if !this.blocking {
+            return match this.reader.as_mut() {
+                Some(reader) => Poll::Ready(read_chunk(reader, this.buf_size).transpose()),
+                None => Poll::Ready(None),
+            }
+        }
+        if this.reading.is_none() {
+            let Some(mut reader) = this.reader.take() else {
+                return Poll::Ready(None)
+            };
+            let buf_size = this.buf_size;
+            this.reading = Some(spawn_blocking(move || {
+                let chunk = read_chunk(&mut reader, buf_size);
+                (reader, chunk)
+            }));
+        }
+        let done = match this.reading.as_mut().map(|reading| Pin::new(reading).poll(cx)) {
+            Some(Poll::Ready(done)) => done,
+            _ => return Poll::Pending,
+        };
+        this.reading = None;
+        match done {
+            Ok((reader, chunk)) => {
+                this.reader = Some(reader);
+                Poll::Ready(chunk.transpose())
+            },
+            Err(e) => Poll::Ready(Some(Err(std::io::Error::other(e)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn read_blob(s: &dyn BlobStore, digest: &Digest) -> Vec<u8> {
+        // Some streams read on the runtime's blocking thread pool
+        let chunks: Vec<_> = actix_web::rt::System::new().block_on(
+            s.get_blob(digest).unwrap().collect());
+        chunks.into_iter().flat_map(|c| c.unwrap().to_vec()).collect()
+    }
+
+    fn store_uploads_and_gets(s: &dyn BlobStore) {
+        let digest = Digest::sha256(b"hello world");
+        let id = UploadId::new();
+        let mut w = s.upload_writer(&id).unwrap();
+        w.write(b"hello ").unwrap();
+        assert_eq!(w.finish().unwrap(), 6);
+        let mut w = s.upload_writer(&id).unwrap();
+        w.write(b"world").unwrap();
+        assert_eq!(w.finish().unwrap(), 11);
+        s.commit(&id, &digest).unwrap();
+
+        assert_eq!(read_blob(s, &digest), b"hello world");
+        assert_eq!(s.stat_blob(&digest).unwrap().size, 11);
+        assert!(s.list_blobs().unwrap().contains(&digest));
+    }
+
+    fn store_deletes(s: &dyn BlobStore) {
+        let digest = Digest::sha256(b"delete me");
+        let id = UploadId::new();
+        let mut w = s.upload_writer(&id).unwrap();
+        w.write(b"delete me").unwrap();
+        w.finish().unwrap();
+        s.commit(&id, &digest).unwrap();
+        assert!(s.blob_exists(&digest));
+        s.delete_blob(&digest).unwrap();
+        assert!(!s.blob_exists(&digest));
+        assert!(s.get_blob(&digest).is_err());
+    }
+
+    fn store_reads_ranges(s: &dyn BlobStore) {
+        let digest = Digest::sha256(b"0123456789");
+        let id = UploadId::new();
+        let mut w = s.upload_writer(&id).unwrap();
+        w.write(b"0123456789").unwrap();
+        w.finish().unwrap();
+        s.commit(&id, &digest).unwrap();
+        let mut range = Vec::new();
+        s.get_blob_range(&digest, 3, 4).unwrap().into_reader().read_to_end(&mut range).unwrap();
+        assert_eq!(range, b"3456");
+    }
+
+    #[test]
+    fn fs_store_tests() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let fstore = fs::Filesystem::new(&test_path, 1024).unwrap();
+        store_uploads_and_gets(&fstore);
+        store_deletes(&fstore);
+        store_reads_ranges(&fstore);
+    }
+
+    #[test]
+    fn chunked_store_tests() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let cstore = chunked::Chunked::new(&test_path, 1024).unwrap();
+        store_uploads_and_gets(&cstore);
+        store_deletes(&cstore);
+        store_reads_ranges(&cstore);
+    }
+
+    #[test]
+    fn memory_store_tests() {
+        let mstore = memory::Memory::new(1024, 1024);
+        store_uploads_and_gets(&mstore);
+        store_deletes(&mstore);
+        store_reads_ranges(&mstore);
+    }
+
+    #[test]
+    fn memory_store_enforces_limit() {
+        let mstore = memory::Memory::new(16, 1024);
+        let mut w = mstore.upload_writer(&UploadId::new()).unwrap();
+        w.write(&[0; 10]).unwrap();
+        assert!(w.write(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn memory_store_frees_abandoned_uploads() {
+        let mstore = memory::Memory::new(16, 1024);
+        let abandoned = UploadId::new();
+        mstore.upload_writer(&abandoned).unwrap().write(&[0; 10]).unwrap();
+        // Cancelling gives the memory back
+        mstore.cancel_upload(&abandoned).unwrap();
+        mstore.upload_writer(&UploadId::new()).unwrap().write(&[0; 10]).unwrap();
+
+        // Uploads idle for longer than the TTL make way for new ones
+        let mstore = memory::Memory::new(16, 1024).upload_ttl(std::time::Duration::ZERO);
+        mstore.upload_writer(&abandoned).unwrap().write(&[0; 10]).unwrap();
+        let id = UploadId::new();
+        mstore.upload_writer(&id).unwrap().write(&[0; 10]).unwrap();
+        assert_eq!(mstore.upload_writer(&abandoned).unwrap().finish().unwrap(), 0);
+    }
+
+    #[test]
+    fn s3_store_tests() {
+        let config = s3::Config {
+            endpoint: s3::stand_in::start(),
+            bucket: String::from("blobert"),
+            region: String::from("us-east-1"),
+            access_key: String::from("access"),
+            secret_key: String::from("secret"),
+            presign: false,
+        };
+        let s3store = s3::S3::new(config, 1024).unwrap();
+        store_uploads_and_gets(&s3store);
+        store_deletes(&s3store);
+        store_reads_ranges(&s3store);
+    }
+}
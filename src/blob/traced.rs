@@ -0,0 +1,106 @@
+use tracing::info_span;
+
+use crate::error::RegistryError;
+use crate::fsck::Problem;
+use crate::types::{Digest, UploadId};
+
+use super::{BlobInfo, BlobStore, BlobStream, StorageStats, UploadWriter};
+
+/// Records a span for every operation of the blob store it wraps
+pub struct Traced {
+    inner: Box<dyn BlobStore>,
+    backend: String,
+}
+
+impl Traced {
+    pub fn new(inner: Box<dyn BlobStore>, backend: &str) -> Traced {
+        Traced { inner, backend: backend.to_string() }
+    }
+}
+
+impl BlobStore for Traced {
+    fn get_blob(&self, digest: &Digest) -> Result<BlobStream, RegistryError> {
+        let _span = info_span!("blob.get", backend = %self.backend, digest = digest.as_str()).entered();
+        self.inner.get_blob(digest)
+    }
+
+    fn get_blob_range(&self, digest: &Digest, start: u64, len: u64) -> Result<BlobStream, RegistryError> {
+        let _span = info_span!("blob.get_range", backend = %self.backend, digest = digest.as_str(), start, len).entered();
+        self.inner.get_blob_range(digest, start, len)
+    }
+
+    fn stat_blob(&self, digest: &Digest) -> Result<BlobInfo, RegistryError> {
+        let _span = info_span!("blob.stat", backend = %self.backend, digest = digest.as_str()).entered();
+        self.inner.stat_blob(digest)
+    }
+
+    fn upload_writer(&self, id: &UploadId) -> Result<Box<dyn UploadWriter>, RegistryError> {
+        let _span = info_span!("blob.open_upload", backend = %self.backend, upload = id.as_str()).entered();
+        let inner = self.inner.upload_writer(id)?;
+        Ok(Box::new(TracedWriter { inner, backend: self.backend.clone(), upload: id.clone() }))
+    }
+
+    fn commit(&self, id: &UploadId, digest: &Digest) -> Result<(), RegistryError> {
+        let _span = info_span!("blob.commit", backend = %self.backend, upload = id.as_str(),
+            digest = digest.as_str()).entered();
+        self.inner.commit(id, digest)
+    }
+
+    fn cancel_upload(&self, id: &UploadId) -> Result<(), RegistryError> {
+        let _span = info_span!("blob.cancel_upload", backend = %self.backend, upload = id.as_str()).entered();
+        self.inner.cancel_upload(id)
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> Result<(), RegistryError> {
+        let _span = info_span!("blob.delete", backend = %self.backend, digest = digest.as_str()).entered();
+        self.inner.delete_blob(digest)
+    }
+
+    fn list_blobs(&self) -> Result<Vec<Digest>, RegistryError> {
+        let _span = info_span!("blob.list", backend = %self.backend).entered();
+        self.inner.list_blobs()
+    }
+
+    fn blob_exists(&self, digest: &Digest) -> bool {
+        let _span = info_span!("blob.exists", backend = %self.backend, digest = digest.as_str()).entered();
+        self.inner.blob_exists(digest)
+    }
+
+    fn redirect_url(&self, digest: &Digest) -> Option<String> {
+        self.inner.redirect_url(digest)
+    }
+
+    fn sweep(&self) -> Result<u64, RegistryError> {
+        let _span = info_span!("blob.sweep", backend = %self.backend).entered();
+        self.inner.sweep()
+    }
+
+    fn stats(&self) -> Result<StorageStats, RegistryError> {
+        let _span = info_span!("blob.stats", backend = %self.backend).entered();
+        self.inner.stats()
+    }
+
+    fn check(&self, repair: bool) -> Result<Vec<Problem>, RegistryError> {
+        let _span = info_span!("blob.check", backend = %self.backend, repair).entered();
+        self.inner.check(repair)
+    }
+}
+
+/// Writes are called once per chunk received, far too often for a span
+/// each, so only finishing the upload gets one
+struct TracedWriter {
+    inner: Box<dyn UploadWriter>,
+    backend: String,
+    upload: UploadId,
+}
+
+impl UploadWriter for TracedWriter {
+    fn write(&mut self, data: &[u8]) -> Result<(), RegistryError> {
+        self.inner.write(data)
+    }
+
+    fn finish(self: Box<Self>) -> Result<u64, RegistryError> {
+        let _span = info_span!("blob.finish_upload", backend = %self.backend, upload = self.upload.as_str()).entered();
+        self.inner.finish()
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+use std::io::Write;
+
+use log::{info, warn};
+use structopt::StructOpt;
+
+use crate::audit::{self, AuditLog, Entry};
+use crate::blob::{self, BlobStore};
+use crate::error;
+use crate::error::RegistryError;
+use crate::fsck;
+use crate::layout;
+use crate::quota;
+use crate::meta;
+use crate::types::{Digest, Reference, RepositoryName};
+use crate::util::to_json;
+use crate::Options;
+
+/// What the binary should do. Serving the registry is the default.
+#[derive(StructOpt, Clone)]
+pub enum Command {
+    /// Serve the registry API
+    Serve,
+    /// List repositories, or the tags of one repository
+    Ls {
+        repository: Option<String>,
+    },
+    /// Print a manifest and the sizes of the blobs it references
+    Inspect {
+        /// `<repository>:<tag>` or `<repository>@<digest>`
+        image: String,
+    },
+    /// Remove a tag, or a manifest and every tag pointing at it
+    Rm {
+        /// `<repository>:<tag>` or `<repository>@<digest>`
+        image: String,
+    },
+    /// Point a tag at the manifest of another image
+    Tag {
+        source: String,
+        target: String,
+    },
+    /// Delete blobs no stored manifest refers to. Pushes in progress while it
+    /// runs may lose their blobs.
+    Gc {
+        /// Only list what would be deleted
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Check blobs, manifests, tags and uploads for damage, printing one JSON
+    /// object per problem
+    Fsck {
+        /// Move corrupt objects into the quarantine directory of the data dir
+        #[structopt(long)]
+        repair: bool,
+    },
+    /// Show how much space each repository takes
+    Du,
+    /// Show how much space the blobs take and how much deduplication saves
+    Stats,
+    /// Upgrade the data dir from an older layout
+    Migrate(Migration),
+    /// Write a repository, or one tag of it, as an OCI image layout tarball
+    Export {
+        /// `<repository>[:<tag>]` or `<repository>@<digest>`
+        image: String,
+
+        /// Where to write the tarball instead of stdout
+        #[structopt(short, long)]
+        output: Option<String>,
+    },
+    /// Load the images of an OCI image layout tarball
+    Import {
+        path: String,
+
+        /// Repository to import into, instead of the one named in the
+        /// tarball's annotations
+        #[structopt(long)]
+        repository: Option<String>,
+    },
+}
+
+#[derive(StructOpt, Clone)]
+pub enum Migration {
+    /// Move blobs from the flat `blobs/` directory into the sharded layout
+    Blobs,
+    /// Copy manifests and tags from the filesystem layout into the redb
+    /// metadata store
+    Meta,
+}
+
+fn audit_log(opts: &Options) -> Result<AuditLog, RegistryError> {
+    AuditLog::from_options(opts).map_err(io_error)
+}
+
+fn io_error(e: std::io::Error) -> RegistryError {
+    RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e))
+}
+
+fn human(bytes: u64) -> String {
+    byte_unit::Byte::from_bytes(bytes as u128).get_appropriate_unit(true).to_string()
+}
+
+/// Splits an image that has to name a tag or digest
+fn parse_reference(image: &str) -> Result<(RepositoryName, Reference), RegistryError> {
+    match layout::parse_image(image)? {
+        (name, Some(reference)) => Ok((name, reference)),
+        (_, None) => Err(RegistryError::from_err(error::MANIFEST_INVALID,
+            format!("{} names no tag or digest", image).into()))
+    }
+}
+
+fn repositories(meta: &dyn meta::Store) -> Result<Vec<RepositoryName>, RegistryError> {
+    Ok(meta.list_repositories()?.iter()
+        .filter_map(|r| RepositoryName::parse(r)
+            .inspect_err(|_| warn!("Skipping invalid repository name {}", r))
+            .ok())
+        .collect())
+}
+
+pub fn ls(meta: &dyn meta::Store, repository: Option<&RepositoryName>, out: &mut dyn Write) -> Result<(), RegistryError> {
+    let repository = match repository {
+        Some(repository) => repository,
+        None => {
+            for repository in repositories(meta)? {
+                writeln!(out, "{}", repository).map_err(io_error)?;
+            }
+            return Ok(())
+        }
+    };
+    for tag in meta.list_tags(repository)? {
+        let manifest = meta.get_manifest(repository, &Reference::parse(&tag)?)?;
+        writeln!(out, "{}\t{}", tag, manifest.digest()).map_err(io_error)?;
+    }
+    Ok(())
+}
+
+pub fn inspect(meta: &dyn meta::Store, blobs: &dyn BlobStore, repository: &RepositoryName,
+    reference: &Reference, out: &mut dyn Write) -> Result<(), RegistryError>
+{
+    let raw = meta.get_manifest(repository, reference)?;
+    let manifest = raw.manifest();
+    let pretty = serde_json::to_string_pretty(manifest)
+        .map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))?;
+    writeln!(out, "{}\n\nDigest: {}\n", pretty, raw.digest()).map_err(io_error)?;
+
+    let mut total = 0;
+    let kinds = manifest.config.iter().map(|_| "config").chain(std::iter::repeat("layer"));
+    for (kind, descriptor) in kinds.zip(manifest.descriptors()) {
+        let size = Digest::parse(&descriptor.digest).ok()
+            .and_then(|digest| blobs.stat_blob(&digest).ok())
+            .map(|info| info.size);
+        total += size.unwrap_or(0);
+        let size = size.map(human).unwrap_or_else(|| String::from("missing"));
+        writeln!(out, "{:<8}{}  {}", kind, descriptor.digest, size).map_err(io_error)?;
+    }
+    writeln!(out, "{:<8}{}", "total", human(total)).map_err(io_error)
+}
+
+pub fn tag(meta: &dyn meta::Store, source: (&RepositoryName, &Reference),
+    target: (&RepositoryName, &Reference)) -> Result<(), RegistryError>
+{
+    if let Reference::Digest(_) = target.1 {
+        return Err(RegistryError::from_err(error::MANIFEST_INVALID,
+            "the target of a tag has to be a tag, not a digest".into()))
+    }
+    let manifest = meta.get_manifest(source.0, source.1)?;
+    meta.put_manifest(target.0, target.1, &manifest)
+}
+
+/// Deletes every blob that isn't referenced by a stored manifest, tagged or
+/// pulled by digest only. Returns the number of blobs and bytes freed.
+pub fn gc(meta: &dyn meta::Store, blobs: &dyn BlobStore, dry_run: bool, out: &mut dyn Write) -> Result<(usize, u64), RegistryError> {
+    let mut referenced = HashSet::new();
+    for repository in repositories(meta)? {
+        referenced.extend(quota::repository_blobs(meta, &repository)?);
+    }
+
+    let (mut count, mut freed) = (0, 0);
+    for digest in blobs.list_blobs()? {
+        if referenced.contains(&digest) {
+            continue
+        }
+        let size = blobs.stat_blob(&digest)?.size;
+        if !dry_run {
+            blobs.delete_blob(&digest)?;
+        }
+        writeln!(out, "{}\t{}", digest, human(size)).map_err(io_error)?;
+        count += 1;
+        freed += size;
+    }
+    Ok((count, freed))
+}
+
+/// Prints the fsck report as JSON lines. Returns the number of problems
+/// that are left after repairing.
+pub fn fsck(meta: &dyn meta::Store, blobs: &dyn BlobStore, repair: bool, out: &mut dyn Write) -> Result<usize, RegistryError> {
+    let problems = fsck::check(meta, blobs, repair)?;
+    for problem in &problems {
+        out.write_all(&to_json(problem)?).map_err(io_error)?;
+        writeln!(out).map_err(io_error)?;
+    }
+    Ok(problems.iter().filter(|p| !p.quarantined).count())
+}
+
+/// Prints the size of the blobs each repository references. Blobs shared
+/// between repositories count towards each of them.
+pub fn du(meta: &dyn meta::Store, blobs: &dyn BlobStore, out: &mut dyn Write) -> Result<(), RegistryError> {
+    for repository in repositories(meta)? {
+        let size = quota::size_of(blobs, &quota::repository_blobs(meta, &repository)?);
+        writeln!(out, "{}\t{}", human(size), repository).map_err(io_error)?;
+    }
+    let mut total = 0;
+    for digest in blobs.list_blobs()? {
+        total += blobs.stat_blob(&digest)?.size;
+    }
+    writeln!(out, "{}\ttotal", human(total)).map_err(io_error)
+}
+
+/// Prints the number and size of the stored blobs and how well they
+/// deduplicate
+pub fn stats(blobs: &dyn BlobStore, out: &mut dyn Write) -> Result<(), RegistryError> {
+    let stats = blobs.stats()?;
+    writeln!(out, "blobs\t{}", stats.blobs).map_err(io_error)?;
+    writeln!(out, "chunks\t{}", stats.chunks).map_err(io_error)?;
+    writeln!(out, "logical\t{}", human(stats.logical_bytes)).map_err(io_error)?;
+    writeln!(out, "stored\t{}", human(stats.stored_bytes)).map_err(io_error)?;
+    writeln!(out, "dedup ratio\t{:.2}", stats.dedup_ratio()).map_err(io_error)
+}
+
+/// Runs every command but `serve` against the stores selected in the options
+pub fn run(opts: &Options, command: &Command) -> Result<(), RegistryError> {
+    let mut out = std::io::stdout().lock();
+    // Migrations have to run before the stores refuse the old layout
+    if let Command::Migrate(migration) = command {
+        return match migration {
+            Migration::Blobs => blob::fs::migrate_layout(&opts.data_dir)
+                .map(|moved| info!("Moved {} blobs into the sharded layout", moved)),
+            Migration::Meta => meta::db::Redb::new(&opts.data_dir)
+                .and_then(|db| db.import_filesystem(&opts.data_dir))
+                .map(|(manifests, tags)| info!("Imported {} manifests and {} tags", manifests, tags)),
+        }
+    }
+
+    let meta_store = meta::open(opts)?;
+    let blob_store = blob::open(opts)?;
+    let (meta, blobs) = (meta_store.as_ref(), blob_store.as_ref());
+    match command {
+        Command::Serve | Command::Migrate(_) => Ok(()),
+        Command::Ls { repository } => {
+            let repository = repository.as_deref().map(RepositoryName::parse).transpose()?;
+            ls(meta, repository.as_ref(), &mut out)
+        },
+        Command::Inspect { image } => {
+            let (repository, reference) = parse_reference(image)?;
+            inspect(meta, blobs, &repository, &reference, &mut out)
+        },
+        Command::Rm { image } => {
+            let (repository, reference) = parse_reference(image)?;
+            let old = meta.get_manifest(&repository, &reference).ok().map(|m| m.digest().to_string());
+            meta.delete_manifest(&repository, &reference)?;
+            audit_log(opts)?.record(&Entry::cli(audit::Action::Delete, repository.as_str(), reference.as_str())
+                .digests(old, None));
+            Ok(())
+        },
+        Command::Tag { source, target } => {
+            let (source, target) = (parse_reference(source)?, parse_reference(target)?);
+            let old = meta.get_manifest(&target.0, &target.1).ok().map(|m| m.digest().to_string());
+            tag(meta, (&source.0, &source.1), (&target.0, &target.1))?;
+            let new = meta.get_manifest(&target.0, &target.1)?.digest().to_string();
+            audit_log(opts)?.record(&Entry::cli(audit::Action::Tag, target.0.as_str(), target.1.as_str())
+                .digests(old, Some(new)));
+            Ok(())
+        },
+        Command::Gc { dry_run } => {
+            let (count, freed) = gc(meta, blobs, *dry_run, &mut out)?;
+            let verb = if *dry_run { "Would delete" } else { "Deleted" };
+            info!("{} {} blobs, {}", verb, count, human(freed));
+            if !dry_run {
+                let swept = blobs.sweep()?;
+                if swept > 0 {
+                    info!("Freed {} of chunks no blob uses anymore", human(swept));
+                }
+            }
+            Ok(())
+        },
+        Command::Fsck { repair } => match fsck(meta, blobs, *repair, &mut out)? {
+            0 => Ok(()),
+            problems => Err(RegistryError::from_err(error::UNKNOWN_ERROR,
+                format!("found {} problems that need attention", problems).into()))
+        },
+        Command::Du => du(meta, blobs, &mut out),
+        Command::Stats => stats(blobs, &mut out),
+        Command::Export { image, output } => {
+            let (repository, reference) = layout::parse_image(image)?;
+            let out: Box<dyn Write> = match output {
+                Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path).map_err(io_error)?)),
+                None => Box::new(out),
+            };
+            let exported = layout::export(meta, blobs, &repository, reference.as_ref(), out)?;
+            info!("Exported {} manifests", exported);
+            Ok(())
+        },
+        Command::Import { path, repository } => {
+            let repository = repository.as_deref().map(RepositoryName::parse).transpose()?;
+            let file = std::fs::File::open(path).map_err(io_error)?;
+            let imported = layout::import(meta, blobs, std::io::BufReader::new(file),
+                repository.as_ref(), opts.get_buf_size_bytes())?;
+            info!("Imported {} manifests", imported);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::memory::Memory as MemoryBlobs;
+    use crate::meta::memory::Memory as MemoryMeta;
+    use crate::meta::{Descriptor, Manifest, RawManifest, Store};
+    use crate::types::UploadId;
+
+    fn put_blob(blobs: &dyn BlobStore, data: &[u8]) -> Digest {
+        let digest = Digest::sha256(data);
+        let id = UploadId::new();
+        let mut upload = blobs.upload_writer(&id).unwrap();
+        upload.write(data).unwrap();
+        upload.finish().unwrap();
+        blobs.commit(&id, &digest).unwrap();
+        digest
+    }
+
+    fn stores() -> (MemoryMeta, MemoryBlobs) {
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        let layer = put_blob(&blobs, b"layer");
+        let manifest = Manifest {
+            config: Some(Descriptor { digest: layer.to_string(), ..Descriptor::default() }),
+            ..Manifest::default()
+        };
+        let app = RepositoryName::parse("app").unwrap();
+        meta.put_manifest(&app, &Reference::parse("v1").unwrap(), &RawManifest::from(&manifest)).unwrap();
+        (meta, blobs)
+    }
+
+    fn output(f: impl FnOnce(&mut dyn Write)) -> String {
+        let mut out = Vec::new();
+        f(&mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn it_collects_unreferenced_blobs() {
+        let (meta, blobs) = stores();
+        let orphan = put_blob(&blobs, b"orphan");
+
+        let listed = output(|out| assert_eq!(gc(&meta, &blobs, true, out).unwrap(), (1, 6)));
+        assert!(listed.starts_with(orphan.as_str()));
+        assert!(blobs.blob_exists(&orphan));
+
+        gc(&meta, &blobs, false, &mut std::io::sink()).unwrap();
+        assert!(!blobs.blob_exists(&orphan));
+        assert!(blobs.blob_exists(&Digest::sha256(b"layer")));
+    }
+
+    #[test]
+    fn it_keeps_blobs_of_manifests_pushed_by_digest() {
+        let (meta, blobs) = stores();
+        let layer = put_blob(&blobs, b"platform layer");
+        let manifest = Manifest {
+            config: Some(Descriptor { digest: layer.to_string(), ..Descriptor::default() }),
+            ..Manifest::default()
+        };
+        // Like the platform manifests of an index before the index is pushed
+        let app = RepositoryName::parse("app").unwrap();
+        meta.put_manifest(&app, &Reference::Digest(manifest.digest()), &RawManifest::from(&manifest)).unwrap();
+
+        assert_eq!(gc(&meta, &blobs, false, &mut std::io::sink()).unwrap(), (0, 0));
+        assert!(blobs.blob_exists(&layer));
+    }
+
+    #[test]
+    fn it_keeps_blobs_of_nested_repositories() {
+        let meta = crate::meta::fs::Filesystem::new(&format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4())).unwrap();
+        let blobs = MemoryBlobs::new(1024 * 1024, 1024);
+        let layer = put_blob(&blobs, b"layer");
+        let manifest = Manifest {
+            config: Some(Descriptor { digest: layer.to_string(), ..Descriptor::default() }),
+            ..Manifest::default()
+        };
+        let app = RepositoryName::parse("docker.io/library/app").unwrap();
+        meta.put_manifest(&app, &Reference::parse("v1").unwrap(), &RawManifest::from(&manifest)).unwrap();
+
+        assert_eq!(gc(&meta, &blobs, false, &mut std::io::sink()).unwrap(), (0, 0));
+        assert!(blobs.blob_exists(&layer));
+    }
+
+    #[test]
+    fn it_tags_and_removes_images() {
+        let (meta, _) = stores();
+        let (app, mirror) = (RepositoryName::parse("app").unwrap(), RepositoryName::parse("mirror").unwrap());
+        let (v1, latest) = (Reference::parse("v1").unwrap(), Reference::parse("latest").unwrap());
+        tag(&meta, (&app, &v1), (&mirror, &latest)).unwrap();
+        assert_eq!(output(|out| ls(&meta, None, out).unwrap()), "app\nmirror\n");
+        assert!(output(|out| ls(&meta, Some(&mirror), out).unwrap()).starts_with("latest\tsha256:"));
+
+        meta.delete_manifest(&app, &v1).unwrap();
+        assert_eq!(output(|out| ls(&meta, Some(&app), out).unwrap()), "");
+    }
+
+    #[test]
+    fn it_reports_missing_blobs() {
+        let (meta, blobs) = stores();
+        assert_eq!(fsck(&meta, &blobs, false, &mut std::io::sink()).unwrap(), 0);
+        blobs.delete_blob(&Digest::sha256(b"layer")).unwrap();
+        let report = output(|out| assert_eq!(fsck(&meta, &blobs, true, out).unwrap(), 1));
+        let problem: serde_json::Value = serde_json::from_str(report.trim()).unwrap();
+        assert_eq!(problem["kind"], "missing_blob");
+        assert_eq!(problem["quarantined"], false);
+    }
+}
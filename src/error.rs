@@ -1,14 +1,46 @@
 use actix_web::{HttpResponse, http::StatusCode};
 use serde::{Serialize, Deserialize};
 
-type ErrorSpec = (&'static str, &'static str);
+/// Code, message and HTTP status of an error defined by the distribution spec
+type ErrorSpec = (&'static str, &'static str, StatusCode);
 
 pub const BLOB_UNKNOWN: ErrorSpec =
-    ("BLOB_UNKNOWN", "blob unknown to registry"); 
+    ("BLOB_UNKNOWN", "blob unknown to registry", StatusCode::NOT_FOUND);
+pub const BLOB_UPLOAD_INVALID: ErrorSpec =
+    ("BLOB_UPLOAD_INVALID", "blob upload invalid", StatusCode::BAD_REQUEST);
+pub const BLOB_UPLOAD_UNKNOWN: ErrorSpec =
+    ("BLOB_UPLOAD_UNKNOWN", "blob upload unknown to registry", StatusCode::NOT_FOUND);
+pub const DIGEST_INVALID: ErrorSpec =
+    ("DIGEST_INVALID", "provided digest did not match uploaded content", StatusCode::BAD_REQUEST);
+pub const MANIFEST_BLOB_UNKNOWN: ErrorSpec =
+    ("MANIFEST_BLOB_UNKNOWN", "manifest references a manifest or blob unknown to registry", StatusCode::BAD_REQUEST);
+pub const MANIFEST_INVALID: ErrorSpec =
+    ("MANIFEST_INVALID", "manifest invalid", StatusCode::BAD_REQUEST);
 pub const MANIFEST_UNKNOWN: ErrorSpec =
-    ("MANIFEST_UNKNOWN", "manifest unknown to registry");
+    ("MANIFEST_UNKNOWN", "manifest unknown to registry", StatusCode::NOT_FOUND);
+pub const NAME_INVALID: ErrorSpec =
+    ("NAME_INVALID", "invalid repository name", StatusCode::BAD_REQUEST);
+pub const NAME_UNKNOWN: ErrorSpec =
+    ("NAME_UNKNOWN", "repository name not known to registry", StatusCode::NOT_FOUND);
+pub const SIZE_INVALID: ErrorSpec =
+    ("SIZE_INVALID", "provided length did not match content length", StatusCode::BAD_REQUEST);
+// blobert has no authentication, so nothing reports this yet
+#[allow(dead_code)]
+pub const UNAUTHORIZED: ErrorSpec =
+    ("UNAUTHORIZED", "authentication required", StatusCode::UNAUTHORIZED);
+pub const DENIED: ErrorSpec =
+    ("DENIED", "requested access to the resource is denied", StatusCode::FORBIDDEN);
+pub const UNSUPPORTED: ErrorSpec =
+    ("UNSUPPORTED", "the operation is unsupported", StatusCode::METHOD_NOT_ALLOWED);
+// Nor this, as there is no rate limiting
+#[allow(dead_code)]
+pub const TOOMANYREQUESTS: ErrorSpec =
+    ("TOOMANYREQUESTS", "too many requests", StatusCode::TOO_MANY_REQUESTS);
+/// Not in the OCI spec, but reported by the Docker registry too
+pub const UNAVAILABLE: ErrorSpec =
+    ("UNAVAILABLE", "service unavailable", StatusCode::SERVICE_UNAVAILABLE);
 pub const UNKNOWN_ERROR: ErrorSpec =
-    ("UNKNOWN ERROR", "something is very wrong");
+    ("UNKNOWN", "something is very wrong", StatusCode::INTERNAL_SERVER_ERROR);
 
 /// Error type expected by OCI specification
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -21,18 +53,20 @@ pub struct RegistryError {
     message: String,
     /// The detail field is OPTIONAL and MAY contain arbitrary JSON data
     /// providing information the client can use to resolve the issue.
-    detail: Detail,
+    #[serde(default, skip_serializing_if = "serde_json::Value::is_null")]
+    detail: serde_json::Value,
+    /// HTTP status the error is reported with
+    #[serde(skip, default = "default_status")]
+    status: StatusCode,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Detail {
-    reason: String
+fn default_status() -> StatusCode {
+    StatusCode::INTERNAL_SERVER_ERROR
 }
 
 /// Registry spec for error response includes an array of errors
-/// TODO: Some way to construct a multi-error
-#[derive(Serialize)]
-struct RegistryErrorResponse {
+#[derive(Serialize, Debug, Clone)]
+pub struct RegistryErrorResponse {
     errors: Vec<RegistryError>
 }
 
@@ -41,45 +75,132 @@ impl RegistryError {
         RegistryError {
             code: String::from(spec.0),
             message: String::from(spec.1),
-            detail: Detail { reason: String::from("") }
+            detail: serde_json::Value::Null,
+            status: spec.2,
         }
     }
 
     pub fn from_err(spec: ErrorSpec, err: Box<dyn std::error::Error>) -> RegistryError {
-        RegistryError {
-            code: String::from(spec.0),
-            message: String::from(spec.1),
-            detail: Detail { reason: String::from(err.to_string()) }
-        }
+        RegistryError::from(spec)
+            .with_detail(serde_json::json!({ "reason": err.to_string() }))
     }
 
-    // Convert the error to an HttpResponse for Actix
-    pub fn respond(&self) -> HttpResponse {
-        let response = RegistryErrorResponse {
-            errors: vec![self.clone()]
-        };
-        let payload = serde_json::to_vec(&response).unwrap();
-        HttpResponse::build(StatusCode::NOT_FOUND)
-            .append_header(("Content-Type", "application/json"))
-            .body(payload)
+    /// Attaches structured information about the error, e.g. the offending
+    /// digest or the expected and actual sizes
+    pub fn with_detail<T: Serialize>(mut self, detail: T) -> RegistryError {
+        self.detail = serde_json::to_value(detail).unwrap_or_default();
+        self
     }
 }
 
 impl std::fmt::Display for RegistryError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: {}", self.code, self.message)
+        write!(f, "{}: {}", self.code, self.message)?;
+        if !self.detail.is_null() {
+            write!(f, " ({})", self.detail)?;
+        }
+        Ok(())
     }
 }
 
 impl std::error::Error for RegistryError {}
 
+impl actix_web::ResponseError for RegistryError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        RegistryErrorResponse::from(self.clone()).error_response()
+    }
+}
+
+impl RegistryErrorResponse {
+    pub fn new(errors: Vec<RegistryError>) -> RegistryErrorResponse {
+        RegistryErrorResponse { errors }
+    }
+}
+
+impl From<RegistryError> for RegistryErrorResponse {
+    fn from(err: RegistryError) -> RegistryErrorResponse {
+        RegistryErrorResponse { errors: vec![err] }
+    }
+}
+
+impl std::fmt::Display for RegistryErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors: Vec<String> = self.errors.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", errors.join(", "))
+    }
+}
+
+impl actix_web::ResponseError for RegistryErrorResponse {
+    /// A response carries a single status, the one of its first error
+    fn status_code(&self) -> StatusCode {
+        self.errors.first().map(|e| e.status).unwrap_or_else(default_status)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let payload = serde_json::to_vec(self).unwrap();
+        HttpResponse::build(self.status_code())
+            .append_header(("Content-Type", "application/json"))
+            .body(payload)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::ResponseError;
+    use actix_web::body::MessageBody;
 
     #[test]
     fn it_constructs_an_error() {
         let err = RegistryError::from(BLOB_UNKNOWN);
         assert_eq!(err.code, "BLOB_UNKNOWN");
     }
+
+    #[test]
+    fn it_responds_with_the_spec_status() {
+        assert_eq!(RegistryError::from(BLOB_UNKNOWN).error_response().status(), StatusCode::NOT_FOUND);
+        assert_eq!(RegistryError::from(DIGEST_INVALID).error_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(RegistryError::from(DENIED).error_response().status(), StatusCode::FORBIDDEN);
+        assert_eq!(RegistryError::from(UNKNOWN_ERROR).error_response().status(),
+            StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn codes_follow_the_spec_format() {
+        let specs = [BLOB_UNKNOWN, BLOB_UPLOAD_INVALID, BLOB_UPLOAD_UNKNOWN, DIGEST_INVALID,
+            MANIFEST_BLOB_UNKNOWN, MANIFEST_INVALID, MANIFEST_UNKNOWN, NAME_INVALID,
+            NAME_UNKNOWN, SIZE_INVALID, UNAUTHORIZED, DENIED, UNSUPPORTED, TOOMANYREQUESTS,
+            UNAVAILABLE, UNKNOWN_ERROR];
+        for (code, _, _) in specs {
+            assert!(code.chars().all(|c| c.is_ascii_uppercase() || c == '_'), "{}", code);
+        }
+    }
+
+    fn body_json(res: HttpResponse) -> serde_json::Value {
+        let body = res.into_body().try_into_bytes().unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn it_returns_several_errors() {
+        let res = RegistryErrorResponse::new(vec![
+            RegistryError::from(MANIFEST_BLOB_UNKNOWN).with_detail(serde_json::json!({ "digest": "sha256:a" })),
+            RegistryError::from(MANIFEST_BLOB_UNKNOWN).with_detail(serde_json::json!({ "digest": "sha256:b" })),
+        ]).error_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = body_json(res);
+        assert_eq!(body["errors"].as_array().unwrap().len(), 2);
+        assert_eq!(body["errors"][1]["code"], "MANIFEST_BLOB_UNKNOWN");
+        assert_eq!(body["errors"][1]["detail"]["digest"], "sha256:b");
+    }
+
+    #[test]
+    fn it_omits_empty_detail() {
+        let body = body_json(RegistryError::from(BLOB_UNKNOWN).error_response());
+        assert!(body["errors"][0].get("detail").is_none());
+    }
 }
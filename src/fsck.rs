@@ -0,0 +1,145 @@
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::blob::BlobStore;
+use crate::error;
+use crate::error::RegistryError;
+use crate::meta;
+use crate::types::{Hasher, RepositoryName};
+
+/// Uploads untouched for this long are considered abandoned
+pub const STALE_UPLOAD_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Directory of the data dir that `--repair` moves damaged files into
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A blob whose content doesn't hash to its digest
+    CorruptBlob,
+    /// A manifest that doesn't hash to its digest or doesn't parse
+    CorruptManifest,
+    /// A tag pointing at a manifest that doesn't exist
+    DanglingTag,
+    /// A blob referenced by a tagged manifest that isn't stored
+    MissingBlob,
+    /// An upload nobody has written to in a long time
+    StaleUpload,
+    /// A file in the data dir that isn't named like anything we store
+    UnknownFile,
+}
+
+/// One line of the fsck report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub kind: Kind,
+    /// The digest, tag or path the problem was found at
+    pub object: String,
+    pub detail: String,
+    /// Whether `--repair` moved the object into the quarantine
+    pub quarantined: bool,
+}
+
+impl Problem {
+    pub fn new(kind: Kind, object: impl ToString, detail: impl ToString) -> Problem {
+        Problem { kind, object: object.to_string(), detail: detail.to_string(), quarantined: false }
+    }
+}
+
+/// Hashes everything a reader returns
+pub fn hash_reader(mut reader: impl Read, hasher: &mut Hasher) -> std::io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => return Ok(()),
+            read => hasher.update(&buf[..read]),
+        }
+    }
+}
+
+/// Moves a file of the data dir into the quarantine, keeping its path
+/// relative to the data dir so it is obvious where it came from
+pub fn quarantine(data_dir: &Path, path: &Path, problem: &mut Problem) -> Result<(), RegistryError> {
+    let unknown = |e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e));
+    let relative = path.strip_prefix(data_dir).unwrap_or(path);
+    let dest = data_dir.join(QUARANTINE_DIR).join(relative);
+    warn!("Quarantining {} to {}", path.display(), dest.display());
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent).map_err(unknown)?;
+    }
+    std::fs::rename(path, dest).map_err(unknown)?;
+    problem.quarantined = true;
+    Ok(())
+}
+
+/// Checks both stores and that every blob a tagged manifest or one of its
+/// referrers points to exists. Missing blobs can't be repaired from here.
+pub fn check(meta: &dyn meta::Store, blobs: &dyn BlobStore, repair: bool) -> Result<Vec<Problem>, RegistryError> {
+    let mut problems = blobs.check(repair)?;
+    problems.extend(meta.check(repair)?);
+
+    for repository in meta.list_repositories()? {
+        let repository = match RepositoryName::parse(&repository) {
+            Ok(repository) => repository,
+            Err(_) => continue,
+        };
+        // Manifests that can't be read are reported by the store checks
+        for (reference, manifest) in meta::readable_manifests(meta, &repository) {
+            for digest in manifest.blobs().filter(|digest| !blobs.blob_exists(digest)) {
+                problems.push(Problem::new(Kind::MissingBlob, digest,
+                    format!("referenced by {}:{}", repository, reference)));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::fs::Filesystem as FsBlobs;
+    use crate::meta::fs::Filesystem as FsMeta;
+    use crate::meta::{Descriptor, Manifest, RawManifest, Store};
+    use crate::types::{Digest, Reference, UploadId};
+
+    #[test]
+    fn it_finds_and_quarantines_damage() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let (meta, blobs) = (FsMeta::new(&test_path).unwrap(), FsBlobs::new(&test_path, 1024).unwrap());
+        let layer = Digest::sha256(b"layer");
+        let id = UploadId::new();
+        let mut upload = blobs.upload_writer(&id).unwrap();
+        upload.write(b"layer").unwrap();
+        upload.finish().unwrap();
+        blobs.commit(&id, &layer).unwrap();
+        let manifest = Manifest {
+            config: Some(Descriptor { digest: layer.to_string(), ..Descriptor::default() }),
+            layers: vec![Descriptor { digest: Digest::sha256(b"gone").to_string(), ..Descriptor::default() }],
+            ..Manifest::default()
+        };
+        let app = RepositoryName::parse("app").unwrap();
+        meta.put_manifest(&app, &Reference::parse("v1").unwrap(), &RawManifest::from(&manifest)).unwrap();
+        let found = check(&meta, &blobs, false).unwrap();
+        assert_eq!(found.iter().map(|p| p.kind).collect::<Vec<_>>(), vec![Kind::MissingBlob]);
+
+        let blob_path = format!("{}/blobs/sha256/{}/{}", test_path, &layer.encoded()[..2], layer.encoded());
+        std::fs::write(&blob_path, b"rot").unwrap();
+        std::fs::write(format!("{}/manifests/app/{}", test_path, manifest.digest()), b"{").unwrap();
+        std::fs::write(format!("{}/upload/not-an-upload", test_path), b"").unwrap();
+        std::os::unix::fs::symlink("/nowhere", format!("{}/manifests/app/broken", test_path)).unwrap();
+
+        let found = check(&meta, &blobs, true).unwrap();
+        let kinds: Vec<_> = found.iter().map(|p| p.kind).collect();
+        assert_eq!(kinds, vec![Kind::CorruptBlob, Kind::UnknownFile,
+            Kind::CorruptManifest, Kind::DanglingTag, Kind::DanglingTag]);
+        assert!(found.iter().all(|p| p.quarantined));
+        assert!(std::path::Path::new(&format!("{}/quarantine/blobs/sha256/{}/{}", test_path,
+            &layer.encoded()[..2], layer.encoded())).exists());
+        assert!(check(&meta, &blobs, false).unwrap().is_empty());
+    }
+}
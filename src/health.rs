@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use serde::Serialize;
+
+use crate::error::RegistryError;
+use crate::types::{Digest, RepositoryName};
+use crate::util::{block, rfc3339};
+use crate::Blobert;
+
+/// File written and removed again to tell whether the data dir is writable
+const PROBE_FILE: &str = ".readyz";
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    draining: bool,
+    /// What each check found, `ok` or the error
+    checks: BTreeMap<&'static str, String>,
+}
+
+#[derive(Serialize)]
+struct Info {
+    version: &'static str,
+    blob_backend: String,
+    meta_backend: String,
+    started: String,
+    uptime_secs: u64,
+}
+
+/// Looking up something that doesn't exist tells whether a store can answer
+/// at all. Not finding it is the expected outcome, only server errors count.
+fn responds<T>(result: Result<T, RegistryError>) -> Result<(), String> {
+    match result {
+        Err(e) if e.status_code().is_server_error() => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+fn writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(PROBE_FILE);
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} isn't writable: {}", dir.display(), e))
+}
+
+/// Liveness: answering at all is enough
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+/// Readiness: not shutting down, the data dir is writable and the storage
+/// backends respond
+pub async fn readyz(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let probe = RepositoryName::parse("blobert/readiness-probe")?;
+    let store = blobert.blob_store.clone();
+    let blob_store = block(move || Ok(responds(store.stat_blob(&Digest::sha256(PROBE_FILE.as_bytes()))))).await?;
+    let results = [
+        ("data_dir", writable(Path::new(&blobert.opts.data_dir))),
+        ("blob_store", blob_store),
+        ("meta_store", responds(blobert.meta_store.list_tags(&probe))),
+    ];
+    // Draining instances should get no new traffic, whatever their health
+    let ready = !blobert.shutdown.draining() && results.iter().all(|(_, result)| result.is_ok());
+    let checks = results.into_iter()
+        .map(|(check, result)| (check, result.err().unwrap_or_else(|| String::from("ok"))))
+        .collect();
+    let mut response = match ready {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    Ok(response.json(Readiness { ready, draining: blobert.shutdown.draining(), checks }))
+}
+
+/// Version, storage backends and uptime of this instance
+pub async fn info(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let started = blobert.started.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    Ok(HttpResponse::Ok().json(Info {
+        version: env!("CARGO_PKG_VERSION"),
+        blob_backend: blobert.opts.blob_backend.clone(),
+        meta_backend: blobert.opts.meta_backend.clone(),
+        started: rfc3339(started.as_secs()),
+        uptime_secs: blobert.started.elapsed().unwrap_or_default().as_secs(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes;
+    use crate::util::instance;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn it_reports_health_and_readiness() {
+        let blobert = instance::blobert(&[]);
+        let data_dir = blobert.opts.data_dir.clone();
+        let app = init_service(App::new().app_data(blobert).configure(routes)).await;
+
+        let res = call_service(&app, TestRequest::get().uri("/v2/").to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get("Docker-Distribution-API-Version").unwrap(), "registry/2.0");
+
+        let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), 200);
+
+        let res = call_service(&app, TestRequest::get().uri("/admin/info").to_request()).await;
+        let info: serde_json::Value = read_body_json(res).await;
+        assert_eq!(info["blob_backend"], "memory");
+
+        // Replacing the data dir with a file makes it unwritable, even as root
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        std::fs::write(&data_dir, b"").unwrap();
+        let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), 503);
+        let readiness: serde_json::Value = read_body_json(res).await;
+        assert_eq!(readiness["checks"]["blob_store"], "ok");
+        assert_ne!(readiness["checks"]["data_dir"], "ok");
+
+        let res = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), 200);
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read};
+
+use actix_web::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::Blobert;
+use crate::error::{self, RegistryError, BLOB_UNKNOWN, MANIFEST_UNKNOWN};
+use crate::meta::Manifest;
+use crate::quota;
+use crate::types::{Digest, Reference, RepositoryName};
+use crate::util::{block, parse_param, path_param};
+
+/// The parts of an OCI image config we report. Everything is optional, as
+/// images built by different tools leave out different fields.
+#[derive(Deserialize, Default)]
+pub struct ImageConfig {
+    created: Option<String>,
+    author: Option<String>,
+    architecture: Option<String>,
+    os: Option<String>,
+    variant: Option<String>,
+    config: Option<ContainerConfig>,
+    history: Option<Vec<History>>,
+}
+
+impl ImageConfig {
+    /// Reads the config blob of an image
+    pub fn read(blobert: &Blobert, manifest: &Manifest) -> Result<ImageConfig, RegistryError> {
+        let config = manifest.config.as_ref()
+            .ok_or_else(|| invalid("image indexes have no config, inspect one of their manifests"))?;
+        let digest = Digest::parse(&config.digest)?;
+        // Configs are small, anything the size of a manifest would be odd
+        blobert.limits.check_manifest_size(blobert.blob_store.stat_blob(&digest)?.size)?;
+        let mut data = Vec::new();
+        blobert.blob_store.get_blob(&digest)?.into_reader().read_to_end(&mut data)
+            .map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))?;
+        serde_json::from_slice(&data).map_err(|e| invalid(format!("{} isn't an image config: {}", digest, e)))
+    }
+
+    /// The platform in the usual `os/architecture/variant` notation
+    pub fn platform(&self) -> Option<String> {
+        let parts: Vec<&str> = [&self.os, &self.architecture, &self.variant].into_iter()
+            .map_while(|part| part.as_deref())
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("/"))
+    }
+
+    pub fn created(&self) -> Option<&str> {
+        self.created.as_deref()
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerConfig {
+    env: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    cmd: Option<Vec<String>>,
+    working_dir: Option<String>,
+    user: Option<String>,
+    labels: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct History {
+    created: Option<String>,
+    created_by: Option<String>,
+    comment: Option<String>,
+    #[serde(default)]
+    empty_layer: bool,
+    /// The layer the step produced, filled in from the manifest
+    #[serde(default, skip_deserializing)]
+    layer: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Layer {
+    digest: String,
+    media_type: String,
+    size: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ConfigSummary {
+    manifest: String,
+    config: String,
+    created: Option<String>,
+    author: Option<String>,
+    os: Option<String>,
+    architecture: Option<String>,
+    variant: Option<String>,
+    env: Vec<String>,
+    entrypoint: Vec<String>,
+    cmd: Vec<String>,
+    working_dir: Option<String>,
+    user: Option<String>,
+    labels: BTreeMap<String, String>,
+    history: Vec<History>,
+    layers: Vec<Layer>,
+}
+
+/// One entry of a layer tarball
+#[derive(Serialize, Debug, PartialEq)]
+struct File {
+    path: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    size: u64,
+    mode: String,
+    /// Where symlinks and hard links point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FileList {
+    layer: String,
+    files: Vec<File>,
+}
+
+fn invalid(e: impl std::fmt::Display) -> RegistryError {
+    RegistryError::from(error::UNSUPPORTED)
+        .with_detail(serde_json::json!({ "reason": e.to_string() }))
+}
+
+/// Matches the non-empty steps of the history with the layers they made, in
+/// order
+fn link_history(history: &mut [History], layers: &[Layer]) {
+    let mut layers = layers.iter();
+    for step in history.iter_mut().filter(|step| !step.empty_layer) {
+        step.layer = layers.next().map(|layer| layer.digest.clone());
+    }
+}
+
+/// Layers are tarballs, compressed with gzip or zstd or not at all
+fn decompress(reader: impl Read + 'static) -> std::io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?;
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::bufread::GzDecoder::new(reader))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    })
+}
+
+fn list_files(layer: impl Read + 'static) -> std::io::Result<Vec<File>> {
+    let mut archive = tar::Archive::new(decompress(layer)?);
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => "file",
+            tar::EntryType::Directory => "dir",
+            tar::EntryType::Symlink => "symlink",
+            tar::EntryType::Link => "hardlink",
+            tar::EntryType::Char | tar::EntryType::Block => "device",
+            tar::EntryType::Fifo => "fifo",
+            _ => "other",
+        };
+        files.push(File {
+            path: entry.path()?.to_string_lossy().to_string(),
+            kind,
+            size: entry.size(),
+            mode: format!("{:o}", header.mode()?),
+            link: entry.link_name()?.map(|link| link.to_string_lossy().to_string()),
+        });
+    }
+    Ok(files)
+}
+
+/// The platform, runtime settings and history of an image, from its config
+#[tracing::instrument(skip_all)]
+pub async fn config(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let reference = Reference::parse(path_param(&req, "reference")?)
+        .map_err(|_| RegistryError::from(MANIFEST_UNKNOWN))?;
+
+    let raw = blobert.meta_store.get_manifest(&namespace, &reference)?;
+    let manifest = raw.manifest().clone();
+    let (b, m) = (blobert.clone(), manifest.clone());
+    let image = block(move || ImageConfig::read(&b, &m)).await?;
+
+    let container = image.config.unwrap_or_default();
+    let layers: Vec<Layer> = manifest.layers.iter()
+        .map(|l| Layer { digest: l.digest.clone(), media_type: l.media_type.clone(), size: l.size })
+        .collect();
+    let mut history = image.history.unwrap_or_default();
+    link_history(&mut history, &layers);
+    Ok(HttpResponse::Ok().json(ConfigSummary {
+        manifest: raw.digest().to_string(),
+        config: manifest.config.map(|c| c.digest).unwrap_or_default(),
+        created: image.created,
+        author: image.author,
+        os: image.os,
+        architecture: image.architecture,
+        variant: image.variant,
+        env: container.env.unwrap_or_default(),
+        entrypoint: container.entrypoint.unwrap_or_default(),
+        cmd: container.cmd.unwrap_or_default(),
+        working_dir: container.working_dir,
+        user: container.user,
+        labels: container.labels.unwrap_or_default(),
+        history,
+        layers,
+    }))
+}
+
+/// The files inside a layer
+#[tracing::instrument(skip_all)]
+pub async fn files(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let digest: Digest = parse_param(&req, "digest")?;
+
+    let (b, layer) = (blobert.clone(), digest.clone());
+    let files = block(move || {
+        // Blobs are shared between repositories, so only list the layers of
+        // images in this one
+        if !quota::repository_blobs(b.meta_store.as_ref(), &namespace)?.contains(&layer) {
+            return Err(RegistryError::from(BLOB_UNKNOWN));
+        }
+        b.blob_store.get_blob(&layer).map(|blob| list_files(blob.into_reader()))
+    }).await?
+        .map_err(|e| invalid(format!("{} isn't a layer tarball: {}", digest, e)))?;
+    Ok(HttpResponse::Ok().json(FileList { layer: digest.to_string(), files }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_lists_the_files_of_compressed_layers() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "etc/motd", &b"hello"[..]).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, "etc/issue", "motd").unwrap();
+        let layer = builder.into_inner().unwrap();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gzip, &layer).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(&layer[..], 3).unwrap();
+        for data in [layer, gzip, zstd] {
+            let files = list_files(std::io::Cursor::new(data)).unwrap();
+            assert_eq!(files, vec![
+                File { path: "etc/motd".into(), kind: "file", size: 5, mode: "644".into(), link: None },
+                File { path: "etc/issue".into(), kind: "symlink", size: 0, mode: "777".into(),
+                    link: Some("motd".into()) },
+            ]);
+        }
+        assert!(list_files(std::io::Cursor::new(b"not a tarball".repeat(100))).is_err());
+    }
+
+    #[test]
+    fn it_matches_history_with_layers() {
+        let config = r#"{
+            "architecture": "amd64", "os": "linux",
+            "config": { "Env": ["PATH=/bin"], "Entrypoint": null, "Labels": { "team": "a" } },
+            "history": [
+                { "created_by": "ADD rootfs.tar /" },
+                { "created_by": "ENV PATH=/bin", "empty_layer": true },
+                { "created_by": "RUN make" }
+            ]
+        }"#;
+        let image: ImageConfig = serde_json::from_str(config).unwrap();
+        assert_eq!(image.platform().as_deref(), Some("linux/amd64"));
+        assert_eq!(image.config.as_ref().unwrap().env.as_deref(), Some(&[String::from("PATH=/bin")][..]));
+        let layers: Vec<Layer> = ["sha256:aa", "sha256:bb"].iter()
+            .map(|d| Layer { digest: d.to_string(), media_type: String::new(), size: None })
+            .collect();
+        let mut history = image.history.unwrap();
+        link_history(&mut history, &layers);
+        let linked: Vec<_> = history.iter().map(|step| step.layer.as_deref()).collect();
+        assert_eq!(linked, vec![Some("sha256:aa"), None, Some("sha256:bb")]);
+    }
+
+    #[actix_web::test]
+    async fn it_lists_only_layers_of_the_repository() {
+        use crate::meta::{Descriptor, RawManifest};
+        use crate::types::UploadId;
+        use crate::routes;
+        use crate::util::instance;
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use actix_web::App;
+
+        let blobert = instance::blobert(&[]);
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_ustar();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "etc/motd", &b"hello"[..]).unwrap();
+        let layer = builder.into_inner().unwrap();
+        let digest = Digest::sha256(&layer);
+        let id = UploadId::new();
+        let mut writer = blobert.blob_store.upload_writer(&id).unwrap();
+        writer.write(&layer).unwrap();
+        writer.finish().unwrap();
+        blobert.blob_store.commit(&id, &digest).unwrap();
+        let manifest = Manifest {
+            layers: vec![Descriptor { digest: digest.to_string(), size: Some(layer.len() as i64), ..Descriptor::default() }],
+            ..Manifest::default()
+        };
+        blobert.meta_store.put_manifest(&"app".parse().unwrap(), &"v1".parse().unwrap(),
+            &RawManifest::from(&manifest)).unwrap();
+        blobert.meta_store.put_manifest(&"other".parse().unwrap(), &"v1".parse().unwrap(),
+            &RawManifest::from(&Manifest::default())).unwrap();
+        let app = init_service(App::new().app_data(blobert).configure(routes)).await;
+
+        let files = format!("/admin/images/app/layers/{}/files", digest);
+        assert_eq!(call_service(&app, TestRequest::get().uri(&files).to_request()).await.status(), 200);
+        let files = format!("/admin/images/other/layers/{}/files", digest);
+        assert_eq!(call_service(&app, TestRequest::get().uri(&files).to_request()).await.status(), 404);
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+
+use log::{debug, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::blob::BlobStore;
+use crate::error;
+use crate::error::RegistryError;
+use crate::meta::{self, Descriptor, RawManifest};
+use crate::types::{Digest, Hasher, Reference, RepositoryName, UploadId};
+use crate::util::to_json;
+
+const LAYOUT_VERSION: &str = "1.0.0";
+const INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
+/// Annotation holding the tag of a manifest in `index.json`
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// Annotation holding the full image name, as written by containerd
+const IMAGE_NAME: &str = "io.containerd.image.name";
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageLayout {
+    image_layout_version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Index {
+    schema_version: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    manifests: Vec<Descriptor>,
+}
+
+fn io_error(e: std::io::Error) -> RegistryError {
+    RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e))
+}
+
+/// Splits `<repository>[:<tag>]` or `<repository>@<digest>`
+pub fn parse_image(image: &str) -> Result<(RepositoryName, Option<Reference>), RegistryError> {
+    if let Some((name, digest)) = image.split_once('@') {
+        return Ok((RepositoryName::parse(name)?, Some(Reference::parse(digest)?)))
+    }
+    // Only a colon after the last slash separates a tag
+    let tag_start = image.rfind(':').filter(|&i| !image[i..].contains('/'));
+    match tag_start {
+        Some(i) => Ok((RepositoryName::parse(&image[..i])?, Some(Reference::parse(&image[i + 1..])?))),
+        None => Ok((RepositoryName::parse(image)?, None)),
+    }
+}
+
+fn append<W: Write>(tar: &mut tar::Builder<W>, path: &str, size: u64, data: impl Read) -> Result<(), RegistryError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    tar.append_data(&mut header, path, data).map_err(io_error)
+}
+
+fn blob_path(digest: &Digest) -> String {
+    format!("blobs/{}/{}", digest.algorithm().name(), digest.encoded())
+}
+
+/// Writes a manifest after everything it references: its blobs, or the
+/// manifests an index lists
+fn write_manifest<W: Write>(tar: &mut tar::Builder<W>, meta: &dyn meta::Store, blobs: &dyn BlobStore,
+    repository: &RepositoryName, manifest: &RawManifest, written: &mut HashSet<Digest>) -> Result<(), RegistryError>
+{
+    for descriptor in manifest.manifest().descriptors() {
+        let digest = Digest::parse(&descriptor.digest)?;
+        if written.insert(digest.clone()) {
+            debug!("Exporting blob {}", digest);
+            let size = blobs.stat_blob(&digest)?.size;
+            append(tar, &blob_path(&digest), size, blobs.get_blob(&digest)?.into_reader())?;
+        }
+    }
+    for child in manifest.manifest().children() {
+        if !written.contains(&child) {
+            let child = meta.get_manifest(repository, &Reference::Digest(child))?;
+            write_manifest(tar, meta, blobs, repository, &child, written)?;
+        }
+    }
+    let digest = manifest.digest();
+    if written.insert(digest.clone()) {
+        append(tar, &blob_path(&digest), manifest.data().len() as u64, manifest.data())?;
+    }
+    Ok(())
+}
+
+/// Writes the tagged manifests of a repository, or just the given one, and
+/// every blob and manifest they reference as an OCI image layout tarball.
+/// Returns the number of manifests listed in its `index.json`.
+pub fn export(meta: &dyn meta::Store, blobs: &dyn BlobStore, repository: &RepositoryName,
+    reference: Option<&Reference>, out: impl Write) -> Result<usize, RegistryError>
+{
+    let references = match reference {
+        Some(reference) => vec![reference.clone()],
+        None => meta.list_tags(repository)?.iter()
+            .map(|tag| Reference::parse(tag))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    if references.is_empty() {
+        return Err(RegistryError::from(error::NAME_UNKNOWN)
+            .with_detail(serde_json::json!({ "name": repository.as_str() })))
+    }
+
+    let mut tar = tar::Builder::new(out);
+    let layout = to_json(&ImageLayout { image_layout_version: LAYOUT_VERSION.to_string() })?;
+    append(&mut tar, "oci-layout", layout.len() as u64, layout.as_slice())?;
+
+    let mut written = HashSet::new();
+    let mut index = Index {
+        schema_version: 2,
+        media_type: Some(INDEX_MEDIA_TYPE.to_string()),
+        manifests: vec![],
+    };
+    for reference in &references {
+        let manifest = meta.get_manifest(repository, reference)?;
+        write_manifest(&mut tar, meta, blobs, repository, &manifest, &mut written)?;
+        let digest = manifest.digest();
+        let mut annotations = BTreeMap::new();
+        match reference {
+            Reference::Tag(tag) => {
+                annotations.insert(REF_NAME.to_string(), tag.clone());
+                annotations.insert(IMAGE_NAME.to_string(), format!("{}:{}", repository, tag));
+            },
+            Reference::Digest(_) => {
+                annotations.insert(IMAGE_NAME.to_string(), format!("{}@{}", repository, digest));
+            }
+        }
+        index.manifests.push(Descriptor {
+            media_type: manifest.media_type().to_string(),
+            digest: digest.to_string(),
+            size: Some(manifest.data().len() as i64),
+            annotations: Some(annotations),
+            ..Descriptor::default()
+        });
+    }
+
+    let data = to_json(&index)?;
+    append(&mut tar, "index.json", data.len() as u64, data.as_slice())?;
+    tar.into_inner().and_then(|mut out| out.flush()).map_err(io_error)?;
+    Ok(index.manifests.len())
+}
+
+/// Copies a blob into the store, refusing it if the content doesn't match
+/// its digest. Returns whether the store didn't have the blob yet.
+fn import_blob(blobs: &dyn BlobStore, digest: &Digest, data: &mut impl Read, buf_size: usize) -> Result<bool, RegistryError> {
+    if blobs.blob_exists(digest) {
+        debug!("Blob {} already present", digest);
+        return Ok(false)
+    }
+    debug!("Importing blob {}", digest);
+    let id = UploadId::new();
+    let mut upload = blobs.upload_writer(&id)?;
+    let mut hasher = Hasher::new(digest.algorithm());
+    let mut buf = vec![0; buf_size.max(1)];
+    let copied = loop {
+        let read = match data.read(&mut buf) {
+            Ok(0) => break upload.finish().map(|_| ()),
+            Ok(read) => read,
+            Err(e) => break Err(io_error(e)),
+        };
+        hasher.update(&buf[..read]);
+        if let Err(e) = upload.write(&buf[..read]) {
+            break Err(e)
+        }
+    };
+    let actual = hasher.finish();
+    let result = match copied {
+        Ok(()) if actual != *digest => Err(RegistryError::from(error::DIGEST_INVALID)
+            .with_detail(serde_json::json!({ "expected": digest.as_str(), "actual": actual.as_str() }))),
+        Ok(()) => return blobs.commit(&id, digest).map(|_| true),
+        Err(e) => Err(e),
+    };
+    // Nothing will ever commit the upload, so don't leave its data behind
+    if let Err(e) = blobs.cancel_upload(&id) {
+        debug!("Cancelling upload {} failed: {}", id, e);
+    }
+    result
+}
+
+/// Works out where a manifest listed in `index.json` should be stored
+fn destination(descriptor: &Descriptor, digest: &Digest, repository: Option<&RepositoryName>)
+    -> Result<(RepositoryName, Reference), RegistryError>
+{
+    let annotations = descriptor.annotations.clone().unwrap_or_default();
+    let named = annotations.get(IMAGE_NAME).map(|name| parse_image(name)).transpose()?;
+    // Some tools put a full image name here rather than just the tag
+    let tag = annotations.get(REF_NAME).and_then(|tag| Reference::parse(tag).ok());
+    let name = match (repository, &named) {
+        (Some(repository), _) => repository.clone(),
+        (None, Some((name, _))) => name.clone(),
+        (None, None) => return Err(RegistryError::from_err(error::NAME_INVALID, format!(
+            "manifest {} has no {} annotation, pass a repository to import into", digest, IMAGE_NAME).into()))
+    };
+    let reference = tag
+        .or_else(|| named.and_then(|(_, reference)| reference))
+        .unwrap_or_else(|| Reference::Digest(digest.clone()));
+    Ok((name, reference))
+}
+
+/// Reads a manifest from the imported blobs, along with every manifest below
+/// it if it is an index. Children come first, so they are stored before the
+/// index that lists them.
+fn read_manifests(blobs: &dyn BlobStore, digest: &Digest, found: &mut Vec<RawManifest>) -> Result<(), RegistryError> {
+    let mut data = Vec::new();
+    blobs.get_blob(digest)?.into_reader().read_to_end(&mut data).map_err(io_error)?;
+    let manifest = RawManifest::parse(data)?;
+    // Manifests are stored by their SHA-256 digest, so that is what has to
+    // refer to them
+    if manifest.digest() != *digest {
+        return Err(RegistryError::from(error::UNSUPPORTED).with_detail(serde_json::json!({
+            "reason": format!("manifest {} has to be referenced as {}", digest, manifest.digest()),
+        })))
+    }
+    for child in manifest.manifest().manifests.iter().flatten() {
+        read_manifests(blobs, &Digest::parse(&child.digest)?, found)?;
+    }
+    found.push(manifest);
+    Ok(())
+}
+
+/// Loads every blob of an OCI image layout tarball, recording the ones that
+/// are new, and reads the manifests listed in its `index.json` along with
+/// where they should be stored
+#[allow(clippy::type_complexity)]
+fn load(blobs: &dyn BlobStore, input: impl Read, repository: Option<&RepositoryName>, buf_size: usize,
+    added: &mut Vec<Digest>) -> Result<Vec<((RepositoryName, Reference), Vec<RawManifest>)>, RegistryError>
+{
+    let invalid = |message: String| RegistryError::from_err(error::MANIFEST_INVALID, message.into());
+    let mut archive = tar::Archive::new(input);
+    let mut layout = None;
+    let mut index = None;
+    for entry in archive.entries().map_err(io_error)? {
+        let mut entry = entry.map_err(io_error)?;
+        let path = entry.path().map_err(io_error)?.to_string_lossy().to_string();
+        let path = path.trim_start_matches("./");
+        match path {
+            "oci-layout" => layout = Some(serde_json::from_reader::<_, ImageLayout>(&mut entry)
+                .map_err(|e| invalid(format!("unreadable oci-layout: {}", e)))?),
+            "index.json" => index = Some(serde_json::from_reader::<_, Index>(&mut entry)
+                .map_err(|e| invalid(format!("unreadable index.json: {}", e)))?),
+            _ => {
+                let blob = path.strip_prefix("blobs/").and_then(|rest| rest.split_once('/'));
+                if let (Some((algorithm, encoded)), tar::EntryType::Regular) = (blob, entry.header().entry_type()) {
+                    let digest = Digest::parse(&format!("{}:{}", algorithm, encoded))?;
+                    if import_blob(blobs, &digest, &mut entry, buf_size)? {
+                        added.push(digest);
+                    }
+                }
+            }
+        }
+    }
+
+    match layout {
+        Some(layout) if layout.image_layout_version == LAYOUT_VERSION => {},
+        Some(layout) => return Err(invalid(format!(
+            "unsupported image layout version {}", layout.image_layout_version))),
+        None => return Err(invalid(String::from("not an OCI image layout, oci-layout is missing"))),
+    }
+    let index = index.ok_or_else(|| invalid(String::from("index.json is missing")))?;
+
+    let mut manifests = Vec::new();
+    for descriptor in &index.manifests {
+        let digest = Digest::parse(&descriptor.digest)?;
+        let mut found = Vec::new();
+        read_manifests(blobs, &digest, &mut found)
+            .map_err(|e| invalid(format!("unreadable manifest {}: {}", digest, e)))?;
+        manifests.push((destination(descriptor, &digest, repository)?, found));
+    }
+    Ok(manifests)
+}
+
+/// Loads every blob of an OCI image layout tarball and stores the manifests
+/// listed in its `index.json`, and for indexes the manifests they list, as
+/// they are. Manifests go to the repository named in their annotations
+/// unless `repository` is given. Returns the number of manifests listed in
+/// `index.json`.
+///
+/// Nothing is stored unless every listed manifest can be read, and the blobs
+/// a refused import brought along are removed again.
+pub fn import(meta: &dyn meta::Store, blobs: &dyn BlobStore, input: impl Read,
+    repository: Option<&RepositoryName>, buf_size: usize) -> Result<usize, RegistryError>
+{
+    let mut added = Vec::new();
+    let manifests = match load(blobs, input, repository, buf_size, &mut added) {
+        Ok(manifests) => manifests,
+        Err(e) => {
+            for digest in &added {
+                if let Err(e) = blobs.delete_blob(digest) {
+                    warn!("Removing blob {} of a refused import failed: {}", digest, e);
+                }
+            }
+            return Err(e)
+        },
+    };
+    for ((name, reference), found) in &manifests {
+        let Some((manifest, children)) = found.split_last() else { continue };
+        for child in children {
+            meta.put_manifest(name, &Reference::Digest(child.digest()), child)?;
+        }
+        debug!("Importing manifest {} as {}:{}", manifest.digest(), name, reference);
+        meta.put_manifest(name, reference, manifest)?;
+    }
+    Ok(manifests.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::memory::Memory as MemoryBlobs;
+    use crate::meta::memory::Memory as MemoryMeta;
+    use crate::meta::{Manifest, Store, IMAGE_LAYER_MEDIA_TYPE};
+
+    fn put_blob(blobs: &dyn BlobStore, data: &[u8]) -> Digest {
+        let digest = Digest::sha256(data);
+        import_blob(blobs, &digest, &mut &data[..], 4).unwrap();
+        digest
+    }
+
+    fn push_image(meta: &dyn meta::Store, blobs: &dyn BlobStore, repository: &RepositoryName) -> Manifest {
+        let config = put_blob(blobs, b"{}");
+        let layer = put_blob(blobs, b"layer data");
+        let manifest = Manifest {
+            config: Some(Descriptor { digest: config.to_string(), ..Descriptor::default() }),
+            layers: vec![Descriptor {
+                media_type: IMAGE_LAYER_MEDIA_TYPE.to_string(),
+                digest: layer.to_string(),
+                size: Some(10),
+                ..Descriptor::default()
+            }],
+            ..Manifest::default()
+        };
+        meta.put_manifest(repository, &Reference::parse("v1").unwrap(), &RawManifest::from(&manifest)).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn it_parses_image_names() {
+        let (name, reference) = parse_image("docker.io/library/app:v1").unwrap();
+        assert_eq!(name.as_str(), "docker.io/library/app");
+        assert_eq!(reference.unwrap().as_str(), "v1");
+        let (name, reference) = parse_image("library/app").unwrap();
+        assert_eq!(name.as_str(), "library/app");
+        assert!(reference.is_none());
+        let digest = Digest::sha256(b"");
+        let (_, reference) = parse_image(&format!("app@{}", digest)).unwrap();
+        assert_eq!(reference, Some(Reference::Digest(digest)));
+    }
+
+    #[test]
+    fn it_round_trips_through_a_tarball() {
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        let repository = RepositoryName::parse("app").unwrap();
+        let manifest = push_image(&meta, &blobs, &repository);
+
+        let mut tarball = Vec::new();
+        assert_eq!(export(&meta, &blobs, &repository, None, &mut tarball).unwrap(), 1);
+
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        assert_eq!(import(&meta, &blobs, tarball.as_slice(), None, 1024).unwrap(), 1);
+        let imported = meta.get_manifest(&repository, &Reference::parse("v1").unwrap()).unwrap();
+        assert_eq!(imported.manifest(), &manifest);
+        for layer in &manifest.layers {
+            assert!(blobs.blob_exists(&Digest::parse(&layer.digest).unwrap()));
+        }
+
+        let other = RepositoryName::parse("mirror/app").unwrap();
+        import(&meta, &blobs, tarball.as_slice(), Some(&other), 1024).unwrap();
+        assert_eq!(meta.list_tags(&other).unwrap(), vec!["v1"]);
+    }
+
+    #[test]
+    fn it_refuses_tampered_blobs() {
+        let blobs = MemoryBlobs::new(1024, 1024);
+        let digest = Digest::sha256(b"original");
+        assert!(import_blob(&blobs, &digest, &mut &b"tampered"[..], 1024).is_err());
+        assert!(!blobs.blob_exists(&digest));
+
+        // The refused data doesn't count toward the limit
+        let blobs = MemoryBlobs::new(10, 1024);
+        for _ in 0..2 {
+            assert!(import_blob(&blobs, &digest, &mut &b"tampered"[..], 1024).is_err());
+        }
+        put_blob(&blobs, b"original");
+    }
+
+    /// An image layout as `docker buildx` writes it: a pretty-printed index
+    /// of pretty-printed image manifests, all listed under their own digest
+    fn layout_with_index(config: &Digest, layer: &Digest) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let image = format!(r#"{{
+  "schemaVersion": 2,
+  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+  "config": {{ "mediaType": "application/vnd.oci.image.config.v1+json", "digest": "{}", "size": 2 }},
+  "layers": [ {{ "mediaType": "{}", "digest": "{}", "size": 10 }} ]
+}}"#, config, IMAGE_LAYER_MEDIA_TYPE, layer).into_bytes();
+        let index = format!(r#"{{
+  "schemaVersion": 2,
+  "mediaType": "{}",
+  "manifests": [
+    {{
+      "mediaType": "application/vnd.oci.image.manifest.v1+json",
+      "digest": "{}",
+      "size": {},
+      "platform": {{ "architecture": "arm64", "os": "linux", "variant": "v8" }}
+    }}
+  ]
+}}"#, INDEX_MEDIA_TYPE, Digest::sha256(&image), image.len()).into_bytes();
+        let layout = to_json(&Index {
+            schema_version: 2,
+            media_type: Some(INDEX_MEDIA_TYPE.to_string()),
+            manifests: vec![Descriptor {
+                media_type: INDEX_MEDIA_TYPE.to_string(),
+                digest: Digest::sha256(&index).to_string(),
+                size: Some(index.len() as i64),
+                annotations: Some([(REF_NAME.to_string(), String::from("v1"))].into()),
+                ..Descriptor::default()
+            }],
+        }).unwrap();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        append(&mut tar, "oci-layout", 30, &br#"{"imageLayoutVersion":"1.0.0"}"#[..]).unwrap();
+        for data in [&b"{}"[..], &b"layer data"[..], &image, &index] {
+            append(&mut tar, &blob_path(&Digest::sha256(data)), data.len() as u64, data).unwrap();
+        }
+        append(&mut tar, "index.json", layout.len() as u64, layout.as_slice()).unwrap();
+        (tar.into_inner().unwrap(), image, index)
+    }
+
+    #[test]
+    fn it_imports_indexes_byte_for_byte() {
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        let repository = RepositoryName::parse("multi").unwrap();
+        let (tarball, image, index) = layout_with_index(&Digest::sha256(b"{}"), &Digest::sha256(b"layer data"));
+        assert_eq!(import(&meta, &blobs, tarball.as_slice(), Some(&repository), 1024).unwrap(), 1);
+
+        let imported = meta.get_manifest(&repository, &Reference::parse("v1").unwrap()).unwrap();
+        assert_eq!(imported.data(), index.as_slice());
+        assert_eq!(imported.media_type(), INDEX_MEDIA_TYPE);
+        let child = meta.get_manifest(&repository, &Reference::Digest(Digest::sha256(&image))).unwrap();
+        assert_eq!(child.data(), image.as_slice());
+
+        // Exporting writes the index along with what it lists
+        let mut exported = Vec::new();
+        export(&meta, &blobs, &repository, None, &mut exported).unwrap();
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        import(&meta, &blobs, exported.as_slice(), None, 1024).unwrap();
+        let child = meta.get_manifest(&repository, &Reference::Digest(Digest::sha256(&image))).unwrap();
+        assert_eq!(child.data(), image.as_slice());
+        assert!(blobs.blob_exists(&Digest::sha256(b"layer data")));
+    }
+
+    #[test]
+    fn it_cleans_up_refused_imports() {
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        let kept = put_blob(&blobs, b"{}");
+        let (tarball, _, index) = layout_with_index(&kept, &Digest::sha256(b"layer data"));
+        // Without a repository to import into, nothing may be stored
+        assert!(import(&meta, &blobs, tarball.as_slice(), None, 1024).is_err());
+        assert!(meta.list_repositories().unwrap().is_empty());
+        assert!(!blobs.blob_exists(&Digest::sha256(b"layer data")));
+        assert!(!blobs.blob_exists(&Digest::sha256(&index)));
+        // Blobs that were there before stay
+        assert!(blobs.blob_exists(&kept));
+    }
+}
@@ -0,0 +1,193 @@
+use std::io::Write;
+use std::time::Duration;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{HttpMessage, HttpRequest};
+use env_logger::Env;
+use log::info;
+
+use crate::util::{now, rfc3339};
+use crate::Options;
+
+/// Target of the access log lines, so they can be filtered with RUST_LOG
+pub const ACCESS_TARGET: &str = "blobert::access";
+
+/// Carries the request ID in both directions
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Names accepted by --log-format
+pub const FORMATS: &[&str] = &["json", "text"];
+
+/// Identifies a request in the access log, the audit log and registry events
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+/// Sets up the logger. In JSON mode every line is a JSON object, and access
+/// log lines carry their fields at the top level.
+pub fn init(opts: &Options) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or(&opts.log_level));
+    if opts.log_format == "json" {
+        builder.format(|buf, record| {
+            let mut line = match record.target() {
+                ACCESS_TARGET => serde_json::from_str(&record.args().to_string()).unwrap_or_default(),
+                _ => serde_json::Map::new(),
+            };
+            line.insert(String::from("time"), rfc3339(now()).into());
+            line.insert(String::from("level"), record.level().as_str().into());
+            if record.target() != ACCESS_TARGET {
+                line.insert(String::from("target"), record.target().into());
+                line.insert(String::from("message"), record.args().to_string().into());
+            }
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        });
+    }
+    builder.init();
+}
+
+/// Tags a request with an ID before it is handled, keeping the one a proxy
+/// in front of us may have assigned. Returns the ID.
+pub fn start(req: &ServiceRequest) -> String {
+    let id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    req.extensions_mut().insert(RequestId(id.clone()));
+    id
+}
+
+/// The ID given to the request by `start`
+pub fn request_id(req: &HttpRequest) -> String {
+    req.extensions().get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default()
+}
+
+/// The user an authenticating proxy in front of us says made the request, if
+/// we were told which header it uses
+pub fn user(req: &HttpRequest, opts: &Options) -> Option<String> {
+    let header = opts.user_header.as_deref()?;
+    req.headers().get(header)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// Client address, as reported by a proxy if there is one
+pub fn remote(req: &HttpRequest) -> String {
+    req.connection_info().realip_remote_addr().unwrap_or_default().to_string()
+}
+
+/// What a request does, in terms of the registry API
+fn action(method: &str, route: &str) -> &'static str {
+    let kind = route.trim_start_matches("/v2/{namespace:.+}/");
+    match (method, kind) {
+        ("GET", "manifests/{reference}") => "manifest.pull",
+        ("HEAD", "manifests/{reference}") => "manifest.stat",
+        ("PUT", "manifests/{reference}") => "manifest.push",
+        ("DELETE", "manifests/{reference}") => "manifest.delete",
+        ("GET", "blobs/{id}") => "blob.pull",
+        // HEAD requests report the pattern of the first resource whose path
+        // matches, which is the blob GET route
+        ("HEAD", "blobs/{id}") => "blob.stat",
+        ("POST", "blobs/uploads/") => "upload.start",
+        ("PATCH", "blobs/upload/{id}") => "upload.chunk",
+        ("PUT", "blobs/upload/{id}") => "upload.complete",
+        ("GET", _) if kind.starts_with("blobs/upload") => "upload.status",
+        ("DELETE", _) if kind.starts_with("blobs/upload") => "upload.cancel",
+        ("GET", "tags/list") => "tags.list",
+        ("GET", "/admin/images/{namespace:.+}/{reference}/config") => "image.config",
+        ("GET", "/admin/images/{namespace:.+}/layers/{digest}/files") => "layer.files",
+        _ => "other",
+    }
+}
+
+/// Logs a handled request as a JSON object
+pub fn access<B: MessageBody>(res: &ServiceResponse<B>, route: &str, elapsed: Duration) {
+    let req = res.request();
+    let opts = req.app_data::<crate::Blobert>().map(|b| &b.opts);
+    let param = |name| req.match_info().get(name).map(String::from);
+    let bytes_in = req.headers().get("Content-Length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    let bytes_out = match res.response().body().size() {
+        BodySize::Sized(size) => size,
+        _ => 0,
+    };
+    let line = serde_json::json!({
+        "request_id": request_id(req),
+        "remote": remote(req),
+        "user": opts.and_then(|opts| user(req, opts)),
+        "method": req.method().as_str(),
+        "path": req.path(),
+        "action": action(req.method().as_str(), route),
+        "repository": param("namespace"),
+        "reference": param("reference").or_else(|| param("digest")).or_else(|| param("id")),
+        "status": res.status().as_u16(),
+        "bytes_in": bytes_in,
+        "bytes_out": bytes_out,
+        "duration_ms": elapsed.as_secs_f64() * 1000.0,
+    });
+    info!(target: ACCESS_TARGET, "{}", line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn it_names_registry_actions() {
+        use crate::routes;
+        use crate::util::instance;
+        use actix_web::dev::Service;
+        use actix_web::http::header::{HeaderName, HeaderValue};
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use actix_web::App;
+
+        // Names each request the way the access log does, from the pattern
+        // the real routes report
+        let app = init_service(App::new()
+            .app_data(instance::blobert(&[]))
+            .wrap_fn(|req, srv| {
+                let route = req.match_pattern().unwrap_or_else(|| String::from("unmatched"));
+                let name = action(req.method().as_str(), &route);
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await?;
+                    res.headers_mut().insert(HeaderName::from_static("x-action"), HeaderValue::from_static(name));
+                    Ok(res)
+                }
+            })
+            .configure(routes)).await;
+
+        let digest = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let cases = [
+            ("GET", "/v2/team/app/manifests/latest".to_string(), "manifest.pull"),
+            ("HEAD", "/v2/team/app/manifests/latest".to_string(), "manifest.stat"),
+            ("PUT", "/v2/team/app/manifests/latest".to_string(), "manifest.push"),
+            ("DELETE", "/v2/team/app/manifests/latest".to_string(), "manifest.delete"),
+            ("GET", format!("/v2/team/app/blobs/{}", digest), "blob.pull"),
+            ("HEAD", format!("/v2/team/app/blobs/{}", digest), "blob.stat"),
+            ("POST", "/v2/team/app/blobs/uploads/".to_string(), "upload.start"),
+            ("PATCH", "/v2/team/app/blobs/upload/1".to_string(), "upload.chunk"),
+            ("PUT", "/v2/team/app/blobs/upload/1".to_string(), "upload.complete"),
+            ("GET", "/v2/team/app/blobs/upload/1".to_string(), "upload.status"),
+            ("GET", "/v2/team/app/blobs/uploads/1".to_string(), "upload.status"),
+            ("DELETE", "/v2/team/app/blobs/upload/1".to_string(), "upload.cancel"),
+            ("DELETE", "/v2/team/app/blobs/uploads/1".to_string(), "upload.cancel"),
+            ("GET", "/v2/team/app/tags/list".to_string(), "tags.list"),
+            ("GET", "/admin/images/team/app/latest/config".to_string(), "image.config"),
+            ("GET", format!("/admin/images/team/app/layers/{}/files", digest), "layer.files"),
+            ("GET", "/metrics".to_string(), "other"),
+        ];
+        for (method, uri, expected) in cases {
+            let req = TestRequest::default()
+                .method(method.parse().unwrap())
+                .uri(&uri)
+                .to_request();
+            let res = call_service(&app, req).await;
+            assert_eq!(res.headers().get("x-action").unwrap(), expected, "{} {}", method, uri);
+        }
+    }
+}
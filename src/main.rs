@@ -1,9 +1,12 @@
 // use oci_distribution::manifest;
-use actix_web::{web, App, HttpServer, HttpResponse, Responder};
-use actix_web::middleware::Logger;
-use env_logger::Env;
+use actix_web::{web, App, HttpServer, HttpRequest, HttpResponse, Responder};
+use actix_web::dev::Service;
+use actix_web::http::header::{HeaderName, HeaderValue};
 use structopt::StructOpt;
-use log::error;
+use log::{error, info};
+use tracing::Instrument;
+
+use std::sync::Arc;
 
 mod util;
 mod error;
@@ -11,6 +14,22 @@ mod blob;
 mod upload;
 mod manifests;
 mod meta;
+mod replication;
+mod layout;
+mod types;
+mod cli;
+mod fsck;
+mod session;
+mod quota;
+mod metrics;
+mod notify;
+mod logging;
+mod audit;
+mod trace;
+mod health;
+mod shutdown;
+mod inspect;
+mod ui;
 
 #[derive(StructOpt, Clone)]
 #[structopt(name = "blobert", about = "Another OCI registry")]
@@ -27,8 +46,132 @@ pub struct Options {
     #[structopt(short = "log", long, default_value = "info")]
     log_level: String,
 
+    /// Whether log lines are JSON objects or plain text
+    #[structopt(long, default_value = "json", possible_values = logging::FORMATS)]
+    log_format: String,
+
+    /// Header an authenticating proxy puts the user name in, for the access
+    /// and audit logs
+    #[structopt(long)]
+    user_header: Option<String>,
+
+    /// File recording every push, tag move and delete, `audit.log` in the
+    /// data directory by default
+    #[structopt(long)]
+    audit_log: Option<String>,
+
     #[structopt(short, long, default_value = "10MB")]
     buf_size: String,
+
+    #[structopt(short, long, default_value = "/tmp/data")]
+    data_dir: String,
+
+    /// Where to store blobs
+    #[structopt(long, default_value = "fs", possible_values = blob::BACKENDS)]
+    blob_backend: String,
+
+    /// Where to store manifests and tags
+    #[structopt(long, default_value = "fs", possible_values = meta::BACKENDS)]
+    meta_backend: String,
+
+    /// Maximum size of each in-memory store
+    #[structopt(long, default_value = "1GB")]
+    memory_limit: String,
+
+    /// Endpoint of the S3-compatible service for the s3 blob backend
+    #[structopt(long, default_value = "http://127.0.0.1:9000")]
+    s3_endpoint: String,
+
+    #[structopt(long, default_value = "blobert")]
+    s3_bucket: String,
+
+    #[structopt(long, default_value = "us-east-1")]
+    s3_region: String,
+
+    #[structopt(long, env = "AWS_ACCESS_KEY_ID", default_value = "", hide_env_values = true)]
+    s3_access_key: String,
+
+    #[structopt(long, env = "AWS_SECRET_ACCESS_KEY", default_value = "", hide_env_values = true)]
+    s3_secret_key: String,
+
+    /// Redirect blob pulls to presigned S3 URLs instead of proxying them
+    #[structopt(long)]
+    s3_presign: bool,
+
+    /// Store uploaded uncompressed tar layers compressed with zstd. Clients
+    /// still get the original bytes. Only supported by the fs blob backend.
+    #[structopt(long)]
+    compress_layers: bool,
+
+    /// Seconds an upload session may sit idle before it and its data are
+    /// removed
+    #[structopt(long, default_value = "3600")]
+    upload_ttl: u64,
+
+    /// Seconds requests in progress get to finish after SIGTERM. Uploads
+    /// still going after that are saved, to be resumed after a restart.
+    #[structopt(long, default_value = "30")]
+    shutdown_grace: u64,
+
+    /// Let users of the web UI delete tags
+    #[structopt(long)]
+    ui_allow_delete: bool,
+
+    /// Largest blob that can be uploaded, e.g. 10GB
+    #[structopt(long)]
+    max_blob_size: Option<String>,
+
+    /// Largest manifest that can be pushed
+    #[structopt(long, default_value = "4MB")]
+    max_manifest_size: String,
+
+    /// Storage quota of every repository without one in the quota config
+    #[structopt(long)]
+    repository_quota: Option<String>,
+
+    /// JSON file mapping repository names, or prefixes ending in `/`, to
+    /// storage quotas
+    #[structopt(long)]
+    quota_config: Option<String>,
+
+    /// JSON file listing HTTP endpoints to send registry events to
+    #[structopt(long)]
+    notification_config: Option<String>,
+
+    /// Seconds to wait for a notification endpoint to accept a connection
+    #[structopt(long, default_value = "5")]
+    notification_connect_timeout: u64,
+
+    /// Seconds a notification endpoint gets to answer, before the delivery
+    /// counts as failed
+    #[structopt(long, default_value = "10")]
+    notification_timeout: u64,
+
+    /// Base URL of an OpenTelemetry collector to send spans to with OTLP over
+    /// HTTP, e.g. http://localhost:4318
+    #[structopt(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Service name spans are reported under
+    #[structopt(long, env = "OTEL_SERVICE_NAME", default_value = "blobert")]
+    otlp_service_name: String,
+
+    /// JSON file describing replication rules
+    #[structopt(long)]
+    replication_config: Option<String>,
+
+    /// Seconds to wait for a registry to accept a connection when
+    /// replicating
+    #[structopt(long, default_value = "5")]
+    replication_connect_timeout: u64,
+
+    /// Seconds a registry gets to answer each request when replicating. Blob
+    /// transfers take as long as they take.
+    #[structopt(long, default_value = "30")]
+    replication_timeout: u64,
+
+    #[structopt(subcommand)]
+    command: Option<cli::Command>,
 }
 
 impl Options {
@@ -40,6 +183,17 @@ impl Options {
         format!("{}://{}", self.protocol, self.get_bind_addr())
     }
 
+    pub fn get_s3_config(&self) -> blob::s3::Config {
+        blob::s3::Config {
+            endpoint: self.s3_endpoint.clone(),
+            bucket: self.s3_bucket.clone(),
+            region: self.s3_region.clone(),
+            access_key: self.s3_access_key.clone(),
+            secret_key: self.s3_secret_key.clone(),
+            presign: self.s3_presign,
+        }
+    }
+
     pub fn get_buf_size_bytes(&self) -> usize {
         match byte_unit::Byte::from_str(&self.buf_size) {
             Ok(bytes) => bytes.get_bytes() as usize,
@@ -49,52 +203,245 @@ impl Options {
             }
         }
     }
+
+    pub fn get_memory_limit_bytes(&self) -> usize {
+        match byte_unit::Byte::from_str(&self.memory_limit) {
+            Ok(bytes) => bytes.get_bytes() as usize,
+            Err(e) => {
+                error!("Invalid spec for memory limit: {}", e);
+                std::process::exit(1)
+            }
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Blobert {
     pub opts: Options,
-    pub meta_store: Box<dyn meta::Store>,
-    pub blob_store: blob::Store
+    pub meta_store: Arc<dyn meta::Store>,
+    pub blob_store: Arc<dyn blob::BlobStore>,
+    pub replicator: Arc<replication::Replicator>,
+    pub sessions: Arc<session::Sessions>,
+    pub limits: Arc<quota::Limits>,
+    pub metrics: Arc<metrics::Metrics>,
+    pub notifier: Arc<notify::Notifier>,
+    pub audit: Arc<audit::AuditLog>,
+    pub started: std::time::SystemTime,
+    pub shutdown: Arc<shutdown::Shutdown>,
 }
 
 impl Blobert {
-    fn new() -> Blobert {
-        let meta_store = meta::fs::Filesystem::new("/tmp/data").unwrap();
-        let opts = Options::from_args();
-        let buf_size = opts.get_buf_size_bytes();
-        Blobert {
+    fn new(opts: Options) -> std::io::Result<Blobert> {
+        let other = |e: error::RegistryError|
+            std::io::Error::other(e.to_string());
+        let meta_store = meta::open(&opts).map_err(other)?;
+        let blob_store = blob::open(&opts).map_err(other)?;
+        let replicator = replication::Replicator::from_options(&opts)?;
+        let sessions = session::Sessions::load(std::time::Duration::from_secs(opts.upload_ttl),
+            &session::saved_path(&opts.data_dir))?;
+        let limits = quota::Limits::from_options(&opts)?;
+        let notifier = notify::Notifier::from_options(&opts)?;
+        let audit = audit::AuditLog::from_options(&opts)?;
+        Ok(Blobert {
             opts,
-            meta_store: Box::new(meta_store),
-            blob_store: blob::Store::new("/tmp/data", buf_size)
-        }
+            meta_store: Arc::from(meta_store),
+            blob_store: Arc::from(blob_store),
+            replicator: Arc::new(replicator),
+            sessions: Arc::new(sessions),
+            limits: Arc::new(limits),
+            metrics: Arc::new(metrics::Metrics::default()),
+            notifier: Arc::new(notifier),
+            audit: Arc::new(audit),
+            started: std::time::SystemTime::now(),
+            shutdown: Arc::new(shutdown::Shutdown::default()),
+        })
+    }
+
+    /// The registry state the app was built with
+    pub fn from_request(req: &HttpRequest) -> Result<&Blobert, error::RegistryError> {
+        req.app_data().ok_or_else(|| error::RegistryError::from_err(error::UNKNOWN_ERROR,
+            "registry state missing from app".into()))
     }
 
+    /// Tells clients this is a registry speaking the v2 API. There is no
+    /// authentication, so there is never a challenge to answer with.
     async fn v2() -> impl Responder {
-        HttpResponse::Ok().body("true")
+        HttpResponse::Ok()
+            .append_header(("Docker-Distribution-API-Version", "registry/2.0"))
+            .json(serde_json::json!({}))
     }
+
+    /// Storage used by every repository and quota prefix
+    async fn usage(req: HttpRequest) -> Result<HttpResponse, error::RegistryError> {
+        let blobert = Blobert::from_request(&req)?;
+        let b = blobert.clone();
+        let usage = util::block(move || b.limits.usage(b.meta_store.as_ref(), b.blob_store.as_ref())).await?;
+        Ok(HttpResponse::Ok().json(usage))
+    }
+}
+
+/// Registers the registry API routes
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg
+        .route("/v2/", web::get().to(Blobert::v2))
+        .route("/healthz", web::get().to(health::healthz))
+        .route("/readyz", web::get().to(health::readyz))
+        .route("/admin/info", web::get().to(health::info))
+        .route("/admin/usage", web::get().to(Blobert::usage))
+        .route("/admin/images/{namespace:.+}/{reference}/config", web::get().to(inspect::config))
+        .route("/admin/images/{namespace:.+}/layers/{digest}/files", web::get().to(inspect::files))
+        .route("/ui", web::get().to(ui::redirect))
+        .route("/ui/", web::get().to(ui::index))
+        .route("/ui/app.js", web::get().to(ui::script))
+        .route("/ui/api/repositories", web::get().to(ui::repositories))
+        .route("/ui/api/repositories/{namespace:.+}", web::get().to(ui::repository))
+        .route("/ui/api/repositories/{namespace:.+}/tags/{reference}", web::delete().to(ui::delete_tag))
+        .route("/metrics", web::get().to(metrics::metrics))
+        .route("/v2/{namespace:.+}/blobs/{id}", web::get().to(upload::get_blob))
+        .route("/v2/{namespace:.+}/blobs/uploads/", web::post().to(upload::start_blob_upload))
+        .route("/v2/{namespace:.+}/blobs/upload/{id}", web::patch().to(upload::patch_blob_data))
+        .route("/v2/{namespace:.+}/blobs/upload/{id}", web::put().to(upload::put_blob_upload_complete))
+        .route("/v2/{namespace:.+}/blobs/upload/{id}", web::get().to(upload::get_upload_status))
+        .route("/v2/{namespace:.+}/blobs/uploads/{id}", web::get().to(upload::get_upload_status))
+        .route("/v2/{namespace:.+}/blobs/upload/{id}", web::delete().to(upload::cancel_blob_upload))
+        .route("/v2/{namespace:.+}/blobs/uploads/{id}", web::delete().to(upload::cancel_blob_upload))
+        .route("/v2/{namespace:.+}/blobs/{digest}", web::head().to(upload::blob_exists))
+        .route("/v2/{namespace:.+}/tags/list", web::get().to(manifests::list_tags))
+        .route("/v2/{namespace:.+}/manifests/{reference}", web::put().to(manifests::put_manifest))
+        .route("/v2/{namespace:.+}/manifests/{reference}", web::head().to(manifests::get_manifest))
+        .route("/v2/{namespace:.+}/manifests/{reference}", web::get().to(manifests::get_manifest))
+        .route("/v2/{namespace:.+}/manifests/{reference}", web::delete().to(manifests::delete_manifest));
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> std::io::Result<()> {
     let opts = Options::from_args();
+    logging::init(&opts);
+
+    match &opts.command {
+        None | Some(cli::Command::Serve) => actix_web::rt::System::new().block_on(serve(opts)),
+        // Commands work on the stores directly, so they run outside of the
+        // runtime, where blocking is fine
+        Some(command) => {
+            // Commands finish before any spans would be exported
+            trace::disable();
+            cli::run(&opts, command).map_err(|e| std::io::Error::other(e.to_string()))
+        },
+    }
+}
+
+async fn serve(opts: Options) -> std::io::Result<()> {
     let bind_addr = opts.get_bind_addr();
-    env_logger::init_from_env(Env::default().default_filter_or(&opts.log_level));
+    if let Some(exporter) = trace::init(&opts) {
+        exporter.schedule();
+    }
+    let blobert = Blobert::new(opts)?;
+    blobert.replicator.clone().schedule();
+    blobert.sessions.clone().schedule(blobert.blob_store.clone());
+    blobert.notifier.clone().schedule();
+    let (sessions, shutdown, notifier) = (blobert.sessions.clone(), blobert.shutdown.clone(), blobert.notifier.clone());
+    // In-memory uploads don't outlive the process, so neither can their sessions
+    let keep_sessions = blobert.opts.blob_backend != "memory";
+    let saved_sessions = session::saved_path(&blobert.opts.data_dir);
+    let grace = std::time::Duration::from_secs(blobert.opts.shutdown_grace);
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(Blobert::new())
-            .wrap(Logger::new("%r"))
-            .route("/v2/", web::get().to(Blobert::v2))
-            .route("/v2/{namespace}/blobs/{id}", web::get().to(upload::get_blob))
-            .route("/v2/{namespace}/blobs/uploads/", web::post().to(upload::start_blob_upload))
-            .route("/v2/{namespace}/blobs/upload/{id}", web::patch().to(upload::patch_blob_data))
-            .route("/v2/{namespace}/blobs/upload/{id}", web::put().to(upload::put_blob_upload_complete))
-            .route("/v2/{namespace}/blobs/{digest}", web::head().to(upload::blob_exists))
-            .route("/v2/{namespace}/manifests/{reference}", web::put().to(manifests::put_manifest))
-            .route("/v2/{namespace}/manifests/{reference}", web::head().to(manifests::get_manifest))
-            .route("/v2/{namespace}/manifests/{reference}", web::get().to(manifests::get_manifest))
+            .app_data(blobert.clone())
+            .wrap_fn(|req, srv| {
+                let start = std::time::Instant::now();
+                let id = logging::start(&req);
+                let route = req.match_pattern().unwrap_or_else(|| String::from("unmatched"));
+                let method = req.method().to_string();
+                let metrics = req.app_data::<Blobert>().map(|b| b.metrics.clone());
+                let span = trace::request_span(&req, &route, &id);
+                let res = span.in_scope(|| srv.call(req));
+                async move {
+                    let mut res = res.instrument(span.clone()).await?;
+                    trace::finish_request(&span, res.status().as_u16());
+                    if let Ok(id) = HeaderValue::from_str(&id) {
+                        res.headers_mut().insert(HeaderName::from_static("x-request-id"), id);
+                    }
+                    logging::access(&res, &route, start.elapsed());
+                    if let Some(metrics) = metrics {
+                        metrics.request(&route, &method, res.status().as_u16(), start.elapsed());
+                    }
+                    Ok(res)
+                }
+            })
+            .configure(routes)
     })
+    .disable_signals()
+    .shutdown_timeout((grace + shutdown::FLUSH_TIME).as_secs())
     .bind(bind_addr)?
-    .run()
-    .await
+    .run();
+    shutdown.watch(server.handle(), grace);
+    server.await?;
+
+    notifier.flush();
+    if keep_sessions && sessions.active() > 0 {
+        let saved = sessions.save(&saved_sessions)?;
+        info!("Saved {} unfinished upload sessions", saved);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::instance;
+    use actix_web::http::Method;
+    use actix_web::test::{call_service, TestRequest};
+    use proptest::prelude::*;
+
+    fn segment() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-zA-Z0-9._:-]{0,16}",
+            "sha(256|512):[0-9a-f]{0,64}",
+            Just(String::from("..")),
+            Just(String::from("%2F")),
+            Just(String::from("%00")),
+        ]
+    }
+
+    fn request() -> impl Strategy<Value = (Method, String, Vec<u8>)> {
+        let method = prop_oneof![
+            Just(Method::GET),
+            Just(Method::HEAD),
+            Just(Method::POST),
+            Just(Method::PUT),
+            Just(Method::PATCH),
+            Just(Method::DELETE),
+        ];
+        let route = prop_oneof![
+            Just("blobs"),
+            Just("blobs/uploads"),
+            Just("blobs/upload"),
+            Just("manifests"),
+            Just("tags/list"),
+            Just(""),
+        ];
+        let body = prop_oneof![
+            prop::collection::vec(any::<u8>(), 0..256),
+            "\\{\"schemaVersion\":[0-9],\"config\":\\{\"digest\":\"[a-z0-9:]{0,10}\"\\}\\}"
+                .prop_map(String::into_bytes),
+        ];
+        (method, segment(), route, segment(), "(\\?digest=[a-z0-9:]{0,20})?", body)
+            .prop_map(|(method, namespace, route, last, query, body)|
+                (method, format!("/v2/{}/{}/{}{}", namespace, route, last, query), body))
+    }
+
+    proptest! {
+        #[test]
+        fn it_answers_malformed_requests_without_server_errors((method, uri, body) in request()) {
+            let status = actix_web::rt::System::new().block_on(async {
+                let app = instance::app(&["--memory-limit", "1MB"]).await;
+                let req = TestRequest::default()
+                    .method(method)
+                    .uri(&uri)
+                    .set_payload(body)
+                    .to_request();
+                call_service(&app, req).await.status()
+            });
+            prop_assert!(status.as_u16() < 500, "{} answered {}", uri, status);
+        }
+    }
 }
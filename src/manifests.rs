@@ -1,12 +1,18 @@
-use actix_web::{web, HttpRequest, HttpResponse, Responder, error::PayloadError};
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::Method;
 // use oci_distribution::manifest::OciManifest;
 use futures::StreamExt;
 use log::{error, debug};
 use serde::Serialize;
 
 use crate::Blobert;
-use crate::util::*;
-use crate::meta::IMAGE_MANIFEST_MEDIA_TYPE;
+use crate::audit::{self, Entry};
+use crate::error::{RegistryError, RegistryErrorResponse};
+use crate::error::{DIGEST_INVALID, MANIFEST_BLOB_UNKNOWN, MANIFEST_INVALID, MANIFEST_UNKNOWN, NAME_UNKNOWN};
+use crate::meta::RawManifest;
+use crate::notify::{Action, Target};
+use crate::types::{Digest, Reference, RepositoryName};
+use crate::util::{block, lock, parse_param, path_param, to_json};
 
 #[derive(Serialize)]
 struct PutManifestResponse {
@@ -14,71 +20,307 @@ struct PutManifestResponse {
     tags: Vec<String>
 }
 
-pub async fn get_manifest(req: HttpRequest) -> impl Responder {
-    let blobert: &Blobert = req.app_data().unwrap();
-    let namespace = req.match_info().get("namespace").unwrap();
-    let reference = req.match_info().get("reference").unwrap();
-
-    match blobert.meta_store.get_manifest(namespace, reference) {
-        Ok(manifest) => {
-            let payload = serde_json::to_vec(&manifest).unwrap();
-            HttpResponse::Ok()
-                .append_header(("Content-Type", IMAGE_MANIFEST_MEDIA_TYPE))
-                .append_header(("Content-Length", format!("{}", payload.len())))
-                .append_header(("Docker-Content-Digest", manifest.digest()))
-                .body(payload)
+#[derive(Serialize)]
+struct TagList {
+    name: String,
+    tags: Vec<String>
+}
+
+/// Describes a manifest in registry events
+fn manifest_target(blobert: &Blobert, namespace: &RepositoryName, manifest: &RawManifest,
+    reference: &Reference) -> Target
+{
+    let digest = manifest.digest_for(reference);
+    let size = manifest.data().len() as u64;
+    Target {
+        media_type: manifest.media_type().to_string(),
+        size: Some(size),
+        length: Some(size),
+        url: format!("{}/v2/{}/manifests/{}", blobert.opts.get_server_url(), namespace, digest),
+        digest: digest.to_string(),
+        repository: namespace.to_string(),
+        tag: match reference {
+            Reference::Tag(tag) => Some(tag.clone()),
+            Reference::Digest(_) => None,
         },
-        Err(e) => {
-            error!("Error retrieving manifest {}/{}: {}",
-                namespace, reference, e);
-            e.respond()
-        }
     }
 }
 
-pub async fn put_manifest(req: HttpRequest, mut payload: web::Payload) -> impl Responder {
-    let blobert: &Blobert = req.app_data().unwrap();
-    let namespace = req.match_info().get("namespace").unwrap();
-    let reference = req.match_info().get("reference").unwrap();
+#[tracing::instrument(skip_all)]
+pub async fn get_manifest(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    // A reference that can't be valid can't name a manifest we have either
+    let reference = Reference::parse(path_param(&req, "reference")?)
+        .map_err(|_| RegistryError::from(MANIFEST_UNKNOWN))?;
+
+    let (store, name, r) = (blobert.meta_store.clone(), namespace.clone(), reference.clone());
+    let manifest = block(move || store.get_manifest(&name, &r)).await
+        .inspect_err(|e| error!("Error retrieving manifest {}/{}: {}", namespace, reference, e))?;
+    if req.method() == Method::GET {
+        let target = manifest_target(blobert, &namespace, &manifest, &reference);
+        blobert.notifier.notify(&req, Action::Pull, target);
+    }
+    Ok(HttpResponse::Ok()
+        .append_header(("Content-Type", manifest.media_type()))
+        .append_header(("Content-Length", format!("{}", manifest.data().len())))
+        .append_header(("Docker-Content-Digest", manifest.digest_for(&reference).to_string()))
+        .body(manifest.data().to_vec()))
+}
+
+/// Reports every blob the manifest references that we don't have, and every
+/// manifest an index lists that isn't in the repository
+fn missing_blobs(blobert: &Blobert, namespace: &RepositoryName, manifest: &RawManifest) -> Vec<RegistryError> {
+    let manifest = manifest.manifest();
+    let blobs = manifest.descriptors()
+        .map(|d| (d, Digest::parse(&d.digest).map(|digest| blobert.blob_store.blob_exists(&digest))));
+    let children = manifest.manifests.iter().flatten()
+        .map(|d| (d, Digest::parse(&d.digest)
+            .map(|digest| blobert.meta_store.get_manifest(namespace, &Reference::Digest(digest)).is_ok())));
+    blobs.chain(children)
+        .filter_map(|(d, found)| match found {
+            Ok(true) => None,
+            Ok(false) => Some(RegistryError::from(MANIFEST_BLOB_UNKNOWN)
+                .with_detail(serde_json::json!({ "digest": d.digest }))),
+            Err(e) => Some(e),
+        })
+        .collect()
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn put_manifest(req: HttpRequest, mut payload: web::Payload) -> Result<HttpResponse, RegistryErrorResponse> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let reference: Reference = parse_param(&req, "reference")?;
 
     let mut body = web::BytesMut::new();
     while let Some(chunk) = payload.next().await {
-        match chunk {
-            Ok(bytes) => body.extend_from_slice(&bytes),
-            Err(e) => return Err(e)
+        let bytes = chunk.map_err(|e| RegistryError::from_err(MANIFEST_INVALID, Box::new(e)))?;
+        blobert.limits.check_manifest_size((body.len() + bytes.len()) as u64)?;
+        body.extend_from_slice(&bytes);
+    }
+
+    let manifest = RawManifest::parse(body.to_vec())
+        .inspect_err(|e| error!("Error decoding manifest: {}", e))?;
+    // Pushed by digest, it has to be the digest of what was sent
+    if let Reference::Digest(digest) = &reference {
+        let actual = manifest.digest_for(&reference);
+        if *digest != actual {
+            return Err(RegistryError::from(DIGEST_INVALID).with_detail(serde_json::json!({
+                "digest": digest.to_string(),
+                "actual": actual.to_string(),
+            })).into())
         }
     }
 
-    let manifest = match serde_json::from_slice(&body) {
-        Err(e) => {
-            error!("Error decoding manifest: {}", e);
-            return Ok(HttpResponse::BadRequest().finish())
-        },
-        Ok(man) => man,
+    let (b, name, m) = (blobert.clone(), namespace.clone(), manifest.clone());
+    let missing = block(move || Ok(missing_blobs(&b, &name, &m))).await?;
+    if !missing.is_empty() {
+        return Err(RegistryErrorResponse::new(missing))
+    }
+    // Where the tag pointed before, to tell whether it moves, and the tags
+    // after storing it
+    let (b, name, r, m) = (blobert.clone(), namespace.clone(), reference.clone(), manifest.clone());
+    let (previous, tags) = block(move || {
+        let push_lock = b.limits.push_lock(&name);
+        let _push = lock(&push_lock);
+        b.limits.check_quota(b.meta_store.as_ref(), b.blob_store.as_ref(), &name, m.manifest())?;
+        let previous = match r {
+            Reference::Tag(_) => b.meta_store.get_manifest(&name, &r).ok().map(|m| m.digest()),
+            Reference::Digest(_) => None,
+        };
+        b.meta_store.put_manifest(&name, &r, &m)
+            .inspect_err(|e| error!("Error storing manifest file: {}", e))?;
+        b.limits.forget(&name);
+        Ok((previous, b.meta_store.list_tags(&name)?))
+    }).await?;
+
+    let target = manifest_target(blobert, &namespace, &manifest, &reference);
+    let entry = |action| Entry::request(&req, &blobert.opts, action, namespace.as_str(), reference.as_str())
+        .digests(previous.as_ref().map(Digest::to_string), Some(target.digest.clone()));
+    blobert.audit.record(&entry(audit::Action::Push));
+    blobert.notifier.notify(&req, Action::Push, target.clone());
+    if target.tag.is_some() && previous.as_ref() != Some(&manifest.digest()) {
+        blobert.audit.record(&entry(audit::Action::Tag));
+        blobert.notifier.notify(&req, Action::Tag, target);
+    }
+
+    let response = PutManifestResponse {
+        name: reference.to_string(),
+        tags,
     };
+    let location = format!("{}/v2/{}/manifests/{}", 
+        blobert.opts.get_server_url(), namespace, reference);
+    let man_bytes = to_json(&response)?;
+    let digest = manifest.digest_for(&reference);
+    debug!("Manifest {}/{} hash: {}", namespace, reference, digest);
+    blobert.replicator.on_push(namespace.as_str(), reference.as_str());
+    blobert.metrics.manifest_pushed(namespace.as_str());
 
-    match blobert.meta_store.put_manifest(namespace, reference, &manifest) {
-        Ok(_) => {
-            let tags = blobert.meta_store.list_tags(namespace);
-            let response = PutManifestResponse {
-                name: reference.to_string(),
-                tags,
-            };
-            let location = format!("{}/v2/{}/manifests/{}", 
-                blobert.opts.get_server_url(), namespace, reference);
-            let man_bytes = serde_json::to_vec(&response).unwrap();
-            let digest = sha256_digest(&man_bytes);
-            debug!("Manifest {}/{} hash: {}", namespace, reference, digest);
-
-            Ok(HttpResponse::Created()
-                .append_header(("Content-Type", "application/json"))
-                .append_header(("Location", location))
-                .append_header(("Docker-Content-Digest", digest))
-                .body(man_bytes))
-        },
-        Err(e) => {
-            error!("Error storing manifest file: {}", e);
-            Err(PayloadError::EncodingCorrupted)
+    Ok(HttpResponse::Created()
+        .append_header(("Content-Type", "application/json"))
+        .append_header(("Location", location))
+        .append_header(("Docker-Content-Digest", digest.to_string()))
+        .body(man_bytes))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn delete_manifest(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let reference: Reference = parse_param(&req, "reference")?;
+
+    // What a deleted tag pointed to, for the audit log
+    let (store, name, r) = (blobert.meta_store.clone(), namespace.clone(), reference.clone());
+    let old = block(move || {
+        let old = match &r {
+            Reference::Tag(_) => store.get_manifest(&name, &r).ok().map(|m| m.digest().to_string()),
+            Reference::Digest(digest) => Some(digest.to_string()),
+        };
+        store.delete_manifest(&name, &r)?;
+        Ok(old)
+    }).await?;
+    blobert.limits.forget(&namespace);
+    blobert.audit.record(&Entry::request(&req, &blobert.opts, audit::Action::Delete, namespace.as_str(), reference.as_str())
+        .digests(old, None));
+    let (digest, tag) = match reference {
+        Reference::Tag(tag) => (String::new(), Some(tag)),
+        Reference::Digest(digest) => (digest.to_string(), None),
+    };
+    blobert.notifier.notify(&req, Action::Delete, Target {
+        digest,
+        tag,
+        repository: namespace.to_string(),
+        ..Target::default()
+    });
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list_tags(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+
+    let (store, name) = (blobert.meta_store.clone(), namespace.clone());
+    let tags = block(move || {
+        let tags = store.list_tags(&name)?;
+        if tags.is_empty() && !store.list_repositories()?.iter().any(|r| r == name.as_str()) {
+            return Err(RegistryError::from(NAME_UNKNOWN))
         }
+        Ok(tags)
+    }).await?;
+    let response = TagList {
+        name: namespace.to_string(),
+        tags,
+    };
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::Digest;
+    use crate::util::instance;
+    use actix_web::test::{call_service, read_body_json, TestRequest};
+
+    #[actix_web::test]
+    async fn it_reports_every_missing_blob() {
+        let app = instance::app(&[]).await;
+
+        let (config, layer) = (format!("sha256:{}", "c".repeat(64)), format!("sha256:{}", "1".repeat(64)));
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": config, "size": 2 },
+            "layers": [{ "mediaType": "application/vnd.oci.image.layer.v1.tar", "digest": layer, "size": 2 }],
+        });
+        let res = call_service(&app, TestRequest::put().uri("/v2/app/manifests/v1")
+            .set_json(&manifest).to_request()).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["errors"].as_array().unwrap().len(), 2);
+        assert_eq!(body["errors"][0]["code"], "MANIFEST_BLOB_UNKNOWN");
+        assert_eq!(body["errors"][0]["detail"]["digest"], config);
+        assert_eq!(body["errors"][1]["detail"]["digest"], layer);
+
+        let res = call_service(&app, TestRequest::get().uri("/v2/app/manifests/v1").to_request()).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn it_takes_manifests_pushed_by_sha512_digest() {
+        use crate::types::Algorithm;
+        use actix_web::test::read_body;
+
+        let app = instance::app(&[]).await;
+
+        let res = call_service(&app, TestRequest::post().uri("/v2/app/blobs/uploads/").to_request()).await;
+        let id = res.headers().get("Docker-Upload-UUID").unwrap().to_str().unwrap().to_string();
+        let config = Digest::of(Algorithm::Sha512, b"{}");
+        let res = call_service(&app, TestRequest::patch().uri(&format!("/v2/app/blobs/upload/{}", id))
+            .set_payload(&b"{}"[..]).to_request()).await;
+        assert_eq!(res.status(), 202);
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("/v2/app/blobs/upload/{}?digest={}", id, config)).to_request()).await;
+        assert_eq!(res.status(), 201);
+
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": config.as_str(), "size": 2 },
+            "layers": [],
+        })).unwrap();
+        let digest = Digest::of(Algorithm::Sha512, &manifest);
+        let uri = format!("/v2/app/manifests/{}", digest);
+        let res = call_service(&app, TestRequest::put().uri(&uri).set_payload(manifest.clone()).to_request()).await;
+        assert_eq!(res.status(), 201);
+        assert_eq!(res.headers().get("Docker-Content-Digest").unwrap().to_str().unwrap(), digest.as_str());
+
+        let res = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.headers().get("Docker-Content-Digest").unwrap().to_str().unwrap(), digest.as_str());
+        assert_eq!(read_body(res).await, manifest);
+    }
+
+    #[actix_web::test]
+    async fn it_takes_pushes_to_nested_repositories() {
+        let app = instance::app(&[]).await;
+
+        let res = call_service(&app, TestRequest::post().uri("/v2/team-a/app/blobs/uploads/").to_request()).await;
+        assert_eq!(res.status(), 202);
+        let id = res.headers().get("Docker-Upload-UUID").unwrap().to_str().unwrap().to_string();
+        let upload = format!("/v2/team-a/app/blobs/upload/{}", id);
+        let res = call_service(&app, TestRequest::patch().uri(&upload).set_payload(&b"{}"[..]).to_request()).await;
+        assert_eq!(res.status(), 202);
+        let config = Digest::sha256(b"{}");
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("{}?digest={}", upload, config)).to_request()).await;
+        assert_eq!(res.status(), 201);
+
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": config.as_str(), "size": 2 },
+            "layers": [],
+        })).unwrap();
+        let digest = Digest::sha256(&manifest);
+        // Pushing by digest takes only the digest of the body
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("/v2/team-a/app/manifests/{}", Digest::sha256(b"other")))
+            .set_payload(manifest.clone()).to_request()).await;
+        assert_eq!(res.status(), 400);
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "DIGEST_INVALID");
+        let res = call_service(&app, TestRequest::put().uri(&format!("/v2/team-a/app/manifests/{}", digest))
+            .set_payload(manifest.clone()).to_request()).await;
+        assert_eq!(res.status(), 201);
+        let res = call_service(&app, TestRequest::put().uri("/v2/team-a/app/manifests/v1")
+            .set_payload(manifest.clone()).to_request()).await;
+        assert_eq!(res.status(), 201);
+
+        let res = call_service(&app, TestRequest::get().uri("/v2/team-a/app/manifests/v1").to_request()).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get("Docker-Content-Digest").unwrap().to_str().unwrap(), digest.as_str());
+        let res = call_service(&app, TestRequest::get().uri("/v2/team-a/app/tags/list").to_request()).await;
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body, serde_json::json!({ "name": "team-a/app", "tags": ["v1"] }));
+        let res = call_service(&app, TestRequest::get().uri("/v2/team-a/manifests/v1").to_request()).await;
+        assert_eq!(res.status(), 404);
     }
 }
@@ -0,0 +1,339 @@
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use redb::{Database, ReadableTable, TableDefinition};
+
+use crate::meta::{Store, Manifest, RawManifest};
+use crate::error;
+use crate::error::RegistryError;
+use crate::types::{Digest, Reference, RepositoryName};
+
+/// `<namespace>\0<digest>` to manifest JSON
+const MANIFESTS: TableDefinition<&str, &[u8]> = TableDefinition::new("manifests");
+/// `<namespace>\0<tag>` to manifest digest
+const TAGS: TableDefinition<&str, &str> = TableDefinition::new("tags");
+/// `<namespace>\0<subject digest>\0<referrer digest>` to the referrer's
+/// artifact type
+const REFERRERS: TableDefinition<&str, &str> = TableDefinition::new("referrers");
+/// `<namespace>` of every repository pushed to. The values are empty; older
+/// databases may still hold timestamps there, which nothing reads.
+const REPOSITORIES: TableDefinition<&str, &[u8]> = TableDefinition::new("repositories");
+
+/// Keeps manifests, tags, referrers and the names of repositories in an embedded
+/// redb database. Every push is a single transaction, so a tag always points
+/// at either its old or its new manifest.
+pub struct Redb {
+    db: Database,
+}
+
+fn db_error<E: Into<redb::Error>>(e: E) -> RegistryError {
+    RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e.into()))
+}
+
+fn key(parts: &[&str]) -> String {
+    parts.join("\0")
+}
+
+/// Bounds of every key starting with the given prefix parts
+fn prefix_range(parts: &[&str]) -> (String, String) {
+    let prefix = key(parts);
+    (format!("{}\0", prefix), format!("{}\u{1}", prefix))
+}
+
+impl Redb {
+    pub fn new(data_dir: &str) -> Result<Redb, RegistryError> {
+        std::fs::create_dir_all(data_dir)
+            .map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))?;
+        let path = PathBuf::from(data_dir).join("meta.redb");
+        let db = Database::create(path).map_err(db_error)?;
+
+        // Create the tables up front so readers never find them missing
+        let txn = db.begin_write().map_err(db_error)?;
+        txn.open_table(MANIFESTS).map_err(db_error)?;
+        txn.open_table(TAGS).map_err(db_error)?;
+        txn.open_table(REFERRERS).map_err(db_error)?;
+        txn.open_table(REPOSITORIES).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+        Ok(Redb { db })
+    }
+
+    /// Writes a manifest under its digest, records it as a referrer of its
+    /// subject and points the tag at it, all in one transaction
+    fn write(&self, namespace: &str, digest: &str, tag: Option<&str>, data: &[u8], m: &Manifest) -> Result<(), RegistryError> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut manifests = txn.open_table(MANIFESTS).map_err(db_error)?;
+            manifests.insert(key(&[namespace, digest]).as_str(), data).map_err(db_error)?;
+
+            if let Some(subject) = &m.subject {
+                let mut referrers = txn.open_table(REFERRERS).map_err(db_error)?;
+                referrers.insert(key(&[namespace, &subject.digest, digest]).as_str(),
+                    m.referrer_type()).map_err(db_error)?;
+            }
+
+            if let Some(tag) = tag {
+                let mut tags = txn.open_table(TAGS).map_err(db_error)?;
+                tags.insert(key(&[namespace, tag]).as_str(), digest).map_err(db_error)?;
+            }
+
+            let mut repositories = txn.open_table(REPOSITORIES).map_err(db_error)?;
+            if repositories.get(namespace).map_err(db_error)?.is_none() {
+                repositories.insert(namespace, [].as_slice()).map_err(db_error)?;
+            }
+        }
+        txn.commit().map_err(db_error)
+    }
+
+    /// Copies the manifests and tags of a filesystem metadata store into the
+    /// database. Manifests keep the digest they were stored under. Returns the
+    /// number of manifests and tags imported.
+    pub fn import_filesystem(&self, data_dir: &str) -> Result<(usize, usize), RegistryError> {
+        let io = |e: std::io::Error| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e));
+        let root = Path::new(data_dir).join("manifests");
+        let (mut manifests, mut tags) = (0, 0);
+        if !root.exists() {
+            return Ok((manifests, tags))
+        }
+
+        // Repositories can be nested, e.g. `docker.io/library/app`
+        for namespace in crate::meta::fs::Filesystem::new(data_dir).map_err(io)?.list_repositories()? {
+            if RepositoryName::parse(&namespace).is_err() {
+                warn!("Skipping invalid repository name {}", namespace);
+                continue
+            }
+            let mut links = Vec::new();
+
+            for entry in std::fs::read_dir(root.join(&namespace)).map_err(io)?.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let file_type = entry.file_type().map_err(io)?;
+                if file_type.is_symlink() {
+                    links.push((name, entry.path()));
+                    continue
+                }
+                // Repositories nested below this one are imported on their own
+                if file_type.is_dir() {
+                    continue
+                }
+                if Digest::parse(&name).is_err() {
+                    warn!("Skipping manifest {}/{} not named by digest", namespace, name);
+                    continue
+                }
+                let data = std::fs::read(entry.path()).map_err(io)?;
+                match serde_json::from_slice::<Manifest>(&data) {
+                    Ok(m) => {
+                        self.write(&namespace, &name, None, &data, &m)?;
+                        manifests += 1;
+                    },
+                    Err(e) => warn!("Skipping unreadable manifest {}/{}: {}", namespace, name, e)
+                }
+            }
+
+            for (tag, path) in links {
+                let target = std::fs::read_link(&path).map_err(io)?;
+                let digest = match target.file_name() {
+                    Some(digest) => digest.to_string_lossy().to_string(),
+                    None => continue
+                };
+                let txn = self.db.begin_write().map_err(db_error)?;
+                {
+                    let known = txn.open_table(MANIFESTS).map_err(db_error)?
+                        .get(key(&[&namespace, &digest]).as_str()).map_err(db_error)?
+                        .is_some();
+                    if !known {
+                        warn!("Skipping tag {}:{} pointing at missing {}", namespace, tag, digest);
+                        continue
+                    }
+                    let mut table = txn.open_table(TAGS).map_err(db_error)?;
+                    table.insert(key(&[&namespace, &tag]).as_str(), digest.as_str()).map_err(db_error)?;
+                }
+                txn.commit().map_err(db_error)?;
+                debug!("Imported tag {}:{} -> {}", namespace, tag, digest);
+                tags += 1;
+            }
+        }
+        Ok((manifests, tags))
+    }
+}
+
+impl Store for Redb {
+    fn put_manifest(&self, namespace: &RepositoryName, reference: &Reference, m: &RawManifest) -> Result<(), RegistryError> {
+        let tag = match reference {
+            Reference::Tag(tag) => Some(tag.as_str()),
+            Reference::Digest(_) => None,
+        };
+        self.write(namespace.as_str(), m.digest_for(reference).as_str(), tag, m.data(), m.manifest())
+    }
+
+    fn get_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<RawManifest, RegistryError> {
+        let namespace = namespace.as_str();
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let digest = match reference {
+            Reference::Digest(digest) => digest.to_string(),
+            Reference::Tag(tag) => {
+                let tags = txn.open_table(TAGS).map_err(db_error)?;
+                match tags.get(key(&[namespace, tag]).as_str()).map_err(db_error)? {
+                    Some(digest) => digest.value().to_string(),
+                    None => return Err(RegistryError::from(error::MANIFEST_UNKNOWN))
+                }
+            }
+        };
+        let manifests = txn.open_table(MANIFESTS).map_err(db_error)?;
+        match manifests.get(key(&[namespace, &digest]).as_str()).map_err(db_error)? {
+            Some(data) => RawManifest::load(data.value().to_vec()),
+            None => Err(RegistryError::from(error::MANIFEST_UNKNOWN))
+        }
+    }
+
+    fn list_tags(&self, namespace: &RepositoryName) -> Result<Vec<String>, RegistryError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let tags = txn.open_table(TAGS).map_err(db_error)?;
+        let (start, end) = prefix_range(&[namespace.as_str()]);
+        let range = tags.range(start.as_str()..end.as_str()).map_err(db_error)?;
+        Ok(range.flatten()
+            .map(|(k, _)| k.value()[start.len()..].to_string())
+            .collect())
+    }
+
+    fn list_repositories(&self) -> Result<Vec<String>, RegistryError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let repositories = txn.open_table(REPOSITORIES).map_err(db_error)?;
+        let iter = repositories.iter().map_err(db_error)?;
+        Ok(iter.flatten()
+            .map(|(k, _)| k.value().to_string())
+            .collect())
+    }
+
+    fn delete_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<(), RegistryError> {
+        let namespace = namespace.as_str();
+        let txn = self.db.begin_write().map_err(db_error)?;
+        let found = {
+            let mut tags = txn.open_table(TAGS).map_err(db_error)?;
+            match reference {
+                Reference::Tag(tag) => tags.remove(key(&[namespace, tag]).as_str()).map_err(db_error)?.is_some(),
+                Reference::Digest(digest) => {
+                    let (start, end) = prefix_range(&[namespace]);
+                    tags.retain_in(start.as_str()..end.as_str(), |_, d| d != digest.as_str())
+                        .map_err(db_error)?;
+                    let mut manifests = txn.open_table(MANIFESTS).map_err(db_error)?;
+                    let removed = manifests.remove(key(&[namespace, digest.as_str()]).as_str())
+                        .map_err(db_error)?
+                        .map(|data| data.value().to_vec());
+                    let subject = removed.as_ref()
+                        .and_then(|data| serde_json::from_slice::<Manifest>(data).ok())
+                        .and_then(|m| m.subject);
+                    let mut referrers = txn.open_table(REFERRERS).map_err(db_error)?;
+                    if let Some(subject) = subject {
+                        referrers.remove(key(&[namespace, &subject.digest, digest.as_str()]).as_str())
+                            .map_err(db_error)?;
+                    }
+                    // Referrers of the manifest stay stored, but are no
+                    // longer listed for it
+                    let (start, end) = prefix_range(&[namespace, digest.as_str()]);
+                    referrers.retain_in(start.as_str()..end.as_str(), |_, _| false).map_err(db_error)?;
+                    removed.is_some()
+                }
+            }
+        };
+        if !found {
+            return Err(RegistryError::from(error::MANIFEST_UNKNOWN))
+        }
+        txn.commit().map_err(db_error)
+    }
+
+    fn list_referrers(&self, namespace: &RepositoryName, digest: &Digest) -> Result<Vec<Digest>, RegistryError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let referrers = txn.open_table(REFERRERS).map_err(db_error)?;
+        let (start, end) = prefix_range(&[namespace.as_str(), digest.as_str()]);
+        let range = referrers.range(start.as_str()..end.as_str()).map_err(db_error)?;
+        Ok(range.flatten()
+            .filter_map(|(k, _)| Digest::parse(&k.value()[start.len()..]).ok())
+            .collect())
+    }
+
+    fn list_manifests(&self, namespace: &RepositoryName) -> Result<Vec<Digest>, RegistryError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let manifests = txn.open_table(MANIFESTS).map_err(db_error)?;
+        let (start, end) = prefix_range(&[namespace.as_str()]);
+        let range = manifests.range(start.as_str()..end.as_str()).map_err(db_error)?;
+        Ok(range.flatten()
+            .filter_map(|(k, _)| Digest::parse(&k.value()[start.len()..]).ok())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::fs::Filesystem;
+    use crate::meta::Descriptor;
+
+    #[test]
+    fn it_imports_the_filesystem_layout() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let fstore = Filesystem::new(&test_path).unwrap();
+        let m = Manifest::default();
+        let imported = RepositoryName::parse("imported").unwrap();
+        let tag = |t| Reference::parse(t).unwrap();
+        fstore.put_manifest(&imported, &tag("latest"), &RawManifest::from(&m)).unwrap();
+        fstore.put_manifest(&imported, &tag("stable"), &RawManifest::from(&m)).unwrap();
+        let nested = RepositoryName::parse("imported/nested").unwrap();
+        fstore.put_manifest(&nested, &tag("latest"), &RawManifest::from(&m)).unwrap();
+
+        let db = Redb::new(&test_path).unwrap();
+        assert_eq!(db.import_filesystem(&test_path).unwrap(), (2, 3));
+        assert_eq!(db.get_manifest(&imported, &tag("stable")).unwrap().manifest(), &m);
+        assert_eq!(db.list_tags(&imported).unwrap(), vec!["latest", "stable"]);
+        assert_eq!(db.list_tags(&nested).unwrap(), vec!["latest"]);
+        assert_eq!(db.list_repositories().unwrap(), vec!["imported", "imported/nested"]);
+    }
+
+    #[test]
+    fn it_records_the_artifact_type_of_referrers() {
+        let db = Redb::new(&format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4())).unwrap();
+        let repository = RepositoryName::parse("signed").unwrap();
+        let image = Manifest::default();
+        let subject = Descriptor { digest: image.digest().to_string(), ..Descriptor::default() };
+        let signature = Manifest {
+            media_type: Some(String::from("application/vnd.oci.image.manifest.v1+json")),
+            artifact_type: Some(String::from("application/vnd.dev.cosign.artifact.sig.v1+json")),
+            subject: Some(subject.clone()),
+            ..Manifest::default()
+        };
+        let sbom = Manifest {
+            config: Some(Descriptor { media_type: String::from("application/spdx+json"), ..Descriptor::default() }),
+            subject: Some(subject),
+            ..Manifest::default()
+        };
+        for m in [&image, &signature, &sbom] {
+            db.put_manifest(&repository, &Reference::Digest(m.digest()), &RawManifest::from(m)).unwrap();
+        }
+
+        let txn = db.db.begin_read().unwrap();
+        let referrers = txn.open_table(REFERRERS).unwrap();
+        let artifact_type = |m: &Manifest| referrers
+            .get(key(&["signed", image.digest().as_str(), m.digest().as_str()]).as_str()).unwrap()
+            .map(|v| v.value().to_string());
+        assert_eq!(artifact_type(&signature).as_deref(), Some("application/vnd.dev.cosign.artifact.sig.v1+json"));
+        assert_eq!(artifact_type(&sbom).as_deref(), Some("application/spdx+json"));
+    }
+
+    #[test]
+    fn it_drops_the_referrers_of_deleted_manifests() {
+        let db = Redb::new(&format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4())).unwrap();
+        let repository = RepositoryName::parse("signed").unwrap();
+        let image = Manifest::default();
+        let signature = Manifest {
+            subject: Some(Descriptor { digest: image.digest().to_string(), ..Descriptor::default() }),
+            ..Manifest::default()
+        };
+        for m in [&image, &signature] {
+            db.put_manifest(&repository, &Reference::Digest(m.digest()), &RawManifest::from(m)).unwrap();
+        }
+        assert_eq!(db.list_referrers(&repository, &image.digest()).unwrap(), vec![signature.digest()]);
+
+        db.delete_manifest(&repository, &Reference::Digest(image.digest())).unwrap();
+        assert!(db.list_referrers(&repository, &image.digest()).unwrap().is_empty());
+        let txn = db.db.begin_read().unwrap();
+        assert!(txn.open_table(REFERRERS).unwrap().iter().unwrap().next().is_none());
+    }
+}
@@ -1,8 +1,10 @@
-use std::{path::PathBuf, os::unix::fs};
+use std::{path::{Path, PathBuf}, os::unix::fs};
 
-use crate::meta::{Store, Manifest};
+use crate::meta::{Store, Manifest, RawManifest};
 use crate::error;
+use crate::fsck::{self, Kind, Problem};
 use crate::error::RegistryError;
+use crate::types::{Digest, Reference, RepositoryName};
 
 pub struct Filesystem {
     data_dir: String
@@ -18,48 +20,48 @@ impl Filesystem {
         }
     }
 
-    fn get_manifest_path(&self, namespace: &str) -> PathBuf {
+    fn get_manifest_path(&self, namespace: &RepositoryName) -> Result<PathBuf, RegistryError> {
         let mut path = PathBuf::from(&self.data_dir);
         path.push("manifests");
-        path.push(namespace);
-        std::fs::create_dir_all(&path).unwrap();
-        path
+        path.push(namespace.as_str());
+        std::fs::create_dir_all(&path).map_err(unknown)?;
+        Ok(path)
     }
 }
 
-impl Store for Filesystem {
-    fn put_manifest(&self, namespace: &str, tag: &str, m: &Manifest) -> Result<(), RegistryError> {
-        let mut tag_path = self.get_manifest_path(namespace);
-        tag_path.push(tag);
+fn unknown(e: std::io::Error) -> RegistryError {
+    RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e))
+}
 
-        let mut sha_path = self.get_manifest_path(namespace);
-        sha_path.push(m.digest());
+impl Store for Filesystem {
+    fn put_manifest(&self, namespace: &RepositoryName, reference: &Reference, m: &RawManifest) -> Result<(), RegistryError> {
+        let mut sha_path = self.get_manifest_path(namespace)?;
+        sha_path.push(m.digest_for(reference).as_str());
 
         // If we already have the manifest at this SHA, skip writing
         if !sha_path.exists() {
-            match std::fs::write(&sha_path, serde_json::to_vec(m).unwrap()) {
-                Err(e) => {
-                    return Err(RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))
-                },
-                _ => ()
-            }
+            std::fs::write(&sha_path, m.data()).map_err(unknown)?;
         }
+        // Pushed by digest, there is no tag to point at it
+        let tag = match reference {
+            Reference::Tag(tag) => tag,
+            Reference::Digest(_) => return Ok(())
+        };
+        let mut tag_path = self.get_manifest_path(namespace)?;
+        tag_path.push(tag);
         // Update the symlink
         if tag_path.exists() {
-            std::fs::remove_file(&tag_path).unwrap()
-        }
-        match fs::symlink(sha_path, tag_path) {
-            Err(e) => Err(RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e))),
-            _ => Ok(())
+            std::fs::remove_file(&tag_path).map_err(unknown)?;
         }
+        fs::symlink(sha_path, tag_path).map_err(unknown)
     }
 
-    fn get_manifest(&self, namespace: &str, reference: &str) -> Result<Manifest, RegistryError> {
-        let mut path = self.get_manifest_path(namespace);
-        path.push(reference);
+    fn get_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<RawManifest, RegistryError> {
+        let mut path = self.get_manifest_path(namespace)?;
+        path.push(reference.as_str());
 
         match std::fs::read(path) {
-            Ok(data) => Ok(serde_json::from_slice(&data).unwrap()),
+            Ok(data) => RawManifest::load(data),
             Err(e) => {
                 match e.kind() {
                     std::io::ErrorKind::NotFound =>
@@ -70,16 +72,165 @@ impl Store for Filesystem {
         }
     }
 
-    fn list_tags(&self, namespace: &str) -> Vec<String> {
-        let dir = std::fs::read_dir(self.get_manifest_path(namespace)).unwrap();
+    fn list_tags(&self, namespace: &RepositoryName) -> Result<Vec<String>, RegistryError> {
+        let dir = std::fs::read_dir(self.get_manifest_path(namespace)?).map_err(unknown)?;
         let mut tags: Vec<String> = Vec::new();
-        for (_, entry) in dir.enumerate() {
-            let entry = entry.unwrap();
-            if entry.file_type().unwrap().is_symlink() {
-                tags.push(String::from(entry.file_name().to_str().unwrap()));
+        for entry in dir {
+            let entry = entry.map_err(unknown)?;
+            if entry.file_type().map_err(unknown)?.is_symlink() {
+                tags.push(entry.file_name().to_string_lossy().to_string());
             }
         }
         tags.sort();
-        tags
+        Ok(tags)
+    }
+
+    fn list_repositories(&self) -> Result<Vec<String>, RegistryError> {
+        let root = Path::new(&self.data_dir).join("manifests");
+        let mut repos = Vec::new();
+        if root.exists() {
+            repositories(&root, &root, &mut repos)?;
+        }
+        repos.sort();
+        Ok(repos)
+    }
+
+    fn delete_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<(), RegistryError> {
+        let dir = self.get_manifest_path(namespace)?;
+        let not_found = |e: std::io::Error| match e.kind() {
+            std::io::ErrorKind::NotFound => RegistryError::from_err(error::MANIFEST_UNKNOWN, Box::new(e)),
+            _ => unknown(e)
+        };
+        let digest = match reference {
+            Reference::Tag(tag) => return std::fs::remove_file(dir.join(tag)).map_err(not_found),
+            Reference::Digest(digest) => digest,
+        };
+        for entry in std::fs::read_dir(&dir).map_err(unknown)?.flatten() {
+            let target = std::fs::read_link(entry.path()).ok();
+            if target.as_ref().and_then(|t| t.file_name()) == Some(digest.as_str().as_ref()) {
+                std::fs::remove_file(entry.path()).map_err(unknown)?;
+            }
+        }
+        std::fs::remove_file(dir.join(digest.as_str())).map_err(not_found)
+    }
+
+    fn list_referrers(&self, namespace: &RepositoryName, digest: &Digest) -> Result<Vec<Digest>, RegistryError> {
+        let dir = std::fs::read_dir(self.get_manifest_path(namespace)?).map_err(unknown)?;
+        let mut referrers = Vec::new();
+        for entry in dir.flatten() {
+            if entry.file_type().map(|t| t.is_symlink()).unwrap_or(true) {
+                continue
+            }
+            let subject = std::fs::read(entry.path()).ok()
+                .and_then(|data| serde_json::from_slice::<Manifest>(&data).ok())
+                .and_then(|m| m.subject);
+            if subject.map(|s| s.digest == digest.as_str()).unwrap_or(false) {
+                if let Ok(referrer) = Digest::parse(&entry.file_name().to_string_lossy()) {
+                    referrers.push(referrer);
+                }
+            }
+        }
+        referrers.sort();
+        Ok(referrers)
+    }
+
+    fn list_manifests(&self, namespace: &RepositoryName) -> Result<Vec<Digest>, RegistryError> {
+        let dir = std::fs::read_dir(self.get_manifest_path(namespace)?).map_err(unknown)?;
+        let mut manifests = Vec::new();
+        for entry in dir {
+            let entry = entry.map_err(unknown)?;
+            // Tags are symlinks, nested repositories directories
+            if entry.file_type().map_err(unknown)?.is_file() {
+                if let Ok(digest) = Digest::parse(&entry.file_name().to_string_lossy()) {
+                    manifests.push(digest);
+                }
+            }
+        }
+        manifests.sort();
+        Ok(manifests)
+    }
+
+    fn check(&self, repair: bool) -> Result<Vec<Problem>, RegistryError> {
+        let data_dir = Path::new(&self.data_dir);
+        let root = data_dir.join("manifests");
+        let (mut manifests, mut tags) = (Vec::new(), Vec::new());
+        if root.exists() {
+            walk(&root, &mut manifests, &mut tags)?;
+        }
+        let repository = |path: &Path| path.parent().and_then(|p| p.strip_prefix(&root).ok())
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+
+        // Manifests go first, so tags of quarantined manifests show up as dangling
+        let mut problems = Vec::new();
+        for path in manifests {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let object = format!("{}@{}", repository(&path), name);
+            let mut problem = match Digest::parse(&name) {
+                Ok(digest) => {
+                    let data = std::fs::read(&path).map_err(unknown)?;
+                    match RawManifest::parse(data.clone()) {
+                        Ok(_) if digest.verify(&data) => continue,
+                        Ok(_) => Problem::new(Kind::CorruptManifest, object,
+                            format!("hashes to {}", Digest::of(digest.algorithm(), &data))),
+                        Err(e) => Problem::new(Kind::CorruptManifest, object, e),
+                    }
+                },
+                Err(_) => Problem::new(Kind::UnknownFile, object, "not named after a digest"),
+            };
+            if repair {
+                fsck::quarantine(data_dir, &path, &mut problem)?;
+            }
+            problems.push(problem);
+        }
+        for path in tags {
+            if path.exists() {
+                continue
+            }
+            let target = std::fs::read_link(&path).map_err(unknown)?;
+            let mut problem = Problem::new(Kind::DanglingTag,
+                format!("{}:{}", repository(&path), path.file_name().unwrap_or_default().to_string_lossy()),
+                format!("points to missing {}", target.display()));
+            if repair {
+                fsck::quarantine(data_dir, &path, &mut problem)?;
+            }
+            problems.push(problem);
+        }
+        Ok(problems)
+    }
+}
+
+/// Collects the names of the directories below `dir` that hold manifests or
+/// tags, e.g. `docker.io/library/app`. Reads create the directory of a
+/// repository, so empty ones don't count.
+fn repositories(root: &Path, dir: &Path, repos: &mut Vec<String>) -> Result<(), RegistryError> {
+    let mut has_content = false;
+    for entry in std::fs::read_dir(dir).map_err(unknown)?.flatten() {
+        match entry.file_type().map_err(unknown)?.is_dir() {
+            true => repositories(root, &entry.path(), repos)?,
+            false => has_content = true,
+        }
+    }
+    if let (true, Ok(name)) = (has_content, dir.strip_prefix(root)) {
+        repos.push(name.to_string_lossy().to_string());
+    }
+    Ok(())
+}
+
+/// Collects manifest files and tag symlinks below a directory of manifests.
+/// Repository names with slashes are kept in nested directories.
+fn walk(dir: &Path, manifests: &mut Vec<PathBuf>, tags: &mut Vec<PathBuf>) -> Result<(), RegistryError> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir).map_err(unknown)?.flatten().collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let file_type = entry.file_type().map_err(unknown)?;
+        if file_type.is_symlink() {
+            tags.push(entry.path());
+        } else if file_type.is_dir() {
+            walk(&entry.path(), manifests, tags)?;
+        } else {
+            manifests.push(entry.path());
+        }
     }
+    Ok(())
 }
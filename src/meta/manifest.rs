@@ -1,5 +1,7 @@
-use std::collections::HashMap;
-use crate::util::sha256_digest;
+use std::collections::BTreeMap;
+use crate::error;
+use crate::error::RegistryError;
+use crate::types::{Digest, Reference};
 
 // /// The mediatype for WASM layers.
 // pub const WASM_LAYER_MEDIA_TYPE: &str = "application/vnd.wasm.content.layer.v1+wasm";
@@ -7,6 +9,8 @@ use crate::util::sha256_digest;
 // pub const WASM_CONFIG_MEDIA_TYPE: &str = "application/vnd.wasm.config.v1+json";
 /// The mediatype for an OCI manifest.
 pub const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
+/// The mediatype for an OCI image index.
+pub const IMAGE_INDEX_MEDIA_TYPE: &str = "application/vnd.oci.image.index.v1+json";
 /// The mediatype for an image config (manifest).
 pub const IMAGE_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
 // /// The mediatype that Docker uses for image configs.
@@ -25,19 +29,59 @@ pub const IMAGE_LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar
 // pub const IMAGE_LAYER_NONDISTRIBUTABLE_GZIP_MEDIA_TYPE: &str =
 //     "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
 
+/// An image manifest, or an image index listing one manifest per platform
 #[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Manifest {
     pub schema_version: u8,
     pub media_type: Option<String>,
-    pub config: Descriptor,
+    /// Image indexes have no config
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<Descriptor>,
+    #[serde(default)]
     pub layers: Vec<Descriptor>,
-    pub annotations: Option<HashMap<String, String>>,
+    /// The manifests of an image index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifests: Option<Vec<Descriptor>>,
+    pub annotations: Option<BTreeMap<String, String>>,
+    /// What kind of artifact this is, e.g. a signature or SBOM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    /// The manifest this one refers to, if it is an artifact such as a
+    /// signature or SBOM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Descriptor>,
 }
 
 impl Manifest {
-    pub fn digest(&self) -> String {
-        sha256_digest(&serde_json::to_vec(self).unwrap())
+    /// The digest of the manifest as we serialize it, which is what it is
+    /// stored under when put through [`RawManifest::from`]
+    pub fn digest(&self) -> Digest {
+        Digest::sha256(&serde_json::to_vec(self).unwrap())
+    }
+
+    /// The config descriptor followed by the layer descriptors
+    pub fn descriptors(&self) -> impl Iterator<Item = &Descriptor> {
+        self.config.iter().chain(self.layers.iter())
+    }
+
+    /// The manifests an image index lists, which live in the same repository
+    pub fn children(&self) -> impl Iterator<Item = Digest> + '_ {
+        self.manifests.iter().flatten().filter_map(|d| Digest::parse(&d.digest).ok())
+    }
+
+    /// The config and layer digests. Descriptors with a malformed digest
+    /// can't point at any blob we have, so they are left out.
+    pub fn blobs(&self) -> impl Iterator<Item = Digest> + '_ {
+        self.descriptors().filter_map(|d| Digest::parse(&d.digest).ok())
+    }
+
+    /// The artifact type referrers are listed with. Artifacts that don't
+    /// state one go by the media type of their config, as the spec says.
+    pub fn referrer_type(&self) -> &str {
+        self.artifact_type.as_deref()
+            .or(self.config.as_ref().map(|c| c.media_type.as_str()))
+            .unwrap_or_default()
     }
 }
 
@@ -46,9 +90,12 @@ impl Default for Manifest {
         Manifest {
             schema_version: 2,
             media_type: None,
-            config: Descriptor::default(),
+            config: Some(Descriptor::default()),
             layers: vec![],
+            manifests: None,
             annotations: None,
+            artifact_type: None,
+            subject: None,
         }
     }
 }
@@ -60,7 +107,10 @@ pub struct Descriptor {
     pub digest: String,
     pub size: Option<i64>,
     pub urls: Option<Vec<String>>,
-    pub annotations: Option<HashMap<String, String>>,
+    pub annotations: Option<BTreeMap<String, String>>,
+    /// What the manifest is for, in the entries of an image index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
 }
 
 impl Default for Descriptor {
@@ -71,6 +121,94 @@ impl Default for Descriptor {
             size: Some(0),
             urls: None,
             annotations: None,
+            platform: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl std::fmt::Display for Platform {
+    /// The usual `os/architecture/variant` notation
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        match &self.variant {
+            Some(variant) => write!(f, "/{}", variant),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A manifest exactly as it was pushed. Clients know a manifest by the
+/// digest of its bytes, so they are stored and served without change and
+/// only parsed to see what the manifest references.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawManifest {
+    data: Vec<u8>,
+    manifest: Manifest,
+}
+
+impl RawManifest {
+    /// Parses a manifest, refusing anything that is neither an image
+    /// manifest nor an image index
+    pub fn parse(data: Vec<u8>) -> Result<RawManifest, RegistryError> {
+        let manifest: Manifest = serde_json::from_slice(&data)
+            .map_err(|e| RegistryError::from_err(error::MANIFEST_INVALID, Box::new(e)))?;
+        if manifest.config.is_none() && manifest.manifests.is_none() {
+            return Err(RegistryError::from_err(error::MANIFEST_INVALID,
+                "neither an image manifest with a config nor an image index".into()))
+        }
+        Ok(RawManifest { data, manifest })
+    }
+
+    /// Parses a manifest read back from a store. Stores only hold manifests
+    /// that parsed when they were pushed, so failing now means damage.
+    pub fn load(data: Vec<u8>) -> Result<RawManifest, RegistryError> {
+        RawManifest::parse(data).map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))
+    }
+
+    /// The SHA-256 digest of the bytes
+    pub fn digest(&self) -> Digest {
+        Digest::sha256(&self.data)
+    }
+
+    /// The digest the manifest is stored and served under when put by the
+    /// reference: one of the algorithm it was pushed by, or else SHA-256
+    pub fn digest_for(&self, reference: &Reference) -> Digest {
+        match reference {
+            Reference::Digest(digest) => Digest::of(digest.algorithm(), &self.data),
+            Reference::Tag(_) => self.digest(),
         }
     }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// The media type the manifest is served with. Manifests that don't
+    /// state one are taken for Docker images, or OCI indexes if they list
+    /// manifests.
+    pub fn media_type(&self) -> &str {
+        match (&self.manifest.media_type, &self.manifest.manifests) {
+            (Some(media_type), _) => media_type,
+            (None, Some(_)) => IMAGE_INDEX_MEDIA_TYPE,
+            (None, None) => IMAGE_MANIFEST_MEDIA_TYPE,
+        }
+    }
+}
+
+impl From<&Manifest> for RawManifest {
+    fn from(manifest: &Manifest) -> RawManifest {
+        RawManifest { data: serde_json::to_vec(manifest).unwrap(), manifest: manifest.clone() }
+    }
 }
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::meta::{Store, Manifest, RawManifest};
+use crate::error;
+use crate::error::RegistryError;
+use crate::types::{Digest, Reference, RepositoryName};
+use crate::util::lock;
+
+#[derive(Default)]
+struct Repository {
+    /// Serialized manifests by digest
+    manifests: HashMap<Digest, Vec<u8>>,
+    /// Tag to manifest digest
+    tags: BTreeMap<String, Digest>,
+}
+
+#[derive(Default)]
+struct Inner {
+    repositories: HashMap<String, Repository>,
+    size: usize,
+}
+
+/// Keeps manifests and tags in memory, refusing new manifests once they
+/// would take more than `limit` bytes. Everything is lost on restart.
+pub struct Memory {
+    limit: usize,
+    inner: Mutex<Inner>,
+}
+
+impl Memory {
+    pub fn new(limit: usize) -> Memory {
+        Memory { limit, inner: Mutex::new(Inner::default()) }
+    }
+}
+
+impl Store for Memory {
+    fn put_manifest(&self, namespace: &RepositoryName, reference: &Reference, m: &RawManifest) -> Result<(), RegistryError> {
+        let digest = m.digest_for(reference);
+        let data = m.data().to_vec();
+        let mut inner = lock(&self.inner);
+        // A refused push mustn't leave an empty repository behind
+        let stored = inner.repositories.get(namespace.as_str())
+            .is_some_and(|repo| repo.manifests.contains_key(&digest));
+        if !stored {
+            if inner.size + data.len() > self.limit {
+                return Err(RegistryError::from_err(error::DENIED,
+                    format!("memory limit of {} bytes exceeded", self.limit).into()))
+            }
+            inner.size += data.len();
+        }
+        let repo = inner.repositories.entry(namespace.to_string()).or_default();
+        repo.manifests.entry(digest.clone()).or_insert(data);
+        if let Reference::Tag(tag) = reference {
+            repo.tags.insert(tag.clone(), digest);
+        }
+        Ok(())
+    }
+
+    fn get_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<RawManifest, RegistryError> {
+        let inner = lock(&self.inner);
+        let data = inner.repositories.get(namespace.as_str()).and_then(|repo| {
+            let digest = match reference {
+                Reference::Tag(tag) => repo.tags.get(tag)?,
+                Reference::Digest(digest) => digest,
+            };
+            repo.manifests.get(digest)
+        });
+        match data {
+            Some(data) => RawManifest::load(data.clone()),
+            None => Err(RegistryError::from(error::MANIFEST_UNKNOWN))
+        }
+    }
+
+    fn list_tags(&self, namespace: &RepositoryName) -> Result<Vec<String>, RegistryError> {
+        let inner = lock(&self.inner);
+        match inner.repositories.get(namespace.as_str()) {
+            Some(repo) => Ok(repo.tags.keys().cloned().collect()),
+            None => Ok(vec![])
+        }
+    }
+
+    fn list_repositories(&self) -> Result<Vec<String>, RegistryError> {
+        let mut repos: Vec<String> = lock(&self.inner).repositories.keys().cloned().collect();
+        repos.sort();
+        Ok(repos)
+    }
+
+    fn delete_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<(), RegistryError> {
+        let mut inner = lock(&self.inner);
+        let repo = inner.repositories.get_mut(namespace.as_str())
+            .ok_or_else(|| RegistryError::from(error::MANIFEST_UNKNOWN))?;
+        let freed = match reference {
+            Reference::Tag(tag) => repo.tags.remove(tag).map(|_| 0),
+            Reference::Digest(digest) => {
+                repo.tags.retain(|_, d| d != digest);
+                repo.manifests.remove(digest).map(|data| data.len())
+            }
+        };
+        match freed {
+            Some(freed) => {
+                inner.size -= freed;
+                Ok(())
+            },
+            None => Err(RegistryError::from(error::MANIFEST_UNKNOWN))
+        }
+    }
+
+    fn list_referrers(&self, namespace: &RepositoryName, digest: &Digest) -> Result<Vec<Digest>, RegistryError> {
+        let inner = lock(&self.inner);
+        let repo = match inner.repositories.get(namespace.as_str()) {
+            Some(repo) => repo,
+            None => return Ok(vec![])
+        };
+        let mut referrers: Vec<Digest> = repo.manifests.iter()
+            .filter(|(_, data)| serde_json::from_slice::<Manifest>(data).ok()
+                .and_then(|m| m.subject)
+                .map(|s| s.digest == digest.as_str())
+                .unwrap_or(false))
+            .map(|(d, _)| d.clone())
+            .collect();
+        referrers.sort();
+        Ok(referrers)
+    }
+
+    fn list_manifests(&self, namespace: &RepositoryName) -> Result<Vec<Digest>, RegistryError> {
+        let inner = lock(&self.inner);
+        let mut manifests: Vec<Digest> = inner.repositories.get(namespace.as_str())
+            .map(|repo| repo.manifests.keys().cloned().collect())
+            .unwrap_or_default();
+        manifests.sort();
+        Ok(manifests)
+    }
+}
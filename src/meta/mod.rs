@@ -1,50 +1,201 @@
+use std::collections::HashSet;
+
+use crate::error;
 use crate::error::RegistryError;
+use crate::fsck::Problem;
+use crate::types::{Digest, Reference, RepositoryName};
+use crate::Options;
 
+pub mod db;
 pub mod fs;
+pub mod memory;
 mod manifest;
+mod traced;
 
 pub use manifest::*;
 
-pub trait Store {
-    fn put_manifest(&self, namespace: &str, reference: &str, m: &Manifest) -> Result<(), RegistryError>;
-    fn get_manifest(&self, namespace: &str, reference: &str) -> Result<Manifest, RegistryError>;
-    fn list_tags(&self, namespace: &str) -> Vec<String>;
+/// Names accepted by --meta-backend
+pub const BACKENDS: &[&str] = &["fs", "memory", "redb"];
+
+pub trait Store: Send + Sync {
+    /// Stores the manifest under its digest and points the tag, if any, at it
+    fn put_manifest(&self, namespace: &RepositoryName, reference: &Reference, m: &RawManifest) -> Result<(), RegistryError>;
+    fn get_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<RawManifest, RegistryError>;
+    fn list_tags(&self, namespace: &RepositoryName) -> Result<Vec<String>, RegistryError>;
+    fn list_repositories(&self) -> Result<Vec<String>, RegistryError>;
+    /// Removes a tag, or a manifest along with every tag pointing at it
+    fn delete_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<(), RegistryError>;
+    /// Digests of the manifests whose subject is the given digest
+    fn list_referrers(&self, namespace: &RepositoryName, digest: &Digest) -> Result<Vec<Digest>, RegistryError>;
+    /// Digests of every manifest stored in the repository, tagged or not
+    fn list_manifests(&self, namespace: &RepositoryName) -> Result<Vec<Digest>, RegistryError>;
+
+    /// Looks for damaged manifests and tags. Stores that can't be damaged
+    /// behind our back have nothing to report.
+    fn check(&self, _repair: bool) -> Result<Vec<Problem>, RegistryError> {
+        Ok(vec![])
+    }
+}
+
+/// Manifests reachable from the tags of a repository, including the
+/// referrers of tagged manifests and the manifests of tagged indexes, along
+/// with the tag or digest they were found under. Whatever can't be read is
+/// skipped, for checks that report damage on their own.
+pub fn readable_manifests(store: &dyn Store, repository: &RepositoryName) -> Vec<(String, Manifest)> {
+    let get = |reference: &Reference| store.get_manifest(repository, reference).ok();
+    let mut manifests = Vec::new();
+    let mut seen = HashSet::new();
+    for tag in store.list_tags(repository).unwrap_or_default() {
+        let Some(manifest) = Reference::parse(&tag).ok().and_then(|r| get(&r)) else {
+            continue
+        };
+        // Indexes list their manifests by digest, and those can be indexes too
+        let mut found = vec![(tag, manifest)];
+        while let Some((reference, manifest)) = found.pop() {
+            if !seen.insert(manifest.digest()) {
+                continue
+            }
+            for digest in manifest.manifest().children() {
+                if let Some(m) = get(&Reference::Digest(digest.clone())) {
+                    found.push((digest.to_string(), m));
+                }
+            }
+            for referrer in store.list_referrers(repository, &manifest.digest()).unwrap_or_default() {
+                if let Some(m) = get(&Reference::Digest(referrer.clone())) {
+                    found.push((referrer.to_string(), m));
+                }
+            }
+            manifests.push((reference, manifest.manifest().clone()));
+        }
+    }
+    manifests
+}
+
+/// Opens the metadata store selected in the options
+pub fn open(opts: &Options) -> Result<Box<dyn Store>, RegistryError> {
+    let store: Box<dyn Store> = match opts.meta_backend.as_str() {
+        "fs" => fs::Filesystem::new(&opts.data_dir)
+            .map(Box::new)
+            .map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))?,
+        "memory" => Box::new(memory::Memory::new(opts.get_memory_limit_bytes())),
+        "redb" => Box::new(db::Redb::new(&opts.data_dir)?),
+        other => return Err(RegistryError::from_err(error::UNKNOWN_ERROR,
+            format!("unknown meta backend {}", other).into())),
+    };
+    Ok(Box::new(traced::Traced::new(store, &opts.meta_backend)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn name(s: &str) -> RepositoryName {
+        RepositoryName::parse(s).unwrap()
+    }
+
+    fn tag(s: &str) -> Reference {
+        Reference::parse(s).unwrap()
+    }
+
     fn store_puts_and_gets(s: &dyn Store) {
         let m = Manifest::default();
-        s.put_manifest("namespace", "reference", &m).unwrap();
-        let m2 = s.get_manifest("namespace", "reference").unwrap();
-        assert_eq!(m, m2);
+        s.put_manifest(&name("namespace"), &tag("reference"), &RawManifest::from(&m)).unwrap();
+        let m2 = s.get_manifest(&name("namespace"), &tag("reference")).unwrap();
+        assert_eq!(&m, m2.manifest());
     }
 
     fn store_lists_tags(s: &dyn Store) {
         let m = Manifest::default();
-        s.put_manifest("tags", "one", &m).unwrap();
-        s.put_manifest("tags", "two", &m).unwrap();
-        s.put_manifest("tags", "three", &m).unwrap();
+        s.put_manifest(&name("tags"), &tag("one"), &RawManifest::from(&m)).unwrap();
+        s.put_manifest(&name("tags"), &tag("two"), &RawManifest::from(&m)).unwrap();
+        s.put_manifest(&name("tags"), &tag("three"), &RawManifest::from(&m)).unwrap();
         // Will be lexicographically sorted for fstore
-        assert_eq!(s.list_tags("tags"), vec!["one", "three", "two"])
+        assert_eq!(s.list_tags(&name("tags")).unwrap(), vec!["one", "three", "two"])
     }
 
     fn stores_by_digest_and_tag(s: &dyn Store) {
         let mut m = Manifest::default();
-        let mut anno = std::collections::HashMap::new();
+        let mut anno = std::collections::BTreeMap::new();
         anno.insert(String::from("foo"), String::from("bar"));
         m.annotations = Some(anno);
-        s.put_manifest("namespace", "tag", &m).unwrap();
-        let m2 = s.get_manifest("namespace", &m.digest()).unwrap();
-        assert_eq!(m, m2);
+        s.put_manifest(&name("namespace"), &tag("tag"), &RawManifest::from(&m)).unwrap();
+        let m2 = s.get_manifest(&name("namespace"), &Reference::Digest(m.digest())).unwrap();
+        assert_eq!(&m, m2.manifest());
+    }
+
+    fn store_keeps_manifest_bytes(s: &dyn Store) {
+        let data = serde_json::to_vec_pretty(&Manifest::default()).unwrap();
+        s.put_manifest(&name("pretty"), &tag("latest"), &RawManifest::parse(data.clone()).unwrap()).unwrap();
+        let stored = s.get_manifest(&name("pretty"), &Reference::Digest(Digest::sha256(&data))).unwrap();
+        assert_eq!(stored.data(), data.as_slice());
+        assert_eq!(s.get_manifest(&name("pretty"), &tag("latest")).unwrap(), stored);
     }
 
     fn allow_overwrite_tag(s: &dyn Store) {
         let m = Manifest::default();
-        s.put_manifest("replace", "latest", &m).unwrap();
-        s.put_manifest("replace", "latest", &m).unwrap();
+        s.put_manifest(&name("replace"), &tag("latest"), &RawManifest::from(&m)).unwrap();
+        s.put_manifest(&name("replace"), &tag("latest"), &RawManifest::from(&m)).unwrap();
+    }
+
+    fn store_lists_repositories(s: &dyn Store) {
+        let m = Manifest::default();
+        s.put_manifest(&name("repo-a"), &tag("latest"), &RawManifest::from(&m)).unwrap();
+        s.put_manifest(&name("repo-b"), &tag("latest"), &RawManifest::from(&m)).unwrap();
+        let repos = s.list_repositories().unwrap();
+        assert!(repos.contains(&String::from("repo-a")));
+        assert!(repos.contains(&String::from("repo-b")));
+
+        s.put_manifest(&name("docker.io/library/app"), &tag("latest"), &RawManifest::from(&m)).unwrap();
+        s.put_manifest(&name("docker.io/library/app/sub"), &tag("latest"), &RawManifest::from(&m)).unwrap();
+        let repos = s.list_repositories().unwrap();
+        assert!(repos.contains(&String::from("docker.io/library/app")));
+        assert!(repos.contains(&String::from("docker.io/library/app/sub")));
+        assert!(!repos.contains(&String::from("docker.io")));
+    }
+
+    fn store_tracks_referrers(s: &dyn Store) {
+        let m = Manifest::default();
+        s.put_manifest(&name("referred"), &tag("latest"), &RawManifest::from(&m)).unwrap();
+        let sig = Manifest {
+            subject: Some(Descriptor {
+                digest: m.digest().to_string(),
+                ..Descriptor::default()
+            }),
+            ..Manifest::default()
+        };
+        s.put_manifest(&name("referred"), &Reference::Digest(sig.digest()), &RawManifest::from(&sig)).unwrap();
+        assert_eq!(s.list_referrers(&name("referred"), &m.digest()).unwrap(), vec![sig.digest()]);
+        assert!(s.list_referrers(&name("referred"), &sig.digest()).unwrap().is_empty());
+        assert!(s.list_tags(&name("referred")).unwrap() == vec!["latest"]);
+    }
+
+    fn store_lists_manifests(s: &dyn Store) {
+        let tagged = Manifest::default();
+        let untagged = Manifest { schema_version: 1, ..Manifest::default() };
+        s.put_manifest(&name("listed"), &tag("latest"), &RawManifest::from(&tagged)).unwrap();
+        s.put_manifest(&name("listed"), &Reference::Digest(untagged.digest()), &RawManifest::from(&untagged)).unwrap();
+        s.put_manifest(&name("listed/nested"), &tag("latest"), &RawManifest::from(&untagged)).unwrap();
+        let mut expected = vec![tagged.digest(), untagged.digest()];
+        expected.sort();
+        assert_eq!(s.list_manifests(&name("listed")).unwrap(), expected);
+        assert!(s.list_manifests(&name("unlisted")).unwrap().is_empty());
+    }
+
+    fn store_deletes_manifests(s: &dyn Store) {
+        let m = Manifest { schema_version: 1, ..Manifest::default() };
+        s.put_manifest(&name("deleted"), &tag("one"), &RawManifest::from(&m)).unwrap();
+        s.put_manifest(&name("deleted"), &tag("two"), &RawManifest::from(&m)).unwrap();
+        s.put_manifest(&name("deleted"), &tag("three"), &RawManifest::from(&m)).unwrap();
+
+        s.delete_manifest(&name("deleted"), &tag("one")).unwrap();
+        assert!(s.get_manifest(&name("deleted"), &tag("one")).is_err());
+        assert_eq!(s.get_manifest(&name("deleted"), &tag("two")).unwrap().manifest(), &m);
+        assert!(s.delete_manifest(&name("deleted"), &tag("one")).is_err());
+
+        let digest = Reference::Digest(m.digest());
+        s.delete_manifest(&name("deleted"), &digest).unwrap();
+        assert!(s.get_manifest(&name("deleted"), &digest).is_err());
+        assert!(s.list_tags(&name("deleted")).unwrap().is_empty());
     }
 
     #[test]
@@ -54,6 +205,62 @@ mod tests {
         store_puts_and_gets(&fstore);
         store_lists_tags(&fstore);
         stores_by_digest_and_tag(&fstore);
+        store_keeps_manifest_bytes(&fstore);
         allow_overwrite_tag(&fstore);
+        store_lists_repositories(&fstore);
+        store_tracks_referrers(&fstore);
+        store_lists_manifests(&fstore);
+        store_deletes_manifests(&fstore);
+    }
+
+    #[test]
+    fn fs_store_reports_corrupt_manifests() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let fstore = fs::Filesystem::new(&test_path).unwrap();
+        fstore.list_tags(&name("corrupt")).unwrap();
+        std::fs::write(format!("{}/manifests/corrupt/latest", test_path), b"{not json").unwrap();
+        assert!(fstore.get_manifest(&name("corrupt"), &tag("latest")).is_err());
+    }
+
+    #[test]
+    fn memory_store_tests() {
+        let mstore = memory::Memory::new(1024 * 1024);
+        store_puts_and_gets(&mstore);
+        store_lists_tags(&mstore);
+        stores_by_digest_and_tag(&mstore);
+        store_keeps_manifest_bytes(&mstore);
+        allow_overwrite_tag(&mstore);
+        store_lists_repositories(&mstore);
+        store_tracks_referrers(&mstore);
+        store_lists_manifests(&mstore);
+        store_deletes_manifests(&mstore);
+    }
+
+    #[test]
+    fn redb_store_tests() {
+        let test_path = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let dbstore = db::Redb::new(&test_path).unwrap();
+        store_puts_and_gets(&dbstore);
+        store_lists_tags(&dbstore);
+        stores_by_digest_and_tag(&dbstore);
+        store_keeps_manifest_bytes(&dbstore);
+        allow_overwrite_tag(&dbstore);
+        store_lists_repositories(&dbstore);
+        store_tracks_referrers(&dbstore);
+        store_lists_manifests(&dbstore);
+        store_deletes_manifests(&dbstore);
+    }
+
+    #[test]
+    fn memory_store_enforces_limit() {
+        let m = Manifest::default();
+        let size = serde_json::to_vec(&m).unwrap().len();
+        let mstore = memory::Memory::new(size);
+        mstore.put_manifest(&name("limit"), &tag("one"), &RawManifest::from(&m)).unwrap();
+        // Same manifest under another tag takes no extra space
+        mstore.put_manifest(&name("limit"), &tag("two"), &RawManifest::from(&m)).unwrap();
+        mstore.put_manifest(&name("other"), &tag("one"), &RawManifest::from(&m)).unwrap_err();
+        // The refused push leaves no repository behind
+        assert_eq!(mstore.list_repositories().unwrap(), vec![String::from("limit")]);
     }
 }
@@ -0,0 +1,65 @@
+use tracing::info_span;
+
+use crate::error::RegistryError;
+use crate::fsck::Problem;
+use crate::types::{Digest, Reference, RepositoryName};
+
+use super::{RawManifest, Store};
+
+/// Records a span for every operation of the metadata store it wraps
+pub struct Traced {
+    inner: Box<dyn Store>,
+    backend: String,
+}
+
+impl Traced {
+    pub fn new(inner: Box<dyn Store>, backend: &str) -> Traced {
+        Traced { inner, backend: backend.to_string() }
+    }
+}
+
+impl Store for Traced {
+    fn put_manifest(&self, namespace: &RepositoryName, reference: &Reference, m: &RawManifest) -> Result<(), RegistryError> {
+        let _span = info_span!("meta.put_manifest", backend = %self.backend, repository = namespace.as_str(),
+            reference = reference.as_str()).entered();
+        self.inner.put_manifest(namespace, reference, m)
+    }
+
+    fn get_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<RawManifest, RegistryError> {
+        let _span = info_span!("meta.get_manifest", backend = %self.backend, repository = namespace.as_str(),
+            reference = reference.as_str()).entered();
+        self.inner.get_manifest(namespace, reference)
+    }
+
+    fn list_tags(&self, namespace: &RepositoryName) -> Result<Vec<String>, RegistryError> {
+        let _span = info_span!("meta.list_tags", backend = %self.backend, repository = namespace.as_str()).entered();
+        self.inner.list_tags(namespace)
+    }
+
+    fn list_repositories(&self) -> Result<Vec<String>, RegistryError> {
+        let _span = info_span!("meta.list_repositories", backend = %self.backend).entered();
+        self.inner.list_repositories()
+    }
+
+    fn delete_manifest(&self, namespace: &RepositoryName, reference: &Reference) -> Result<(), RegistryError> {
+        let _span = info_span!("meta.delete_manifest", backend = %self.backend, repository = namespace.as_str(),
+            reference = reference.as_str()).entered();
+        self.inner.delete_manifest(namespace, reference)
+    }
+
+    fn list_referrers(&self, namespace: &RepositoryName, digest: &Digest) -> Result<Vec<Digest>, RegistryError> {
+        let _span = info_span!("meta.list_referrers", backend = %self.backend, repository = namespace.as_str(),
+            digest = digest.as_str()).entered();
+        self.inner.list_referrers(namespace, digest)
+    }
+
+    fn list_manifests(&self, namespace: &RepositoryName) -> Result<Vec<Digest>, RegistryError> {
+        let _span = info_span!("meta.list_manifests", backend = %self.backend, repository = namespace.as_str()).entered();
+        self.inner.list_manifests(namespace)
+    }
+
+    fn check(&self, repair: bool) -> Result<Vec<Problem>, RegistryError> {
+        let _span = info_span!("meta.check", backend = %self.backend, repair).entered();
+        self.inner.check(repair)
+    }
+}
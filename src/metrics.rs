@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::blob::BlobStore;
+use crate::error::RegistryError;
+use crate::util::{block, lock};
+use crate::Blobert;
+
+/// Upper bounds of the latency buckets, in seconds
+const BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Listing a large blob store takes a while, so its size is only refreshed
+/// this often
+const BLOB_STATS_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; BUCKETS.len()];
+        }
+        if let Some(i) = BUCKETS.iter().position(|b| value <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Route, method and status of a request
+type RequestKey = (String, String, u16);
+
+#[derive(Default)]
+struct Inner {
+    requests: BTreeMap<RequestKey, Histogram>,
+    bytes_pushed: u64,
+    bytes_pulled: u64,
+    manifest_pushes: BTreeMap<String, u64>,
+    /// When the blob store was last listed, and its blob count and size
+    blob_stats: Option<(Instant, usize, u64)>,
+}
+
+/// Counters exported at `/metrics` in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+/// Quotes a label value
+fn label(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+impl Metrics {
+    /// Records a handled request. `route` is the pattern the request matched,
+    /// so repositories and digests don't each get their own series.
+    pub fn request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        lock(&self.inner).requests.entry((route.to_string(), method.to_string(), status))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn pushed(&self, bytes: u64) {
+        lock(&self.inner).bytes_pushed += bytes;
+    }
+
+    pub fn pulled(&self, bytes: u64) {
+        lock(&self.inner).bytes_pulled += bytes;
+    }
+
+    pub fn manifest_pushed(&self, repository: &str) {
+        *lock(&self.inner).manifest_pushes.entry(repository.to_string()).or_default() += 1;
+    }
+
+    fn blob_stats(&self, blobs: &dyn BlobStore) -> Result<(usize, u64), RegistryError> {
+        if let Some((at, count, size)) = lock(&self.inner).blob_stats {
+            if at.elapsed() < BLOB_STATS_TTL {
+                return Ok((count, size))
+            }
+        }
+        let digests = blobs.list_blobs()?;
+        let size = digests.iter()
+            .filter_map(|d| blobs.stat_blob(d).ok())
+            .map(|info| info.size)
+            .sum();
+        lock(&self.inner).blob_stats = Some((Instant::now(), digests.len(), size));
+        Ok((digests.len(), size))
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self, blobs: &dyn BlobStore, upload_sessions: usize) -> Result<String, RegistryError> {
+        let (blob_count, blob_size) = self.blob_stats(blobs)?;
+        let inner = lock(&self.inner);
+        // Writing to a String can't fail, so results are ignored throughout
+        let mut out = String::new();
+
+        header(&mut out, "blobert_http_requests_total", "counter", "Requests handled, by route, method and status");
+        for ((route, method, status), histogram) in &inner.requests {
+            let _ = writeln!(out, "blobert_http_requests_total{{route={},method={},status=\"{}\"}} {}",
+                label(route), label(method), status, histogram.count);
+        }
+
+        let name = "blobert_http_request_duration_seconds";
+        header(&mut out, name, "histogram", "Time taken to answer requests, by route, method and status");
+        for ((route, method, status), histogram) in &inner.requests {
+            let labels = format!("route={},method={},status=\"{}\"", label(route), label(method), status);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+            }
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, histogram.count);
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, histogram.count);
+        }
+
+        header(&mut out, "blobert_pushed_bytes_total", "counter", "Blob data received from clients");
+        let _ = writeln!(out, "blobert_pushed_bytes_total {}", inner.bytes_pushed);
+        header(&mut out, "blobert_pulled_bytes_total", "counter", "Blob data served to clients");
+        let _ = writeln!(out, "blobert_pulled_bytes_total {}", inner.bytes_pulled);
+
+        header(&mut out, "blobert_manifest_pushes_total", "counter", "Manifests pushed, by repository");
+        for (repository, count) in &inner.manifest_pushes {
+            let _ = writeln!(out, "blobert_manifest_pushes_total{{repository={}}} {}", label(repository), count);
+        }
+
+        header(&mut out, "blobert_upload_sessions", "gauge", "Upload sessions in progress");
+        let _ = writeln!(out, "blobert_upload_sessions {}", upload_sessions);
+        header(&mut out, "blobert_blobs", "gauge", "Blobs in the blob store");
+        let _ = writeln!(out, "blobert_blobs {}", blob_count);
+        header(&mut out, "blobert_blob_bytes", "gauge", "Size of the blob store");
+        let _ = writeln!(out, "blobert_blob_bytes {}", blob_size);
+        Ok(out)
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+pub async fn metrics(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let b = blobert.clone();
+    let body = block(move || b.metrics.render(b.blob_store.as_ref(), b.sessions.active())).await?;
+    Ok(HttpResponse::Ok()
+        .append_header(("Content-Type", "text/plain; version=0.0.4"))
+        .body(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::memory::Memory;
+
+    #[test]
+    fn it_renders_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.request("/v2/{namespace}/blobs/{id}", "GET", 200, Duration::from_millis(20));
+        metrics.request("/v2/{namespace}/blobs/{id}", "GET", 200, Duration::from_secs(20));
+        metrics.pulled(42);
+        metrics.manifest_pushed("library/\"app\"");
+
+        let text = metrics.render(&Memory::new(1024, 1024), 3).unwrap();
+        let series = "route=\"/v2/{namespace}/blobs/{id}\",method=\"GET\",status=\"200\"";
+        for line in [
+            format!("blobert_http_requests_total{{{}}} 2", series),
+            format!("blobert_http_request_duration_seconds_bucket{{{},le=\"0.01\"}} 0", series),
+            format!("blobert_http_request_duration_seconds_bucket{{{},le=\"0.025\"}} 1", series),
+            format!("blobert_http_request_duration_seconds_bucket{{{},le=\"10\"}} 1", series),
+            format!("blobert_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2", series),
+            String::from("blobert_pulled_bytes_total 42"),
+            String::from("blobert_manifest_pushes_total{repository=\"library/\\\"app\\\"\"} 1"),
+            String::from("blobert_upload_sessions 3"),
+            String::from("blobert_blobs 0"),
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} missing from\n{}", line, text);
+        }
+    }
+}
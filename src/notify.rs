@@ -0,0 +1,410 @@
+use actix_web::HttpRequest;
+use log::{debug, error, warn};
+use serde::{Serialize, Deserialize};
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::util::{block, lock, now, rfc3339};
+use crate::Options;
+
+/// Media type of the notification envelope, as sent by the Docker registry
+pub const EVENTS_MEDIA_TYPE: &str = "application/vnd.docker.distribution.events.v1+json";
+
+/// Longest we will wait between two attempts of the same delivery
+const MAX_BACKOFF_SECS: u64 = 300;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Push,
+    Pull,
+    Delete,
+    /// A tag was created or moved to another manifest. The manifest push is
+    /// reported as well, this is for consumers that only care about tags.
+    Tag,
+}
+
+/// An HTTP endpoint as read from the notification config file
+#[derive(Deserialize, Clone, Debug)]
+pub struct Endpoint {
+    pub name: String,
+    pub url: String,
+    /// Repositories to send events of, all of them if empty
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Actions to send events of, all of them if empty
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    /// Extra headers sent with every request, e.g. for authentication
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+impl Endpoint {
+    fn wants(&self, event: &Event) -> bool {
+        (self.repositories.is_empty() || self.repositories.contains(&event.target.repository))
+            && (self.actions.is_empty() || self.actions.contains(&event.action))
+    }
+}
+
+/// What an event is about
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Target {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub media_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Same as size, kept for consumers of older envelopes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub digest: String,
+    pub repository: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+/// The request that caused an event
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RequestInfo {
+    pub id: String,
+    pub addr: String,
+    pub host: String,
+    pub method: String,
+    pub useragent: String,
+}
+
+/// The registry instance an event comes from
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Source {
+    pub addr: String,
+    #[serde(rename = "instanceID")]
+    pub instance_id: String,
+}
+
+/// There is no authentication, so there is nobody to name
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Actor {}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Event {
+    pub id: String,
+    pub timestamp: String,
+    pub action: Action,
+    pub target: Target,
+    pub request: RequestInfo,
+    pub actor: Actor,
+    pub source: Source,
+}
+
+/// The body of every notification request
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    pub events: Vec<Event>,
+}
+
+/// An event waiting to be delivered to an endpoint
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Delivery {
+    endpoint: String,
+    event: Event,
+    attempts: u32,
+    /// UNIX time of the next attempt
+    next_attempt: u64,
+}
+
+/// Sends registry events to the configured endpoints. Pending deliveries are
+/// kept in `<data-dir>/notifications/queue.json`, so they survive restarts.
+pub struct Notifier {
+    endpoints: Vec<Endpoint>,
+    source: Source,
+    queue_path: PathBuf,
+    queue: Mutex<Vec<Delivery>>,
+    /// Whether the queue changed since it was last written
+    dirty: AtomicBool,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    /// Deliveries give up on endpoints that don't accept a connection within
+    /// `connect_timeout` or don't answer within `timeout`
+    pub fn new(addr: &str, data_dir: &str, endpoints: Vec<Endpoint>, connect_timeout: Duration,
+        timeout: Duration) -> Result<Notifier, Box<dyn Error>>
+    {
+        let dir = PathBuf::from(data_dir).join("notifications");
+        std::fs::create_dir_all(&dir)?;
+        let queue_path = dir.join("queue.json");
+        let queue = match std::fs::read(&queue_path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(Box::new(e)),
+        };
+        Ok(Notifier {
+            endpoints,
+            source: Source { addr: addr.to_string(), instance_id: uuid::Uuid::new_v4().to_string() },
+            queue_path,
+            queue: Mutex::new(queue),
+            dirty: AtomicBool::new(false),
+            client: reqwest::Client::builder()
+                .connect_timeout(connect_timeout)
+                .timeout(timeout)
+                .build()?,
+        })
+    }
+
+    pub fn from_options(opts: &Options) -> std::io::Result<Notifier> {
+        let invalid = |e: Box<dyn Error>|
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string());
+        let endpoints = match &opts.notification_config {
+            Some(path) => serde_json::from_slice(&std::fs::read(path)?)
+                .map_err(|e| invalid(Box::new(e)))?,
+            None => vec![],
+        };
+        Notifier::new(&opts.get_bind_addr(), &opts.data_dir, endpoints,
+            Duration::from_secs(opts.notification_connect_timeout),
+            Duration::from_secs(opts.notification_timeout)).map_err(invalid)
+    }
+
+    /// Writes the queue to disk if it changed since the last write. Events
+    /// only mark the queue as changed, and the delivery task writes it once
+    /// per round, so a busy registry doesn't rewrite it for every pull. A
+    /// failure only costs us the deliveries pending at the next restart.
+    pub fn flush(&self) {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return
+        }
+        let tmp = self.queue_path.with_extension("json.tmp");
+        let written = serde_json::to_vec(&*lock(&self.queue))
+            .map_err(std::io::Error::other)
+            .and_then(|payload| std::fs::write(&tmp, payload))
+            .and_then(|_| std::fs::rename(&tmp, &self.queue_path));
+        if let Err(e) = written {
+            error!("Error writing notification queue: {}", e);
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Number of deliveries waiting to be sent
+    pub fn pending(&self) -> usize {
+        lock(&self.queue).len()
+    }
+
+    /// Queues an event for every endpoint interested in it
+    pub fn notify(&self, req: &HttpRequest, action: Action, target: Target) {
+        if self.endpoints.is_empty() {
+            return
+        }
+        let header = |name| req.headers().get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let event = Event {
+            id: uuid::Uuid::new_v4().to_string(),
+            timestamp: rfc3339(now()),
+            action,
+            target,
+            request: RequestInfo {
+                id: crate::logging::request_id(req),
+                addr: req.peer_addr().map(|a| a.to_string()).unwrap_or_default(),
+                host: header("Host"),
+                method: req.method().to_string(),
+                useragent: header("User-Agent"),
+            },
+            actor: Actor::default(),
+            source: self.source.clone(),
+        };
+        self.enqueue(event);
+    }
+
+    fn enqueue(&self, event: Event) {
+        let mut queue = lock(&self.queue);
+        let before = queue.len();
+        for endpoint in self.endpoints.iter().filter(|e| e.wants(&event)) {
+            queue.push(Delivery { endpoint: endpoint.name.clone(), event: event.clone(), attempts: 0, next_attempt: 0 });
+        }
+        if queue.len() > before {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    async fn send(&self, endpoint: &Endpoint, event: &Event) -> Result<(), Box<dyn Error>> {
+        let envelope = Envelope { events: vec![event.clone()] };
+        let mut req = self.client.post(&endpoint.url)
+            .header("Content-Type", EVENTS_MEDIA_TYPE)
+            .body(serde_json::to_vec(&envelope)?);
+        for (name, value) in &endpoint.headers {
+            req = req.header(name, value);
+        }
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Attempts every delivery that is due, rescheduling failed ones with
+    /// exponential backoff until the endpoint's retries are used up. Every
+    /// endpoint gets its events in order, but endpoints are sent to side by
+    /// side, so a slow one doesn't hold up the others.
+    pub async fn deliver(&self) {
+        let mut due: BTreeMap<String, Vec<Delivery>> = BTreeMap::new();
+        for delivery in lock(&self.queue).iter().filter(|d| d.next_attempt <= now()) {
+            due.entry(delivery.endpoint.clone()).or_default().push(delivery.clone());
+        }
+        futures::future::join_all(due.into_values().map(|deliveries| self.deliver_to(deliveries))).await;
+    }
+
+    /// Attempts the due deliveries of one endpoint, one after the other
+    async fn deliver_to(&self, deliveries: Vec<Delivery>) {
+        for delivery in deliveries {
+            let endpoint = self.endpoints.iter().find(|e| e.name == delivery.endpoint);
+            let result = match endpoint {
+                Some(endpoint) => self.send(endpoint, &delivery.event).await,
+                None => Err(format!("endpoint {} is no longer configured", delivery.endpoint).into()),
+            };
+
+            let mut queue = lock(&self.queue);
+            let position = queue.iter()
+                .position(|d| d.endpoint == delivery.endpoint && d.event.id == delivery.event.id);
+            let position = match position {
+                Some(position) => position,
+                None => continue,
+            };
+            match result {
+                Ok(()) => {
+                    debug!("Delivered event {} to {}", delivery.event.id, delivery.endpoint);
+                    queue.remove(position);
+                },
+                Err(e) => {
+                    let pending = &mut queue[position];
+                    pending.attempts += 1;
+                    let retries = endpoint.map(|e| e.max_retries).unwrap_or(0);
+                    if pending.attempts > retries {
+                        error!("Giving up delivering event {} to {}: {}", delivery.event.id, delivery.endpoint, e);
+                        queue.remove(position);
+                    } else {
+                        warn!("Delivering event {} to {} failed: {}", delivery.event.id, delivery.endpoint, e);
+                        let backoff = 2u64.saturating_pow(pending.attempts).min(MAX_BACKOFF_SECS);
+                        pending.next_attempt = now() + backoff;
+                    }
+                },
+            }
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Starts a background task delivering queued events
+    pub fn schedule(self: Arc<Self>) {
+        if self.endpoints.is_empty() {
+            return
+        }
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(Duration::from_secs(1));
+            loop {
+                ticker.tick().await;
+                self.deliver().await;
+                let notifier = self.clone();
+                if let Err(e) = block(move || {
+                    notifier.flush();
+                    Ok(())
+                }).await {
+                    error!("Error writing notification queue: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::stand_in;
+    use actix_web::test::TestRequest;
+
+    fn endpoint(url: &str, repositories: &[&str], actions: Vec<Action>) -> Endpoint {
+        Endpoint {
+            name: url.to_string(),
+            url: url.to_string(),
+            repositories: repositories.iter().map(|r| r.to_string()).collect(),
+            actions,
+            headers: BTreeMap::new(),
+            max_retries: 1,
+        }
+    }
+
+    fn new_notifier(data_dir: &str, endpoints: Vec<Endpoint>) -> Notifier {
+        Notifier::new("127.0.0.1:7000", data_dir, endpoints, Duration::from_secs(1), Duration::from_secs(1)).unwrap()
+    }
+
+    fn target(repository: &str) -> Target {
+        Target { repository: repository.to_string(), tag: Some(String::from("v1")), ..Target::default() }
+    }
+
+    #[actix_web::test]
+    async fn it_delivers_filtered_events() {
+        let (url, received) = stand_in::recorder::<Envelope>("/events");
+        let url = format!("{}/events", url);
+        let data_dir = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let notifier = new_notifier(&data_dir, vec![
+            endpoint(&url, &["app"], vec![Action::Tag]),
+            endpoint("http://127.0.0.1:1/unreachable", &[], vec![]),
+        ]);
+        let req = TestRequest::default().insert_header(("User-Agent", "docker")).to_http_request();
+        notifier.notify(&req, Action::Push, target("app"));
+        notifier.notify(&req, Action::Tag, target("other"));
+        notifier.notify(&req, Action::Tag, target("app"));
+        assert_eq!(notifier.pending(), 4);
+        assert!(!notifier.queue_path.exists());
+
+        notifier.deliver().await;
+        let envelopes = received.lock().unwrap();
+        assert_eq!(envelopes.len(), 1);
+        let event = &envelopes[0].events[0];
+        assert_eq!((event.action, event.target.repository.as_str()), (Action::Tag, "app"));
+        assert_eq!(event.request.useragent, "docker");
+
+        // The failed deliveries wait for their retry, even across restarts
+        assert_eq!(notifier.pending(), 3);
+        notifier.flush();
+        let restarted = new_notifier(&data_dir, notifier.endpoints.clone());
+        assert_eq!(restarted.pending(), 3);
+    }
+
+    #[actix_web::test]
+    async fn it_gives_up_on_slow_endpoints_without_holding_up_others() {
+        let (url, received) = stand_in::recorder::<Envelope>("/events");
+        let slow = stand_in::serve(|cfg| {
+            cfg.route("/events", actix_web::web::post().to(|| async {
+                actix_web::rt::time::sleep(Duration::from_secs(30)).await;
+                actix_web::HttpResponse::Ok().finish()
+            }));
+        });
+        let data_dir = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let notifier = new_notifier(&data_dir, vec![
+            endpoint(&format!("{}/events", slow), &[], vec![]),
+            endpoint(&format!("{}/events", url), &[], vec![]),
+        ]);
+        let req = TestRequest::default().to_http_request();
+        notifier.notify(&req, Action::Push, target("app"));
+        notifier.notify(&req, Action::Tag, target("app"));
+
+        let started = std::time::Instant::now();
+        notifier.deliver().await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let actions: Vec<Action> = received.lock().unwrap().iter().map(|e| e.events[0].action).collect();
+        assert_eq!(actions, vec![Action::Push, Action::Tag]);
+        // Both events to the slow endpoint timed out and wait for a retry
+        assert_eq!(notifier.pending(), 2);
+    }
+}
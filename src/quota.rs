@@ -0,0 +1,290 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::blob::BlobStore;
+use crate::error::{RegistryError, DENIED, SIZE_INVALID};
+use crate::meta::{self, Manifest};
+use crate::types::{Digest, Reference, RepositoryName};
+use crate::util::lock;
+use crate::Options;
+
+/// How long the usage of a repository is reused. Pushes and deletes through
+/// the registry drop it right away, so this only delays changes made with the
+/// CLI.
+const USAGE_TTL: Duration = Duration::from_secs(60);
+
+/// Size of each blob of a repository or scope
+type Sizes = BTreeMap<Digest, u64>;
+
+/// Size limits on what can be pushed. Quotas are keyed by repository name, or
+/// by a prefix ending in `/` that covers every repository below it.
+///
+/// Pushes to a repository hold its [`Limits::push_lock`] from checking the
+/// quotas until the manifest is stored, so they can't both fit in room left
+/// for one. Pushes to different repositories under a prefix don't wait for
+/// each other, so together they can go over the prefix quota by up to one
+/// push each.
+#[derive(Debug, Default)]
+pub struct Limits {
+    pub max_blob_size: Option<u64>,
+    pub max_manifest_size: Option<u64>,
+    /// Quota of every repository without one of its own
+    pub repository_quota: Option<u64>,
+    pub quotas: BTreeMap<String, u64>,
+    /// The size of every blob of a repository, and when it was computed
+    usage: Mutex<HashMap<String, (Instant, Sizes)>>,
+    /// A lock per repository pushes were made to
+    pushes: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+/// Storage used by a repository or prefix, as reported by `/admin/usage`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub scope: String,
+    pub usage: u64,
+    pub limit: Option<u64>,
+}
+
+/// Unique blobs referenced by the stored manifests of a repository, tagged
+/// or not. That is what gc keeps, so it is what a repository takes up.
+pub fn repository_blobs(store: &dyn meta::Store, repository: &RepositoryName) -> Result<BTreeSet<Digest>, RegistryError> {
+    let mut blobs = BTreeSet::new();
+    for digest in store.list_manifests(repository)? {
+        blobs.extend(store.get_manifest(repository, &Reference::Digest(digest))?.manifest().blobs());
+    }
+    Ok(blobs)
+}
+
+/// Total size of the blobs we have among the given ones
+pub fn size_of(blobs: &dyn BlobStore, digests: &BTreeSet<Digest>) -> u64 {
+    sizes_of(blobs, digests).values().sum()
+}
+
+/// Sizes of the blobs we have among the given ones
+fn sizes_of(blobs: &dyn BlobStore, digests: &BTreeSet<Digest>) -> Sizes {
+    digests.iter()
+        .filter_map(|d| blobs.stat_blob(d).ok().map(|info| (d.clone(), info.size)))
+        .collect()
+}
+
+impl Limits {
+    pub fn from_options(opts: &Options) -> std::io::Result<Limits> {
+        let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let quotas: BTreeMap<String, String> = match &opts.quota_config {
+            Some(path) => serde_json::from_slice(&std::fs::read(path)?)
+                .map_err(|e| invalid(e.to_string()))?,
+            None => BTreeMap::new(),
+        };
+        let quotas = quotas.into_iter()
+            .map(|(scope, size)| parse_size(&size).map(|size| (scope, size)))
+            .collect::<Result<_, _>>()
+            .map_err(invalid)?;
+        let optional = |spec: &Option<String>| spec.as_deref().map(parse_size).transpose().map_err(invalid);
+        Ok(Limits {
+            max_blob_size: optional(&opts.max_blob_size)?,
+            max_manifest_size: Some(parse_size(&opts.max_manifest_size).map_err(invalid)?),
+            repository_quota: optional(&opts.repository_quota)?,
+            quotas,
+            ..Limits::default()
+        })
+    }
+
+    pub fn check_blob_size(&self, size: u64) -> Result<(), RegistryError> {
+        check_size(size, self.max_blob_size, "blob")
+    }
+
+    pub fn check_manifest_size(&self, size: u64) -> Result<(), RegistryError> {
+        check_size(size, self.max_manifest_size, "manifest")
+    }
+
+    /// The quotas that apply to a repository: its own, or the default, and
+    /// those of every prefix it falls under
+    fn scopes(&self, repository: &RepositoryName) -> Vec<(String, u64)> {
+        let own = self.quotas.get(repository.as_str()).copied().or(self.repository_quota)
+            .map(|limit| (repository.to_string(), limit));
+        let prefixes = self.quotas.iter()
+            .filter(|(scope, _)| scope.ends_with('/') && repository.as_str().starts_with(scope.as_str()))
+            .map(|(scope, limit)| (scope.clone(), *limit));
+        own.into_iter().chain(prefixes).collect()
+    }
+
+    /// Sizes of the blobs of a repository. Reading its manifests and stating
+    /// its blobs takes a while, so the result is kept until the repository
+    /// changes or [`USAGE_TTL`] passes.
+    fn repository_usage(&self, store: &dyn meta::Store, blobs: &dyn BlobStore, repository: &RepositoryName)
+        -> Result<Sizes, RegistryError>
+    {
+        if let Some((at, sizes)) = lock(&self.usage).get(repository.as_str()) {
+            if at.elapsed() < USAGE_TTL {
+                return Ok(sizes.clone())
+            }
+        }
+        let sizes = sizes_of(blobs, &repository_blobs(store, repository)?);
+        lock(&self.usage).insert(repository.to_string(), (Instant::now(), sizes.clone()));
+        Ok(sizes)
+    }
+
+    /// The lock to hold from checking a push to the repository against the
+    /// quotas until it is stored and its usage forgotten
+    pub fn push_lock(&self, repository: &RepositoryName) -> Arc<Mutex<()>> {
+        lock(&self.pushes).entry(repository.to_string()).or_default().clone()
+    }
+
+    /// Drops the usage kept for a repository, after its tags changed
+    pub fn forget(&self, repository: &RepositoryName) {
+        lock(&self.usage).remove(repository.as_str());
+    }
+
+    /// Sizes of the unique blobs of every repository in a scope
+    fn scope_usage(&self, store: &dyn meta::Store, blobs: &dyn BlobStore, scope: &str)
+        -> Result<Sizes, RegistryError>
+    {
+        if !scope.ends_with('/') {
+            return match RepositoryName::parse(scope) {
+                Ok(repository) => self.repository_usage(store, blobs, &repository),
+                Err(_) => Ok(Sizes::new()),
+            }
+        }
+        let mut sizes = Sizes::new();
+        for repository in store.list_repositories()? {
+            if let (true, Ok(repository)) = (repository.starts_with(scope), RepositoryName::parse(&repository)) {
+                sizes.extend(self.repository_usage(store, blobs, &repository)?);
+            }
+        }
+        Ok(sizes)
+    }
+
+    /// Fails with `DENIED` if storing the manifest would take a repository or
+    /// prefix over its quota. Pushes that add no new blobs are always allowed,
+    /// so lowering a quota doesn't stop retagging.
+    pub fn check_quota(&self, store: &dyn meta::Store, blobs: &dyn BlobStore,
+        repository: &RepositoryName, manifest: &Manifest) -> Result<(), RegistryError>
+    {
+        for (scope, limit) in self.scopes(repository) {
+            let sizes = self.scope_usage(store, blobs, &scope)?;
+            let before: u64 = sizes.values().sum();
+            let new = manifest.blobs().filter(|digest| !sizes.contains_key(digest)).collect();
+            let after = before + size_of(blobs, &new);
+            if after > limit && after > before {
+                return Err(RegistryError::from(DENIED).with_detail(serde_json::json!({
+                    "reason": format!("storage quota of {} exceeded", scope),
+                    "limit": limit,
+                    "usage": before,
+                    "requested": after - before,
+                })))
+            }
+        }
+        Ok(())
+    }
+
+    /// Usage of every repository and every configured prefix
+    pub fn usage(&self, store: &dyn meta::Store, blobs: &dyn BlobStore) -> Result<Vec<Usage>, RegistryError> {
+        let mut scopes: BTreeSet<String> = store.list_repositories()?.into_iter().collect();
+        scopes.extend(self.quotas.keys().filter(|scope| scope.ends_with('/')).cloned());
+        let mut usage = Vec::new();
+        for scope in scopes {
+            let limit = match scope.ends_with('/') {
+                true => self.quotas.get(&scope).copied(),
+                false => self.quotas.get(&scope).copied().or(self.repository_quota),
+            };
+            let used = self.scope_usage(store, blobs, &scope)?.values().sum();
+            usage.push(Usage { scope, usage: used, limit });
+        }
+        Ok(usage)
+    }
+}
+
+fn parse_size(spec: &str) -> Result<u64, String> {
+    byte_unit::Byte::from_str(spec)
+        .map(|bytes| bytes.get_bytes() as u64)
+        .map_err(|e| format!("invalid size {}: {}", spec, e))
+}
+
+fn check_size(size: u64, limit: Option<u64>, what: &str) -> Result<(), RegistryError> {
+    match limit {
+        Some(limit) if size > limit => Err(RegistryError::from(SIZE_INVALID).with_detail(serde_json::json!({
+            "reason": format!("{} larger than the limit of {} bytes", what, limit),
+            "limit": limit,
+            "size": size,
+        }))),
+        _ => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::memory::Memory as MemoryBlobs;
+    use crate::meta::memory::Memory as MemoryMeta;
+    use crate::meta::{Descriptor, RawManifest, Store};
+    use crate::types::UploadId;
+
+    fn put_blob(blobs: &dyn BlobStore, data: &[u8]) -> Descriptor {
+        let digest = Digest::sha256(data);
+        let id = UploadId::new();
+        let mut upload = blobs.upload_writer(&id).unwrap();
+        upload.write(data).unwrap();
+        upload.finish().unwrap();
+        blobs.commit(&id, &digest).unwrap();
+        Descriptor { digest: digest.to_string(), ..Descriptor::default() }
+    }
+
+    #[test]
+    fn it_enforces_quotas_on_unique_blobs() {
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        let limits = Limits {
+            repository_quota: Some(10),
+            quotas: BTreeMap::from([(String::from("team/"), 12)]),
+            ..Limits::default()
+        };
+        let (config, layer, big) = (put_blob(&blobs, b"conf"), put_blob(&blobs, b"layer"), put_blob(&blobs, b"0123456"));
+        let app = RepositoryName::parse("team/app").unwrap();
+        let v1 = Manifest { config: Some(config.clone()), layers: vec![layer.clone()], ..Manifest::default() };
+        limits.check_quota(&meta, &blobs, &app, &v1).unwrap();
+        meta.put_manifest(&app, &Reference::parse("v1").unwrap(), &RawManifest::from(&v1)).unwrap();
+        limits.forget(&app);
+        assert_eq!(limits.usage(&meta, &blobs).unwrap()[1], Usage { scope: String::from("team/app"), usage: 9, limit: Some(10) });
+
+        // Shared blobs only count once
+        let v2 = Manifest { config: Some(config), layers: vec![layer.clone(), layer], ..Manifest::default() };
+        limits.check_quota(&meta, &blobs, &app, &v2).unwrap();
+
+        let other = RepositoryName::parse("team/other").unwrap();
+        let big = Manifest { config: Some(big), ..Manifest::default() };
+        let err = limits.check_quota(&meta, &blobs, &other, &big).unwrap_err();
+        assert!(err.to_string().contains("team/"), "{}", err);
+
+        assert_eq!(limits.usage(&meta, &blobs).unwrap(), vec![
+            Usage { scope: String::from("team/"), usage: 9, limit: Some(12) },
+            Usage { scope: String::from("team/app"), usage: 9, limit: Some(10) },
+        ]);
+    }
+
+    #[test]
+    fn it_counts_manifests_pushed_by_digest() {
+        let (meta, blobs) = (MemoryMeta::new(1024 * 1024), MemoryBlobs::new(1024 * 1024, 1024));
+        let limits = Limits { repository_quota: Some(10), ..Limits::default() };
+        let app = RepositoryName::parse("app").unwrap();
+        let first = Manifest { config: Some(put_blob(&blobs, b"first")), ..Manifest::default() };
+        limits.check_quota(&meta, &blobs, &app, &first).unwrap();
+        meta.put_manifest(&app, &Reference::Digest(first.digest()), &RawManifest::from(&first)).unwrap();
+        limits.forget(&app);
+        assert_eq!(limits.usage(&meta, &blobs).unwrap(), vec![
+            Usage { scope: String::from("app"), usage: 5, limit: Some(10) },
+        ]);
+
+        let second = Manifest { config: Some(put_blob(&blobs, b"second")), ..Manifest::default() };
+        assert!(limits.check_quota(&meta, &blobs, &app, &second).is_err());
+    }
+
+    #[test]
+    fn it_limits_sizes() {
+        let limits = Limits { max_blob_size: Some(4), ..Limits::default() };
+        assert!(limits.check_blob_size(4).is_ok());
+        assert!(limits.check_blob_size(5).is_err());
+        assert!(limits.check_manifest_size(u64::MAX).is_ok());
+    }
+}
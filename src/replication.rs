@@ -0,0 +1,613 @@
+use futures::future::{FutureExt, LocalBoxFuture};
+use log::{debug, info, warn, error};
+use serde::{Serialize, Deserialize};
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::Options;
+use crate::types::Digest;
+use crate::util::{lock, now};
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Manifest types we are willing to copy between registries
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// Longest we will wait between two attempts of the same job
+const MAX_BACKOFF_SECS: u64 = 300;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Copy local repositories to the remote registry
+    Push,
+    /// Copy repositories from the remote registry into this one
+    Pull,
+}
+
+/// A replication rule as read from the replication config file
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    /// Base URL of the other registry, e.g. http://10.0.0.2:7000
+    pub remote: String,
+    pub direction: Direction,
+    pub repositories: Vec<String>,
+    /// Replicate a tag as soon as it is pushed (push rules only)
+    #[serde(default)]
+    pub on_push: bool,
+    /// Replicate every tag of the repositories every `interval` seconds
+    pub interval: Option<u64>,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Outcome of the last replication of a reference under a rule
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Status {
+    pub rule: String,
+    pub repository: String,
+    pub reference: String,
+    pub state: State,
+    pub attempts: u32,
+    pub digest: Option<String>,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+/// Minimal client for the parts of the distribution API we need to copy
+/// images around
+struct Registry<'a> {
+    client: &'a reqwest::Client,
+    base: &'a str,
+    /// How long each request but the blob transfers may take
+    timeout: Duration,
+}
+
+impl<'a> Registry<'a> {
+    fn url(&self, path: &str) -> String {
+        format!("{}/v2/{}", self.base.trim_end_matches('/'), path)
+    }
+
+    async fn blob_exists(&self, name: &str, digest: &str) -> Result<bool> {
+        let res = self.client.head(self.url(&format!("{}/blobs/{}", name, digest)))
+            .timeout(self.timeout)
+            .send().await?;
+        Ok(res.status().is_success())
+    }
+
+    async fn get_blob(&self, name: &str, digest: &str) -> Result<reqwest::Body> {
+        let res = self.client.get(self.url(&format!("{}/blobs/{}", name, digest)))
+            .send().await?
+            .error_for_status()?;
+        Ok(reqwest::Body::wrap_stream(res.bytes_stream()))
+    }
+
+    async fn push_blob(&self, name: &str, digest: &str, body: reqwest::Body) -> Result<()> {
+        let res = self.client.post(self.url(&format!("{}/blobs/uploads/", name)))
+            .timeout(self.timeout)
+            .send().await?
+            .error_for_status()?;
+        let location = self.location(&res)?;
+
+        let res = self.client.patch(&location)
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+            .send().await?
+            .error_for_status()?;
+        let location = self.location(&res).unwrap_or(location);
+
+        let separator = if location.contains('?') { '&' } else { '?' };
+        self.client.put(format!("{}{}digest={}", location, separator, digest))
+            .timeout(self.timeout)
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn get_manifest(&self, name: &str, reference: &str) -> Result<(Vec<u8>, String)> {
+        let res = self.client.get(self.url(&format!("{}/manifests/{}", name, reference)))
+            .header("Accept", MANIFEST_ACCEPT)
+            .timeout(self.timeout)
+            .send().await?
+            .error_for_status()?;
+        let media_type = res.headers().get("Content-Type")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        Ok((res.bytes().await?.to_vec(), media_type))
+    }
+
+    async fn put_manifest(&self, name: &str, reference: &str, body: Vec<u8>, media_type: &str) -> Result<Option<String>> {
+        let res = self.client.put(self.url(&format!("{}/manifests/{}", name, reference)))
+            .header("Content-Type", media_type)
+            .body(body)
+            .timeout(self.timeout)
+            .send().await?
+            .error_for_status()?;
+        Ok(res.headers().get("Docker-Content-Digest")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from))
+    }
+
+    async fn list_tags(&self, name: &str) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct TagList {
+            tags: Option<Vec<String>>,
+        }
+        let list: TagList = self.client.get(self.url(&format!("{}/tags/list", name)))
+            .timeout(self.timeout)
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+        Ok(list.tags.unwrap_or_default())
+    }
+
+    /// Resolves the Location header of an upload response, which registries
+    /// may return relative to their base URL
+    fn location(&self, res: &reqwest::Response) -> Result<String> {
+        let location = res.headers().get("Location")
+            .ok_or("upload response has no Location header")?
+            .to_str()?;
+        if location.starts_with('/') {
+            Ok(format!("{}{}", self.base.trim_end_matches('/'), location))
+        } else {
+            Ok(location.to_string())
+        }
+    }
+}
+
+/// Copies a manifest and everything it references from one registry to
+/// another. Indexes are copied recursively, child manifests first, and the
+/// manifest bytes are passed through untouched so the digest is preserved.
+fn copy_image<'a>(src: &'a Registry, dst: &'a Registry, name: &'a str, reference: &'a str)
+    -> LocalBoxFuture<'a, Result<String>>
+{
+    async move {
+        let (body, media_type) = src.get_manifest(name, reference).await?;
+        let digest = Digest::sha256(&body).to_string();
+        let value: serde_json::Value = serde_json::from_slice(&body)?;
+
+        if let Some(children) = value.get("manifests").and_then(|m| m.as_array()) {
+            for child in children {
+                let child_digest = child.get("digest")
+                    .and_then(|d| d.as_str())
+                    .ok_or("index entry has no digest")?;
+                copy_image(src, dst, name, child_digest).await?;
+            }
+        } else {
+            let layers = value.get("layers")
+                .and_then(|l| l.as_array())
+                .into_iter()
+                .flatten();
+            for descriptor in value.get("config").into_iter().chain(layers) {
+                let blob = descriptor.get("digest")
+                    .and_then(|d| d.as_str())
+                    .ok_or("descriptor has no digest")?;
+                if dst.blob_exists(name, blob).await? {
+                    debug!("Blob {}/{} already present at {}", name, blob, dst.base);
+                    continue
+                }
+                debug!("Copying blob {}/{} to {}", name, blob, dst.base);
+                let data = src.get_blob(name, blob).await?;
+                dst.push_blob(name, blob, data).await?;
+            }
+        }
+
+        match dst.put_manifest(name, reference, body, &media_type).await? {
+            Some(stored) if stored != digest => Err(format!(
+                "digest mismatch for {}:{}: sent {}, stored as {}",
+                name, reference, digest, stored).into()),
+            _ => Ok(digest)
+        }
+    }.boxed_local()
+}
+
+pub struct Replicator {
+    local: String,
+    rules: Vec<Rule>,
+    status_path: PathBuf,
+    status: Mutex<HashMap<String, Status>>,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl Replicator {
+    /// Replication gives up on registries that don't accept a connection
+    /// within `connect_timeout`, or take longer than `timeout` to answer a
+    /// request other than a blob transfer
+    pub fn new(local: &str, data_dir: &str, rules: Vec<Rule>, connect_timeout: Duration,
+        timeout: Duration) -> Result<Replicator>
+    {
+        let mut status_path = PathBuf::from(data_dir);
+        status_path.push("replication");
+        std::fs::create_dir_all(&status_path)?;
+        status_path.push("status.json");
+
+        let mut status = HashMap::new();
+        if status_path.exists() {
+            let saved: Vec<Status> = serde_json::from_slice(&std::fs::read(&status_path)?)?;
+            for s in saved {
+                status.insert(Replicator::key(&s.rule, &s.repository, &s.reference), s);
+            }
+        }
+
+        Ok(Replicator {
+            local: local.to_string(),
+            rules,
+            status_path,
+            status: Mutex::new(status),
+            client: reqwest::Client::builder().connect_timeout(connect_timeout).build()?,
+            timeout,
+        })
+    }
+
+    pub fn from_options(opts: &Options) -> std::io::Result<Replicator> {
+        let invalid = |e: Box<dyn Error>|
+            std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string());
+        let rules = match &opts.replication_config {
+            Some(path) => serde_json::from_slice(&std::fs::read(path)?)
+                .map_err(|e| invalid(Box::new(e)))?,
+            None => vec![],
+        };
+        Replicator::new(&opts.get_server_url(), &opts.data_dir, rules,
+            Duration::from_secs(opts.replication_connect_timeout),
+            Duration::from_secs(opts.replication_timeout)).map_err(invalid)
+    }
+
+    fn registry<'a>(&'a self, base: &'a str) -> Registry<'a> {
+        Registry { client: &self.client, base, timeout: self.timeout }
+    }
+
+    fn key(rule: &str, repository: &str, reference: &str) -> String {
+        format!("{}/{}:{}", rule, repository, reference)
+    }
+
+    /// Snapshot of the status of every replicated reference
+    pub fn statuses(&self) -> Vec<Status> {
+        let mut statuses: Vec<Status> = lock(&self.status)
+            .values().cloned().collect();
+        statuses.sort_by(|a, b| (&a.rule, &a.repository, &a.reference)
+            .cmp(&(&b.rule, &b.repository, &b.reference)));
+        statuses
+    }
+
+    /// Updates the status of a reference and writes every status to disk.
+    /// The lock is held while writing, so concurrent jobs can't interleave
+    /// their writes or save an older snapshot last.
+    fn record(&self, status: &Status) {
+        let key = Replicator::key(&status.rule, &status.repository, &status.reference);
+        let mut statuses = lock(&self.status);
+        statuses.insert(key, status.clone());
+        let mut snapshot: Vec<&Status> = statuses.values().collect();
+        snapshot.sort_by(|a, b| (&a.rule, &a.repository, &a.reference)
+            .cmp(&(&b.rule, &b.repository, &b.reference)));
+        let written = serde_json::to_vec_pretty(&snapshot)
+            .map_err(std::io::Error::other)
+            .and_then(|payload| std::fs::write(&self.status_path, payload));
+        if let Err(e) = written {
+            error!("Error writing replication status: {}", e);
+        }
+    }
+
+    /// Called after a manifest is pushed to this registry
+    pub fn on_push(self: &Arc<Self>, repository: &str, reference: &str) {
+        for rule in &self.rules {
+            if rule.direction == Direction::Push && rule.on_push
+                && rule.repositories.iter().any(|r| r == repository) {
+                let this = self.clone();
+                let rule = rule.clone();
+                let repository = repository.to_string();
+                let reference = reference.to_string();
+                actix_web::rt::spawn(async move {
+                    this.replicate(&rule, &repository, &reference).await;
+                });
+            }
+        }
+    }
+
+    /// Starts a background task for every rule with an interval
+    pub fn schedule(self: Arc<Self>) {
+        for rule in &self.rules {
+            if let Some(interval) = rule.interval {
+                let this = self.clone();
+                let rule = rule.clone();
+                actix_web::rt::spawn(async move {
+                    let mut ticker = actix_web::rt::time::interval(Duration::from_secs(interval));
+                    loop {
+                        ticker.tick().await;
+                        this.sync(&rule).await;
+                    }
+                });
+            }
+        }
+    }
+
+    /// Replicates every tag of every repository covered by the rule
+    pub async fn sync(&self, rule: &Rule) {
+        let (local, remote) = (self.registry(&self.local), self.registry(&rule.remote));
+        let src = match rule.direction {
+            Direction::Push => &local,
+            Direction::Pull => &remote,
+        };
+        for repository in &rule.repositories {
+            match src.list_tags(repository).await {
+                Ok(tags) => for tag in tags {
+                    self.replicate(rule, repository, &tag).await;
+                },
+                Err(e) => error!("Error listing tags of {} at {}: {}",
+                    repository, src.base, e),
+            }
+        }
+    }
+
+    /// Copies a single reference according to the rule, retrying with
+    /// exponential backoff, and records the outcome
+    pub async fn replicate(&self, rule: &Rule, repository: &str, reference: &str) -> Status {
+        let (local, remote) = (self.registry(&self.local), self.registry(&rule.remote));
+        let (src, dst) = match rule.direction {
+            Direction::Push => (&local, &remote),
+            Direction::Pull => (&remote, &local),
+        };
+
+        let mut status = Status {
+            rule: rule.name.clone(),
+            repository: repository.to_string(),
+            reference: reference.to_string(),
+            state: State::Running,
+            attempts: 0,
+            digest: None,
+            last_error: None,
+            updated_at: now(),
+        };
+
+        loop {
+            status.attempts += 1;
+            self.record(&status);
+            info!("Replicating {}:{} from {} to {} (attempt {})",
+                repository, reference, src.base, dst.base, status.attempts);
+
+            match copy_image(src, dst, repository, reference).await {
+                Ok(digest) => {
+                    status.state = State::Succeeded;
+                    status.digest = Some(digest);
+                    status.last_error = None;
+                },
+                Err(e) => {
+                    warn!("Replication of {}:{} failed: {}", repository, reference, e);
+                    status.last_error = Some(e.to_string());
+                    if status.attempts <= rule.max_retries {
+                        let backoff = 2u64.saturating_pow(status.attempts).min(MAX_BACKOFF_SECS);
+                        self.record(&status);
+                        actix_web::rt::time::sleep(Duration::from_secs(backoff)).await;
+                        continue
+                    }
+                    status.state = State::Failed;
+                },
+            }
+            status.updated_at = now();
+            self.record(&status);
+            return status
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{routes, Blobert};
+    use crate::util::{instance, stand_in};
+    use crate::meta::{Descriptor, Manifest, IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE};
+    use actix_web::{App, HttpServer};
+
+    /// Starts a blobert instance on a random port with its own data dir
+    fn start_instance() -> Options {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let opts = instance::options(&["--port", &port]);
+
+        let blobert = Blobert::new(opts.clone()).unwrap();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(blobert.clone())
+                .configure(routes)
+        })
+        .workers(1)
+        .listen(listener).unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+        opts
+    }
+
+    /// A client for checking on instances, which fails rather than hangs if
+    /// one doesn't answer
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(10))
+            .build().unwrap()
+    }
+
+    fn rule(remote: &str, direction: Direction) -> Rule {
+        Rule {
+            name: String::from("test"),
+            remote: remote.to_string(),
+            direction,
+            repositories: vec![String::from("repo")],
+            on_push: false,
+            interval: None,
+            max_retries: 0,
+        }
+    }
+
+    /// Pushes a single-layer image and returns its manifest digest
+    async fn push_image(client: &reqwest::Client, base: &str) -> (String, String) {
+        let registry = Registry { client, base, timeout: Duration::from_secs(10) };
+        let layer = b"layer data".to_vec();
+        let layer_digest = Digest::sha256(&layer).to_string();
+        registry.push_blob("repo", &layer_digest, layer.into()).await.unwrap();
+
+        let mut manifest = Manifest::default();
+        manifest.layers.push(Descriptor {
+            media_type: IMAGE_LAYER_MEDIA_TYPE.to_string(),
+            digest: layer_digest.clone(),
+            size: Some(10),
+            ..Descriptor::default()
+        });
+        manifest.config = Some(Descriptor { digest: layer_digest.clone(), ..Descriptor::default() });
+        let body = serde_json::to_vec(&manifest).unwrap();
+        registry.put_manifest("repo", "latest", body, IMAGE_MANIFEST_MEDIA_TYPE).await.unwrap();
+        (manifest.digest().to_string(), layer_digest)
+    }
+
+    async fn assert_replicated(client: &reqwest::Client, base: &str, digest: &str, layer: &str) {
+        let registry = Registry { client, base, timeout: Duration::from_secs(10) };
+        assert!(registry.blob_exists("repo", layer).await.unwrap());
+        let (body, _) = registry.get_manifest("repo", "latest").await.unwrap();
+        assert!(Digest::parse(digest).unwrap().verify(&body));
+        let (body, _) = registry.get_manifest("repo", digest).await.unwrap();
+        assert!(Digest::parse(digest).unwrap().verify(&body));
+    }
+
+    #[actix_web::test]
+    async fn it_pushes_to_another_instance() {
+        let a = start_instance();
+        let b = start_instance();
+        let client = client();
+        let (digest, layer) = push_image(&client, &a.get_server_url()).await;
+
+        let replicator = Replicator::from_options(&a).unwrap();
+        let status = replicator.replicate(
+            &rule(&b.get_server_url(), Direction::Push), "repo", "latest").await;
+        assert_eq!(status.state, State::Succeeded);
+        assert_eq!(status.digest, Some(digest.clone()));
+        assert_replicated(&client, &b.get_server_url(), &digest, &layer).await;
+
+        // A second run finds every blob already present
+        let status = replicator.replicate(
+            &rule(&b.get_server_url(), Direction::Push), "repo", "latest").await;
+        assert_eq!(status.state, State::Succeeded);
+    }
+
+    #[actix_web::test]
+    async fn it_pulls_from_another_instance() {
+        let a = start_instance();
+        let b = start_instance();
+        let client = client();
+        let (digest, layer) = push_image(&client, &a.get_server_url()).await;
+
+        let replicator = Replicator::from_options(&b).unwrap();
+        replicator.sync(&rule(&a.get_server_url(), Direction::Pull)).await;
+        let statuses = replicator.statuses();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].state, State::Succeeded);
+        assert_replicated(&client, &b.get_server_url(), &digest, &layer).await;
+    }
+
+    #[actix_web::test]
+    async fn it_copies_indexes_from_other_registries_byte_for_byte() {
+        let b = start_instance();
+        // Other registries don't write manifests the way blobert would
+        let layer = b"layer data".to_vec();
+        let layer_digest = Digest::sha256(&layer).to_string();
+        let image = serde_json::to_vec_pretty(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": layer_digest, "size": 10 },
+            "layers": [{ "mediaType": IMAGE_LAYER_MEDIA_TYPE, "digest": layer_digest, "size": 10 }],
+        })).unwrap();
+        let image_digest = Digest::sha256(&image).to_string();
+        let index = serde_json::to_vec_pretty(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": image_digest,
+                "size": image.len(),
+                "platform": { "architecture": "arm64", "os": "linux" },
+            }],
+        })).unwrap();
+        let index_digest = Digest::sha256(&index).to_string();
+
+        let (child, served) = (image_digest.clone(), (index.clone(), image.clone(), layer));
+        let remote = stand_in::serve(move |cfg| {
+            let (child, (index, image, layer)) = (child.clone(), served.clone());
+            cfg.route("/v2/repo/manifests/{reference}", actix_web::web::get().to(move |req: actix_web::HttpRequest| {
+                let (body, media_type) = match req.match_info().get("reference") == Some(child.as_str()) {
+                    true => (image.clone(), "application/vnd.oci.image.manifest.v1+json"),
+                    false => (index.clone(), "application/vnd.oci.image.index.v1+json"),
+                };
+                async move { actix_web::HttpResponse::Ok().content_type(media_type).body(body) }
+            }));
+            let layer = layer.clone();
+            cfg.route("/v2/repo/blobs/{digest}", actix_web::web::get().to(move || {
+                let layer = layer.clone();
+                async move { actix_web::HttpResponse::Ok().body(layer) }
+            }));
+        });
+
+        let replicator = Replicator::from_options(&b).unwrap();
+        let status = replicator.replicate(&rule(&remote, Direction::Pull), "repo", "latest").await;
+        assert_eq!(status.state, State::Succeeded, "{:?}", status.last_error);
+        assert_eq!(status.digest.as_deref(), Some(index_digest.as_str()));
+
+        let client = client();
+        let base = b.get_server_url();
+        let registry = Registry { client: &client, base: &base, timeout: Duration::from_secs(10) };
+        assert_eq!(registry.get_manifest("repo", "latest").await.unwrap(),
+            (index, String::from("application/vnd.oci.image.index.v1+json")));
+        assert_eq!(registry.get_manifest("repo", &image_digest).await.unwrap().0, image);
+        assert!(registry.blob_exists("repo", &layer_digest).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn it_gives_up_on_registries_that_dont_answer() {
+        let remote = stand_in::serve(|cfg| {
+            cfg.route("/v2/repo/manifests/latest", actix_web::web::get().to(|| async {
+                actix_web::rt::time::sleep(Duration::from_secs(30)).await;
+                actix_web::HttpResponse::Ok().finish()
+            }));
+        });
+        let data_dir = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let replicator = Replicator::new("http://127.0.0.1:1", &data_dir, vec![],
+            Duration::from_secs(1), Duration::from_secs(1)).unwrap();
+
+        let started = std::time::Instant::now();
+        let status = replicator.replicate(&rule(&remote, Direction::Pull), "repo", "latest").await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(status.state, State::Failed);
+    }
+
+    #[actix_web::test]
+    async fn it_records_failures() {
+        let a = start_instance();
+        let replicator = Replicator::from_options(&a).unwrap();
+        let status = replicator.replicate(
+            &rule(&a.get_server_url(), Direction::Push), "repo", "missing").await;
+        assert_eq!(status.state, State::Failed);
+        assert_eq!(status.attempts, 1);
+        assert!(status.last_error.is_some());
+
+        let reloaded = Replicator::from_options(&a).unwrap();
+        assert_eq!(reloaded.statuses().len(), 1);
+    }
+}
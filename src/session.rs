@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::blob::BlobStore;
+use crate::error;
+use crate::error::RegistryError;
+use crate::types::{Algorithm, Digest, RepositoryName, ResumableSha256, ResumableSha512, UploadId};
+use crate::util::{block, lock};
+
+/// Where sessions are saved across restarts, relative to the data dir
+const SAVED_SESSIONS: &str = "upload-sessions.json";
+
+pub fn saved_path(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join(SAVED_SESSIONS)
+}
+
+/// An upload handed out by `POST /v2/<name>/blobs/uploads/`
+pub struct Session {
+    pub repository: RepositoryName,
+    pub created: SystemTime,
+    pub received: u64,
+    last_active: Instant,
+    /// Hashes of everything received so far, so finishing an upload doesn't
+    /// have to read it back. Either algorithm can complete it.
+    hasher: ResumableSha256,
+    sha512: Option<ResumableSha512>,
+}
+
+/// A session as saved across restarts
+#[derive(Serialize, Deserialize)]
+struct SavedSession {
+    id: String,
+    repository: String,
+    /// Seconds since the UNIX epoch
+    created: u64,
+    received: u64,
+    hasher: ResumableSha256,
+    /// Missing from sessions saved before SHA-512 hashes were kept
+    #[serde(default)]
+    sha512: Option<ResumableSha512>,
+}
+
+impl Session {
+    /// Digest of the received data with the algorithm. Sessions resumed
+    /// from before SHA-512 hashes were kept have none.
+    pub fn digest(&self, algorithm: Algorithm) -> Option<Digest> {
+        match algorithm {
+            Algorithm::Sha256 => Some(self.hasher.clone().finish()),
+            Algorithm::Sha512 => self.sha512.clone().map(ResumableSha512::finish),
+        }
+    }
+}
+
+/// The upload sessions in progress. They live in memory, and are only saved
+/// to disk when the server shuts down cleanly. Uploads interrupted by a crash
+/// have to start over; their leftovers are found by `blobert fsck`.
+pub struct Sessions {
+    ttl: Duration,
+    sessions: Mutex<HashMap<UploadId, Session>>,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Sessions {
+        Sessions { ttl, sessions: Mutex::new(HashMap::new()) }
+    }
+
+    /// Number of sessions in progress
+    pub fn active(&self) -> usize {
+        lock(&self.sessions).len()
+    }
+
+    pub fn start(&self, repository: &RepositoryName) -> UploadId {
+        let id = UploadId::new();
+        lock(&self.sessions).insert(id.clone(), Session {
+            repository: repository.clone(),
+            created: SystemTime::now(),
+            received: 0,
+            last_active: Instant::now(),
+            hasher: ResumableSha256::default(),
+            sha512: Some(ResumableSha512::default()),
+        });
+        id
+    }
+
+    /// Bytes received so far by a session of the repository
+    pub fn progress(&self, repository: &RepositoryName, id: &UploadId) -> Result<u64, RegistryError> {
+        self.check(repository, id)?;
+        lock(&self.sessions).get(id).map(|s| s.received).ok_or_else(|| RegistryError::from(error::BLOB_UPLOAD_UNKNOWN))
+    }
+
+    /// Fails with `BLOB_UPLOAD_UNKNOWN` unless the session was started for
+    /// this repository and hasn't expired
+    pub fn check(&self, repository: &RepositoryName, id: &UploadId) -> Result<(), RegistryError> {
+        match lock(&self.sessions).get(id) {
+            Some(session) if session.repository == *repository => Ok(()),
+            _ => Err(RegistryError::from(error::BLOB_UPLOAD_UNKNOWN)
+                .with_detail(serde_json::json!({ "id": id.as_str() })))
+        }
+    }
+
+    /// Records data written to the session, returning the total received
+    pub fn received(&self, id: &UploadId, data: &[u8]) -> Result<u64, RegistryError> {
+        let mut sessions = lock(&self.sessions);
+        let session = sessions.get_mut(id).ok_or_else(|| RegistryError::from(error::BLOB_UPLOAD_UNKNOWN))?;
+        session.hasher.update(data);
+        if let Some(sha512) = &mut session.sha512 {
+            sha512.update(data);
+        }
+        session.received += data.len() as u64;
+        session.last_active = Instant::now();
+        Ok(session.received)
+    }
+
+    /// Bytes received by a session of the repository and their digest, as
+    /// [`Session::digest`] gives it. The session is left in place.
+    pub fn digest(&self, repository: &RepositoryName, id: &UploadId, algorithm: Algorithm)
+            -> Result<(u64, Option<Digest>), RegistryError> {
+        self.check(repository, id)?;
+        lock(&self.sessions).get(id).map(|s| (s.received, s.digest(algorithm)))
+            .ok_or_else(|| RegistryError::from(error::BLOB_UPLOAD_UNKNOWN))
+    }
+
+    /// Ends a session, returning what was recorded about it
+    pub fn finish(&self, repository: &RepositoryName, id: &UploadId) -> Result<Session, RegistryError> {
+        self.check(repository, id)?;
+        lock(&self.sessions).remove(id).ok_or_else(|| RegistryError::from(error::BLOB_UPLOAD_UNKNOWN))
+    }
+
+    /// Removes the sessions idle for longer than the TTL along with their
+    /// data. Returns how many were removed.
+    pub fn reap(&self, blobs: &dyn BlobStore) -> usize {
+        let expired: Vec<UploadId> = {
+            let mut sessions = lock(&self.sessions);
+            let expired = sessions.iter()
+                .filter(|(_, s)| s.last_active.elapsed() >= self.ttl)
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                sessions.remove(id);
+            }
+            expired
+        };
+        for id in &expired {
+            if let Err(e) = blobs.cancel_upload(id) {
+                warn!("Removing expired upload {} failed: {}", id, e);
+            }
+        }
+        expired.len()
+    }
+
+    /// Writes every session to a file, so uploads can be resumed after a
+    /// restart. Returns how many were saved.
+    pub fn save(&self, path: &Path) -> std::io::Result<usize> {
+        let saved: Vec<SavedSession> = lock(&self.sessions).iter()
+            .map(|(id, session)| SavedSession {
+                id: id.to_string(),
+                repository: session.repository.to_string(),
+                created: session.created.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+                received: session.received,
+                hasher: session.hasher.clone(),
+                sha512: session.sha512.clone(),
+            })
+            .collect();
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&saved)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(saved.len())
+    }
+
+    /// Picks up the sessions saved by `save`, if any. The file is removed,
+    /// as the sessions stop matching it as soon as they receive more data.
+    pub fn load(ttl: Duration, path: &Path) -> std::io::Result<Sessions> {
+        let sessions = Sessions::new(ttl);
+        let saved: Vec<SavedSession> = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(sessions),
+            Err(e) => return Err(e),
+        };
+        for session in saved {
+            let (id, repository) = match (UploadId::parse(&session.id), RepositoryName::parse(&session.repository)) {
+                (Ok(id), Ok(repository)) => (id, repository),
+                _ => {
+                    warn!("Skipping invalid saved upload session {}", session.id);
+                    continue
+                },
+            };
+            lock(&sessions.sessions).insert(id, Session {
+                repository,
+                created: UNIX_EPOCH + Duration::from_secs(session.created),
+                received: session.received,
+                // Time spent shut down doesn't count towards the TTL
+                last_active: Instant::now(),
+                hasher: session.hasher,
+                sha512: session.sha512,
+            });
+        }
+        std::fs::remove_file(path)?;
+        info!("Resumed {} upload sessions", sessions.active());
+        Ok(sessions)
+    }
+
+    /// Starts a background task reaping expired sessions
+    pub fn schedule(self: Arc<Self>, blobs: Arc<dyn BlobStore>) {
+        let period = self.ttl.clamp(Duration::from_secs(1), Duration::from_secs(60));
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(period);
+            loop {
+                ticker.tick().await;
+                let (sessions, blobs) = (self.clone(), blobs.clone());
+                match block(move || Ok(sessions.reap(blobs.as_ref()))).await {
+                    Ok(0) => (),
+                    Ok(reaped) => info!("Removed {} expired upload sessions", reaped),
+                    Err(e) => warn!("Error removing expired upload sessions: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::memory::Memory;
+
+    #[test]
+    fn it_tracks_sessions_per_repository() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let (app, other) = (RepositoryName::parse("app").unwrap(), RepositoryName::parse("other").unwrap());
+        let id = sessions.start(&app);
+        assert!(sessions.check(&app, &id).is_ok());
+        assert!(sessions.check(&other, &id).is_err());
+        assert!(sessions.check(&app, &UploadId::new()).is_err());
+
+        assert_eq!(sessions.received(&id, b"data").unwrap(), 4);
+        let session = sessions.finish(&app, &id).unwrap();
+        assert_eq!(session.received, 4);
+        assert_eq!(session.digest(Algorithm::Sha256), Some(Digest::sha256(b"data")));
+        assert!(sessions.finish(&app, &id).is_err());
+    }
+
+    #[test]
+    fn it_resumes_saved_sessions() {
+        let path = saved_path(&format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let app = RepositoryName::parse("app").unwrap();
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let id = sessions.start(&app);
+        sessions.received(&id, b"hello ").unwrap();
+        assert_eq!(sessions.save(&path).unwrap(), 1);
+
+        let resumed = Sessions::load(Duration::from_secs(60), &path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(resumed.progress(&app, &id).unwrap(), 6);
+        resumed.received(&id, b"world").unwrap();
+        let session = resumed.finish(&app, &id).unwrap();
+        assert_eq!(session.digest(Algorithm::Sha256), Some(Digest::sha256(b"hello world")));
+        assert_eq!(session.digest(Algorithm::Sha512), Some(Digest::of(Algorithm::Sha512, b"hello world")));
+
+        // Nothing saved, nothing to resume
+        assert_eq!(Sessions::load(Duration::from_secs(60), &path).unwrap().active(), 0);
+    }
+
+    #[test]
+    fn it_reaps_idle_sessions() {
+        let blobs = Memory::new(1024, 1024);
+        let sessions = Sessions::new(Duration::ZERO);
+        let id = sessions.start(&RepositoryName::parse("app").unwrap());
+        let mut upload = blobs.upload_writer(&id).unwrap();
+        upload.write(b"partial").unwrap();
+        upload.finish().unwrap();
+
+        assert_eq!(sessions.reap(&blobs), 1);
+        assert!(sessions.received(&id, b"more").is_err());
+        assert!(blobs.commit(&id, &Digest::sha256(b"partial")).is_err());
+    }
+}
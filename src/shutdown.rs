@@ -0,0 +1,108 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use log::{info, warn};
+
+use crate::error::{self, RegistryError};
+use crate::util::lock;
+
+/// Time uploads get to save what they received once the grace period is
+/// over, before the server drops their connections
+pub const FLUSH_TIME: Duration = Duration::from_secs(10);
+
+/// Lets requests know the server is shutting down. While draining, no new
+/// upload sessions are started. Once the grace period is over, uploads still
+/// receiving data save what they have and stop.
+pub struct Shutdown {
+    draining: AtomicBool,
+    expired: Shared<oneshot::Receiver<()>>,
+    expire: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        let (expire, expired) = oneshot::channel();
+        Shutdown {
+            draining: AtomicBool::new(false),
+            expired: expired.shared(),
+            expire: Mutex::new(Some(expire)),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `UNAVAILABLE` once the server is shutting down
+    pub fn check_accepting(&self) -> Result<(), RegistryError> {
+        match self.draining() {
+            true => Err(RegistryError::from(error::UNAVAILABLE)
+                .with_detail(serde_json::json!({ "reason": "the registry is shutting down" }))),
+            false => Ok(()),
+        }
+    }
+
+    /// Resolves once the grace period is over
+    pub fn expired(&self) -> impl Future<Output = ()> + Unpin {
+        self.expired.clone().map(|_| ())
+    }
+
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn expire(&self) {
+        self.drain();
+        let expire = lock(&self.expire).take();
+        if let Some(expire) = expire {
+            let _ = expire.send(());
+        }
+    }
+
+    /// Waits for SIGTERM or SIGINT, then stops the server, giving requests in
+    /// progress `grace` to finish. The server should be built with signal
+    /// handling disabled and a shutdown timeout of `grace` plus `FLUSH_TIME`.
+    pub fn watch(self: Arc<Self>, server: ServerHandle, grace: Duration) {
+        actix_web::rt::spawn(async move {
+            let (mut term, mut int) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+                (Ok(term), Ok(int)) => (term, int),
+                (Err(e), _) | (_, Err(e)) => {
+                    warn!("Can't handle shutdown signals, stopping abruptly instead: {}", e);
+                    return
+                },
+            };
+            futures::future::select(Box::pin(term.recv()), Box::pin(int.recv())).await;
+            info!("Shutting down, giving requests in progress {}s to finish", grace.as_secs());
+            self.drain();
+            actix_web::rt::spawn(server.stop(true));
+            actix_web::rt::time::sleep(grace).await;
+            info!("Grace period over, saving unfinished uploads");
+            self.expire();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn it_signals_expiry_to_every_waiter() {
+        let shutdown = Shutdown::default();
+        assert!(shutdown.check_accepting().is_ok());
+        let waiters = (shutdown.expired(), shutdown.expired());
+        shutdown.expire();
+        futures::join!(waiters.0, waiters.1);
+        // Waiting after the fact returns right away
+        shutdown.expired().await;
+        assert!(shutdown.check_accepting().is_err());
+    }
+}
@@ -0,0 +1,395 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::ServiceRequest;
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Span, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::Options;
+use crate::util::lock;
+
+/// Spans kept while the collector can't be reached. Newer ones are dropped.
+const MAX_QUEUED_SPANS: usize = 10_000;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Spans of other crates, like the HTTP client's, aren't exported
+const TARGET: &str = "blobert";
+
+// Span kinds of the OTLP protocol
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const KIND_CLIENT: u8 = 3;
+
+const STATUS_ERROR: u8 = 2;
+
+/// The trace a request belongs to, as passed in the W3C `traceparent` header
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// IDs are hex and can't be all zeros
+fn is_id(s: &str, len: usize) -> bool {
+    is_hex(s, len) && s.bytes().any(|b| b != b'0')
+}
+
+impl TraceContext {
+    /// Parses a `traceparent` header. Later versions of the format may add
+    /// fields, which are ignored.
+    pub fn parse(header: &str) -> Option<TraceContext> {
+        let fields: Vec<&str> = header.trim().split('-').collect();
+        let (version, trace_id, span_id, flags, rest) = match fields.as_slice() {
+            [version, trace_id, span_id, flags, rest @ ..] => (*version, *trace_id, *span_id, *flags, rest),
+            _ => return None,
+        };
+        let valid = is_hex(version, 2) && version != "ff"
+            && (version != "00" || rest.is_empty())
+            && is_id(trace_id, 32)
+            && is_id(span_id, 16)
+            && is_hex(flags, 2);
+        valid.then(|| TraceContext { trace_id: trace_id.to_string(), span_id: span_id.to_string() })
+    }
+}
+
+/// A span as it is sent to the collector
+#[derive(Debug, Clone)]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    error: bool,
+    start: u64,
+    end: u64,
+    attributes: serde_json::Map<String, Value>,
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// Random hex ID of the given number of bytes
+fn random_id(bytes: usize) -> String {
+    hex::encode(&uuid::Uuid::new_v4().as_bytes()[..bytes])
+}
+
+/// Copies span fields into the span data. A few fields named like those of
+/// `tracing-opentelemetry` set properties of the span instead.
+struct Fields<'a> {
+    span: &'a mut SpanData,
+    remote_parent: Option<TraceContext>,
+}
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "traceparent" => self.remote_parent = TraceContext::parse(value),
+            "otel.name" => self.span.name = value.to_string(),
+            "otel.kind" => self.span.kind = match value {
+                "server" => KIND_SERVER,
+                "client" => KIND_CLIENT,
+                _ => KIND_INTERNAL,
+            },
+            "otel.status_code" => self.span.error = value.eq_ignore_ascii_case("error"),
+            name => { self.span.attributes.insert(name.to_string(), value.into()); },
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value))
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.span.attributes.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.span.attributes.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.span.attributes.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.span.attributes.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// Collects finished spans and sends them to an OTLP collector over HTTP,
+/// in the JSON encoding
+pub struct Exporter {
+    url: String,
+    service: String,
+    client: reqwest::Client,
+    spans: Mutex<Vec<SpanData>>,
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+        Value::Number(n) => json!({ "intValue": n.to_string() }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+impl Exporter {
+    /// `endpoint` is the base URL of the collector, e.g. http://localhost:4318
+    pub fn new(endpoint: &str, service: &str) -> Exporter {
+        Exporter {
+            url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            service: service.to_string(),
+            client: reqwest::Client::new(),
+            spans: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn push(&self, span: SpanData) {
+        let mut spans = lock(&self.spans);
+        if spans.len() < MAX_QUEUED_SPANS {
+            spans.push(span);
+        }
+    }
+
+    /// Body of an OTLP export request
+    fn export_request(&self, spans: &[SpanData]) -> Value {
+        let spans: Vec<Value> = spans.iter().map(|span| json!({
+            "traceId": span.trace_id,
+            "spanId": span.span_id,
+            "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+            "name": span.name,
+            "kind": span.kind,
+            "startTimeUnixNano": span.start.to_string(),
+            "endTimeUnixNano": span.end.to_string(),
+            "attributes": span.attributes.iter().map(|(k, v)| attribute(k, v)).collect::<Vec<_>>(),
+            "status": if span.error { json!({ "code": STATUS_ERROR }) } else { json!({}) },
+        })).collect();
+        json!({
+            "resourceSpans": [{
+                "resource": { "attributes": [attribute("service.name", &self.service.as_str().into())] },
+                "scopeSpans": [{ "scope": { "name": TARGET }, "spans": spans }],
+            }]
+        })
+    }
+
+    /// Sends the spans finished since the last flush
+    pub async fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let spans = std::mem::take(&mut *lock(&self.spans));
+        if spans.is_empty() {
+            return Ok(())
+        }
+        debug!("Exporting {} spans to {}", spans.len(), self.url);
+        self.client.post(&self.url)
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&self.export_request(&spans))?)
+            .send().await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Starts a background task sending finished spans to the collector.
+    /// Spans that fail to send are dropped, tracing is best effort.
+    pub fn schedule(self: Arc<Self>) {
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(FLUSH_INTERVAL);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.flush().await {
+                    warn!("Exporting spans to {} failed: {}", self.url, e);
+                }
+            }
+        });
+    }
+}
+
+/// Turns our spans into OTLP spans and hands them to the exporter once they
+/// close
+pub struct OtlpLayer {
+    exporter: Arc<Exporter>,
+}
+
+impl OtlpLayer {
+    pub fn new(exporter: Arc<Exporter>) -> OtlpLayer {
+        OtlpLayer { exporter }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer where S: Subscriber + for<'a> LookupSpan<'a> {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !attrs.metadata().target().starts_with(TARGET) {
+            return
+        }
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut data = SpanData {
+            trace_id: String::new(),
+            span_id: random_id(8),
+            parent_span_id: None,
+            name: attrs.metadata().name().to_string(),
+            kind: KIND_INTERNAL,
+            error: false,
+            start: now_nanos(),
+            end: 0,
+            attributes: serde_json::Map::new(),
+        };
+        let mut fields = Fields { span: &mut data, remote_parent: None };
+        attrs.record(&mut fields);
+        let remote_parent = fields.remote_parent;
+
+        // The closest enclosing span of ours, skipping those of other crates
+        let local_parent = span.scope().skip(1)
+            .find_map(|parent| parent.extensions().get::<SpanData>()
+                .map(|p| (p.trace_id.clone(), p.span_id.clone())));
+        let (trace_id, parent_span_id) = match (local_parent, remote_parent) {
+            (Some((trace_id, span_id)), _) => (trace_id, Some(span_id)),
+            (None, Some(remote)) => (remote.trace_id, Some(remote.span_id)),
+            (None, None) => (random_id(16), None),
+        };
+        data.trace_id = trace_id;
+        data.parent_span_id = parent_span_id;
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut Fields { span: data, remote_parent: None });
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(mut data) = span.extensions_mut().remove::<SpanData>() {
+                data.end = now_nanos();
+                self.exporter.push(data);
+            }
+        }
+    }
+}
+
+/// Drops every span. Without any subscriber, `tracing` would turn spans
+/// into log lines.
+pub fn disable() {
+    let _ = tracing::subscriber::set_global_default(tracing::subscriber::NoSubscriber::default());
+}
+
+/// Installs the span exporter if `--otlp-endpoint` is set. Without it spans
+/// are never recorded and cost next to nothing.
+pub fn init(opts: &Options) -> Option<Arc<Exporter>> {
+    let endpoint = match opts.otlp_endpoint.as_deref() {
+        Some(endpoint) => endpoint,
+        None => {
+            disable();
+            return None
+        },
+    };
+    let exporter = Arc::new(Exporter::new(endpoint, &opts.otlp_service_name));
+    let subscriber = tracing_subscriber::registry().with(OtlpLayer::new(exporter.clone()));
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        warn!("Not exporting spans: {}", e);
+        return None
+    }
+    Some(exporter)
+}
+
+/// The span every other span of a request is nested in. It continues the
+/// trace of the `traceparent` header, if the client sent one.
+pub fn request_span(req: &ServiceRequest, route: &str, request_id: &str) -> Span {
+    let traceparent = req.headers().get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!("request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        traceparent,
+        http.method = %req.method(),
+        http.route = route,
+        http.status_code = tracing::field::Empty,
+        request_id,
+    )
+}
+
+/// Records the outcome of a request on its span
+pub fn finish_request(span: &Span, status: u16) {
+    span.record("http.status_code", status);
+    if status >= 500 {
+        span.record("otel.status_code", "error");
+    }
+}
+
+/// Milliseconds, for recording durations as span fields
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::stand_in;
+
+    #[test]
+    fn it_parses_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        assert_eq!(TraceContext::parse(header), Some(TraceContext {
+            trace_id: String::from("4bf92f3577b34da6a3ce929d0e0e4736"),
+            span_id: String::from("00f067aa0ba902b7"),
+        }));
+        assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra").is_some());
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[actix_web::test]
+    async fn it_exports_spans_in_the_callers_trace() {
+        let (url, received) = stand_in::recorder::<Value>("/v1/traces");
+        let exporter = Arc::new(Exporter::new(&url, "blobert-test"));
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer::new(exporter.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", otel.kind = "server",
+                traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+            let _entered = request.enter();
+            tracing::info_span!("blob.write", bytes = 42u64).in_scope(|| ());
+        });
+        exporter.flush().await.unwrap();
+
+        let requests = received.lock().unwrap();
+        let spans = &requests[0]["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        let (write, request) = (&spans[0], &spans[1]);
+        assert_eq!(write["name"], "blob.write");
+        assert_eq!(write["attributes"][0], json!({ "key": "bytes", "value": { "intValue": "42" } }));
+        assert_eq!(write["parentSpanId"], request["spanId"]);
+        assert_eq!(request["kind"], KIND_SERVER);
+        assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+        for span in [write, request] {
+            assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        }
+    }
+}
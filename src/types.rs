@@ -0,0 +1,376 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+use sha2::Digest as _;
+use uuid::Uuid;
+
+use crate::error;
+use crate::error::RegistryError;
+
+/// Hash functions we accept in digests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "sha256" => Some(Algorithm::Sha256),
+            "sha512" => Some(Algorithm::Sha512),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Length of the hex encoded hash
+    fn encoded_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+/// A content digest such as `sha256:<hex>`. Only lowercase hex of the right
+/// length is accepted, so a digest is always safe to use as a file name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Digest(String);
+
+impl Digest {
+    pub fn parse(digest: &str) -> Result<Digest, RegistryError> {
+        let invalid = || RegistryError::from(error::DIGEST_INVALID)
+            .with_detail(serde_json::json!({ "digest": digest }));
+        let (algorithm, encoded) = digest.split_once(':').ok_or_else(invalid)?;
+        let algorithm = Algorithm::from_name(algorithm).ok_or_else(invalid)?;
+        if encoded.len() != algorithm.encoded_len()
+            || !encoded.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+            return Err(invalid())
+        }
+        Ok(Digest(digest.to_string()))
+    }
+
+    /// Computes the digest of some content
+    pub fn of(algorithm: Algorithm, data: &[u8]) -> Digest {
+        let mut hasher = Hasher::new(algorithm);
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    pub fn sha256(data: &[u8]) -> Digest {
+        Digest::of(Algorithm::Sha256, data)
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        // Checked when parsing
+        match self.0.starts_with("sha512:") {
+            true => Algorithm::Sha512,
+            false => Algorithm::Sha256,
+        }
+    }
+
+    /// The hex encoded hash, without the algorithm
+    pub fn encoded(&self) -> &str {
+        &self.0[self.algorithm().name().len() + 1..]
+    }
+
+    /// Whether the content hashes to this digest
+    pub fn verify(&self, data: &[u8]) -> bool {
+        Digest::of(self.algorithm(), data) == *self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Computes a digest of content that arrives in pieces
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Hasher {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Digest {
+        match self {
+            Hasher::Sha256(h) => Digest(format!("sha256:{:x}", h.finalize())),
+            Hasher::Sha512(h) => Digest(format!("sha512:{:x}", h.finalize())),
+        }
+    }
+}
+
+/// Initial SHA-256 state, from FIPS 180-4
+const SHA256_INIT: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Initial SHA-512 state, from FIPS 180-4
+const SHA512_INIT: [u64; 8] = [
+    0x6a09e667f3bcc908, 0xbb67ae8584caa73b, 0x3c6ef372fe94f82b, 0xa54ff53a5f1d36f1,
+    0x510e527fade682d1, 0x9b05688c2b3e6c1f, 0x1f83d9abfb41bd6b, 0x5be0cd19137e2179,
+];
+
+/// Defines a SHA-2 hasher whose state can be saved and restored, so the hash
+/// of an upload survives a restart. `sha2` doesn't expose the state of its
+/// hashers, only the compression functions.
+macro_rules! resumable_sha2 {
+    ($name:ident, $algorithm:literal, $word:ty, $init:expr, $compress:path, $block:literal, $length:ty) => {
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        pub struct $name {
+            state: [$word; 8],
+            /// Input not yet making up a whole block
+            buffer: Vec<u8>,
+            length: u64,
+        }
+
+        impl Default for $name {
+            fn default() -> $name {
+                $name { state: $init, buffer: Vec::with_capacity($block), length: 0 }
+            }
+        }
+
+        impl $name {
+            fn compress(&mut self, blocks: &[u8]) {
+                for block in blocks.chunks_exact($block) {
+                    $compress(&mut self.state, &[*sha2::digest::generic_array::GenericArray::from_slice(block)]);
+                }
+            }
+
+            pub fn update(&mut self, mut data: &[u8]) {
+                self.length += data.len() as u64;
+                if !self.buffer.is_empty() {
+                    let take = data.len().min($block - self.buffer.len());
+                    self.buffer.extend_from_slice(&data[..take]);
+                    data = &data[take..];
+                    if self.buffer.len() < $block {
+                        return
+                    }
+                    let block = std::mem::take(&mut self.buffer);
+                    self.compress(&block);
+                }
+                let (blocks, rest) = data.split_at(data.len() - data.len() % $block);
+                self.compress(blocks);
+                self.buffer.extend_from_slice(rest);
+            }
+
+            pub fn finish(mut self) -> Digest {
+                let bits = (self.length as $length).wrapping_mul(8).to_be_bytes();
+                let mut tail = std::mem::take(&mut self.buffer);
+                tail.push(0x80);
+                while tail.len() % $block != $block - bits.len() {
+                    tail.push(0);
+                }
+                tail.extend_from_slice(&bits);
+                self.compress(&tail);
+                let hash: Vec<u8> = self.state.iter().flat_map(|word| word.to_be_bytes()).collect();
+                Digest(format!("{}:{}", $algorithm, hex::encode(hash)))
+            }
+        }
+    };
+}
+
+resumable_sha2!(ResumableSha256, "sha256", u32, SHA256_INIT, sha2::compress256, 64, u64);
+resumable_sha2!(ResumableSha512, "sha512", u64, SHA512_INIT, sha2::compress512, 128, u128);
+
+/// A repository name made of `[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*` components
+/// joined by `/`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RepositoryName(String);
+
+impl RepositoryName {
+    pub fn parse(name: &str) -> Result<RepositoryName, RegistryError> {
+        let alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+        let component = |c: &str| c.starts_with(alnum) && c.ends_with(alnum)
+            && c.split(alnum).filter(|sep| !sep.is_empty())
+                .all(|sep| sep == "." || sep == "_" || sep == "__" || sep.chars().all(|c| c == '-'));
+        if name.len() > 255 || !name.split('/').all(component) {
+            return Err(RegistryError::from(error::NAME_INVALID)
+                .with_detail(serde_json::json!({ "name": name })))
+        }
+        Ok(RepositoryName(name.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Names a manifest either by tag or by digest
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Reference {
+    /// `[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}`
+    Tag(String),
+    Digest(Digest),
+}
+
+impl Reference {
+    pub fn parse(reference: &str) -> Result<Reference, RegistryError> {
+        if reference.contains(':') {
+            return Digest::parse(reference).map(Reference::Digest)
+        }
+        let tag = reference.len() <= 128
+            && reference.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            && reference.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+        if !tag {
+            return Err(RegistryError::from(error::MANIFEST_INVALID)
+                .with_detail(serde_json::json!({ "reference": reference })))
+        }
+        Ok(Reference::Tag(reference.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Reference::Tag(tag) => tag,
+            Reference::Digest(digest) => digest.as_str(),
+        }
+    }
+}
+
+/// Identifies an upload session. We only ever hand out UUIDs, so anything
+/// else can't be a session of ours.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UploadId(String);
+
+impl UploadId {
+    pub fn new() -> UploadId {
+        UploadId(Uuid::new_v4().to_string())
+    }
+
+    pub fn parse(id: &str) -> Result<UploadId, RegistryError> {
+        match Uuid::parse_str(id) {
+            Ok(uuid) => Ok(UploadId(uuid.to_string())),
+            Err(e) => Err(RegistryError::from_err(error::BLOB_UPLOAD_UNKNOWN, Box::new(e)))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+macro_rules! string_type {
+    ($($t:ty),*) => {$(
+        impl FromStr for $t {
+            type Err = RegistryError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                <$t>::parse(s)
+            }
+        }
+
+        impl fmt::Display for $t {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl AsRef<str> for $t {
+            fn as_ref(&self) -> &str {
+                self.as_str()
+            }
+        }
+    )*}
+}
+
+string_type!(Digest, RepositoryName, Reference, UploadId);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_resumes_sha256() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for split in [0, 1, 63, 64, 65, 500, 1000] {
+            let mut hasher = ResumableSha256::default();
+            hasher.update(&data[..split]);
+            let saved = serde_json::to_string(&hasher).unwrap();
+            let mut hasher: ResumableSha256 = serde_json::from_str(&saved).unwrap();
+            hasher.update(&data[split..]);
+            assert_eq!(hasher.finish(), Digest::sha256(&data), "split at {}", split);
+        }
+        assert_eq!(ResumableSha256::default().finish(), Digest::sha256(b""));
+    }
+
+    #[test]
+    fn it_resumes_sha512() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for split in [0, 1, 111, 112, 127, 128, 129, 500, 1000] {
+            let mut hasher = ResumableSha512::default();
+            hasher.update(&data[..split]);
+            let saved = serde_json::to_string(&hasher).unwrap();
+            let mut hasher: ResumableSha512 = serde_json::from_str(&saved).unwrap();
+            hasher.update(&data[split..]);
+            assert_eq!(hasher.finish(), Digest::of(Algorithm::Sha512, &data), "split at {}", split);
+        }
+        assert_eq!(ResumableSha512::default().finish(), Digest::of(Algorithm::Sha512, b""));
+    }
+
+    #[test]
+    fn it_computes_digests() {
+        assert_eq!(Digest::sha256("thisisatest\n".as_bytes()).as_str(),
+            "sha256:f4bb45533d30329f994c4462bb9d3662881836931ffdf4a418a4339e5a4d57ac");
+        let sha512 = Digest::of(Algorithm::Sha512, b"");
+        assert_eq!(sha512.as_str().len(), "sha512:".len() + 128);
+        assert_eq!(Digest::parse(sha512.as_str()).unwrap().algorithm(), Algorithm::Sha512);
+        assert!(sha512.verify(b""));
+        assert!(!sha512.verify(b"tampered"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_digests() {
+        let valid = Digest::sha256(b"").to_string();
+        for digest in ["", "sha256", "sha256:", ":abc", "md5:d41d8cd98f00b204e9800998ecf8427e",
+            &valid.to_uppercase(), &valid[..valid.len() - 1], "sha256:../../../../etc/passwd"] {
+            assert!(Digest::parse(digest).is_err(), "{}", digest);
+        }
+    }
+
+    #[test]
+    fn it_validates_repository_names() {
+        for name in ["library/ubuntu", "a", "my-org/my__repo", "a.b", "x--y"] {
+            assert!(RepositoryName::parse(name).is_ok(), "{}", name);
+        }
+        for name in ["", "Upper", "a/", "-a", "a..b", "a\0", "../etc", "..%2F.."] {
+            assert!(RepositoryName::parse(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn it_parses_references() {
+        assert_eq!(Reference::parse("v1.0_rc-1").unwrap(), Reference::Tag(String::from("v1.0_rc-1")));
+        let digest = Digest::sha256(b"");
+        assert_eq!(Reference::parse(digest.as_str()).unwrap(), Reference::Digest(digest));
+        assert!(Reference::parse(".hidden").is_err());
+        assert!(Reference::parse("..").is_err());
+    }
+
+    #[test]
+    fn it_only_accepts_uuid_upload_ids() {
+        let id = UploadId::new();
+        assert_eq!(UploadId::parse(id.as_str()).unwrap(), id);
+        assert!(UploadId::parse("..").is_err());
+    }
+}
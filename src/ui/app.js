@@ -0,0 +1,111 @@
+// Browses repositories and tags through /ui/api. Pages are picked by the
+// URL fragment: #/ lists repositories, #/r/<name> the tags of one.
+"use strict";
+
+const main = document.getElementById("main");
+
+// Builds an element. Text always goes in as text, never as HTML, as tags and
+// annotations are whatever clients pushed.
+function el(name, attrs, ...children) {
+  const node = document.createElement(name);
+  for (const [key, value] of Object.entries(attrs || {})) {
+    if (key.startsWith("on")) {
+      node.addEventListener(key.slice(2), value);
+    } else {
+      node.setAttribute(key, value);
+    }
+  }
+  for (const child of children.flat()) {
+    if (child !== null && child !== undefined) {
+      node.append(child instanceof Node ? child : String(child));
+    }
+  }
+  return node;
+}
+
+function human(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit++;
+  }
+  return `${bytes.toFixed(unit ? 1 : 0)} ${units[unit]}`;
+}
+
+async function api(path, options) {
+  const res = await fetch(`/ui/api/${path}`, options);
+  if (!res.ok) {
+    const body = await res.json().catch(() => ({}));
+    const error = (body.errors || [])[0] || {};
+    throw new Error(error.detail && error.detail.reason || error.message || res.statusText);
+  }
+  return res.status === 202 ? null : res.json();
+}
+
+function show(...nodes) {
+  main.replaceChildren(...nodes);
+}
+
+async function repositories() {
+  const list = await api("repositories");
+  const rows = el("tbody");
+  const filter = el("input", { type: "search", placeholder: "Filter repositories", autofocus: "" });
+  const render = () => rows.replaceChildren(...list
+    .filter((repo) => repo.name.includes(filter.value))
+    .map((repo) => el("tr", {},
+      el("td", {}, el("a", { href: `#/r/${encodeURIComponent(repo.name)}` }, repo.name)),
+      el("td", {}, repo.tags))));
+  filter.addEventListener("input", render);
+  render();
+  show(el("h2", {}, "Repositories"), filter,
+    el("table", {}, el("thead", {}, el("tr", {}, el("th", {}, "Name"), el("th", {}, "Tags"))), rows));
+}
+
+async function repository(name) {
+  const repo = await api(`repositories/${name}`);
+  const remove = async (tag) => {
+    if (!confirm(`Delete ${repo.name}:${tag}?`)) {
+      return;
+    }
+    try {
+      await api(`repositories/${repo.name}/tags/${encodeURIComponent(tag)}`, { method: "DELETE" });
+      route();
+    } catch (e) {
+      alert(`Deleting ${tag} failed: ${e.message}`);
+    }
+  };
+  const rows = repo.tags.map((tag) => el("tr", {},
+    el("td", {}, tag.tag),
+    el("td", {}, el("code", { title: tag.digest }, tag.digest.slice(0, 19))),
+    el("td", {}, tag.platforms.length ? tag.platforms.join(", ") : el("span", { class: "muted" }, "unknown")),
+    el("td", {}, human(tag.size), el("span", { class: "muted" }, ` in ${tag.layers} layers`)),
+    el("td", {}, tag.pushed || el("span", { class: "muted" }, "unknown")),
+    el("td", {}, tag.created || el("span", { class: "muted" }, "unknown")),
+    el("td", {}, Object.entries(tag.annotations).map(([key, value]) =>
+      el("span", { class: "annotation" }, `${key}=${value}`))),
+    el("td", {},
+      tag.index ? null : el("a", { href: `/admin/images/${repo.name}/${tag.digest}/config` }, "config"),
+      repo.can_delete ? [" ", el("button", { onclick: () => remove(tag.tag) }, "Delete")] : null)));
+  const headings = ["Tag", "Digest", "Platform", "Size", "Pushed", "Created", "Annotations", ""];
+  show(el("h2", {}, repo.name),
+    rows.length ? el("table", {},
+      el("thead", {}, el("tr", {}, headings.map((h) => el("th", {}, h)))),
+      el("tbody", {}, rows)) : el("p", { class: "muted" }, "No tags"));
+}
+
+async function route() {
+  const hash = location.hash.replace(/^#/, "") || "/";
+  try {
+    if (hash.startsWith("/r/")) {
+      await repository(decodeURIComponent(hash.slice(3)));
+    } else {
+      await repositories();
+    }
+  } catch (e) {
+    show(el("p", { class: "error" }, e.message));
+  }
+}
+
+window.addEventListener("hashchange", route);
+route();
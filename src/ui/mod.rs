@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+
+use actix_web::{HttpRequest, HttpResponse};
+use log::warn;
+use serde::Serialize;
+
+use crate::Blobert;
+use crate::error::{self, RegistryError, NAME_UNKNOWN};
+use crate::inspect::ImageConfig;
+use crate::manifests;
+use crate::types::{Reference, RepositoryName};
+use crate::util::{block, parse_param};
+
+/// The page and script are built into the binary, so the UI needs no files
+/// next to it
+const INDEX_HTML: &str = include_str!("index.html");
+const APP_JS: &str = include_str!("app.js");
+
+#[derive(Serialize)]
+struct RepositorySummary {
+    name: String,
+    tags: usize,
+}
+
+#[derive(Serialize)]
+struct TagSummary {
+    tag: String,
+    digest: String,
+    media_type: Option<String>,
+    /// Whether the tag points at an image index rather than an image
+    index: bool,
+    /// Config and layers, as the manifest states them. For an index, those
+    /// of every image it lists.
+    size: u64,
+    layers: usize,
+    /// When the tag was last pushed or moved, if the audit log knows
+    pushed: Option<String>,
+    created: Option<String>,
+    /// The platform of an image, or those an index lists images for
+    platforms: Vec<String>,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct RepositoryDetail {
+    name: String,
+    can_delete: bool,
+    tags: Vec<TagSummary>,
+}
+
+pub async fn redirect() -> HttpResponse {
+    HttpResponse::PermanentRedirect().append_header(("Location", "/ui/")).finish()
+}
+
+pub async fn index() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(INDEX_HTML)
+}
+
+pub async fn script() -> HttpResponse {
+    HttpResponse::Ok().content_type("text/javascript; charset=utf-8").body(APP_JS)
+}
+
+/// Every repository and how many tags it has
+pub async fn repositories(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?.clone();
+    let repositories = block(move || {
+        let mut repositories = Vec::new();
+        for name in blobert.meta_store.list_repositories()? {
+            let tags = RepositoryName::parse(&name)
+                .and_then(|repository| blobert.meta_store.list_tags(&repository))
+                .map(|tags| tags.len())
+                .unwrap_or_default();
+            repositories.push(RepositorySummary { name, tags });
+        }
+        Ok(repositories)
+    }).await?;
+    Ok(HttpResponse::Ok().json(repositories))
+}
+
+/// The tags of a repository and what the images they point to are
+pub async fn repository(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?.clone();
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    // Reads every tagged manifest and its config
+    let detail = block(move || describe(&blobert, &namespace)).await?;
+    Ok(HttpResponse::Ok().json(detail))
+}
+
+fn describe(blobert: &Blobert, namespace: &RepositoryName) -> Result<RepositoryDetail, RegistryError> {
+    let names = blobert.meta_store.list_tags(namespace)?;
+    if names.is_empty() && !blobert.meta_store.list_repositories()?.iter().any(|r| r == namespace.as_str()) {
+        return Err(RegistryError::from(NAME_UNKNOWN))
+    }
+    let pushed = blobert.audit.last_changes(namespace.as_str())
+        .inspect_err(|e| warn!("Can't read push times from the audit log: {}", e))
+        .unwrap_or_default();
+    let mut tags = Vec::new();
+    for tag in names {
+        let raw = match Reference::parse(&tag).and_then(|r| blobert.meta_store.get_manifest(namespace, &r)) {
+            Ok(raw) => raw,
+            Err(e) => {
+                warn!("Skipping tag {}:{}: {}", namespace, tag, e);
+                continue
+            },
+        };
+        let manifest = raw.manifest();
+        // Artifacts such as signatures have configs that aren't image configs
+        let config = ImageConfig::read(blobert, manifest).ok();
+        let mut images = vec![manifest.clone()];
+        let mut platforms: Vec<String> = config.as_ref().and_then(|c| c.platform()).into_iter().collect();
+        if let Some(children) = &manifest.manifests {
+            platforms = children.iter().filter_map(|d| d.platform.as_ref()).map(|p| p.to_string()).collect();
+            images = manifest.children()
+                .filter_map(|digest| blobert.meta_store.get_manifest(namespace, &Reference::Digest(digest)).ok())
+                .map(|child| child.manifest().clone())
+                .collect();
+        }
+        tags.push(TagSummary {
+            digest: raw.digest().to_string(),
+            media_type: Some(raw.media_type().to_string()),
+            index: manifest.manifests.is_some(),
+            size: images.iter().flat_map(|m| m.descriptors()).map(|d| d.size.unwrap_or(0).max(0) as u64).sum(),
+            layers: images.iter().map(|m| m.layers.len()).sum(),
+            pushed: pushed.get(&tag).cloned(),
+            created: config.as_ref().and_then(|c| c.created()).map(String::from),
+            platforms,
+            annotations: manifest.annotations.clone().unwrap_or_default(),
+            tag,
+        });
+    }
+    Ok(RepositoryDetail {
+        name: namespace.to_string(),
+        can_delete: blobert.opts.ui_allow_delete,
+        tags,
+    })
+}
+
+/// Deletes a tag like the registry API does, if `--ui-allow-delete` is set.
+/// Manifests can't be deleted by digest here.
+pub async fn delete_tag(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    if !blobert.opts.ui_allow_delete {
+        return Err(RegistryError::from(error::DENIED)
+            .with_detail(serde_json::json!({ "reason": "deleting tags from the web UI is turned off" })))
+    }
+    if let Reference::Digest(_) = parse_param::<Reference>(&req, "reference")? {
+        return Err(RegistryError::from(error::UNSUPPORTED)
+            .with_detail(serde_json::json!({ "reason": "only tags can be deleted from the web UI" })))
+    }
+    manifests::delete_manifest(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::meta::{Descriptor, Manifest, Platform, RawManifest, IMAGE_INDEX_MEDIA_TYPE};
+    use crate::types::{Digest, UploadId};
+    use crate::util::instance;
+    use crate::{routes, Blobert};
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::App;
+
+    fn blobert(allow_delete: bool) -> Blobert {
+        let blobert = instance::blobert(if allow_delete { &["--ui-allow-delete"] } else { &[] });
+        let config = br#"{"os":"linux","architecture":"arm64","variant":"v8"}"#;
+        let id = UploadId::new();
+        let mut writer = blobert.blob_store.upload_writer(&id).unwrap();
+        writer.write(config).unwrap();
+        writer.finish().unwrap();
+        blobert.blob_store.commit(&id, &Digest::sha256(config)).unwrap();
+        let manifest = Manifest {
+            config: Some(Descriptor { digest: Digest::sha256(config).to_string(), size: Some(config.len() as i64),
+                ..Descriptor::default() }),
+            annotations: Some([(String::from("team"), String::from("a"))].into()),
+            ..Manifest::default()
+        };
+        let app = "app".parse().unwrap();
+        blobert.meta_store.put_manifest(&app, &"v1".parse().unwrap(), &RawManifest::from(&manifest)).unwrap();
+        blobert
+    }
+
+    #[actix_web::test]
+    async fn it_describes_repositories_and_tags() {
+        let blobert = blobert(false);
+        let image = blobert.meta_store.get_manifest(&"app".parse().unwrap(), &"v1".parse().unwrap()).unwrap();
+        blobert.meta_store.put_manifest(&"team/app".parse().unwrap(), &"v2".parse().unwrap(), &image).unwrap();
+        let app = init_service(App::new().app_data(blobert).configure(routes)).await;
+        let res = call_service(&app, TestRequest::get().uri("/ui/").to_request()).await;
+        assert!(String::from_utf8(read_body(res).await.to_vec()).unwrap().contains("app.js"));
+
+        let res = call_service(&app, TestRequest::get().uri("/ui/api/repositories").to_request()).await;
+        let repositories: serde_json::Value = read_body_json(res).await;
+        assert_eq!(repositories, serde_json::json!([{ "name": "app", "tags": 1 }, { "name": "team/app", "tags": 1 }]));
+
+        // Nested names are sent with their slashes, as the UI builds paths
+        let res = call_service(&app, TestRequest::get().uri("/ui/api/repositories/team/app").to_request()).await;
+        let repository: serde_json::Value = read_body_json(res).await;
+        assert_eq!(repository["name"], "team/app");
+        assert_eq!(repository["tags"][0]["tag"], "v2");
+        let config = format!("/admin/images/team/app/{}/config", image.digest());
+        assert_eq!(call_service(&app, TestRequest::get().uri(&config).to_request()).await.status(), 200);
+
+        let res = call_service(&app, TestRequest::get().uri("/ui/api/repositories/app").to_request()).await;
+        let repository: serde_json::Value = read_body_json(res).await;
+        assert_eq!(repository["can_delete"], false);
+        assert_eq!(repository["tags"][0]["platforms"], serde_json::json!(["linux/arm64/v8"]));
+        assert_eq!(repository["tags"][0]["annotations"]["team"], "a");
+
+        let res = call_service(&app, TestRequest::get().uri("/ui/api/repositories/nope").to_request()).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn it_lists_the_platforms_of_indexes() {
+        let blobert = blobert(false);
+        let app = "app".parse().unwrap();
+        let image = blobert.meta_store.get_manifest(&app, &"v1".parse().unwrap()).unwrap();
+        let platform = |architecture: &str| Some(Platform {
+            architecture: architecture.to_string(),
+            os: String::from("linux"),
+            variant: None,
+        });
+        let child = |platform| Descriptor { digest: image.digest().to_string(), platform, ..Descriptor::default() };
+        let index = Manifest {
+            media_type: Some(String::from(IMAGE_INDEX_MEDIA_TYPE)),
+            manifests: Some(vec![child(platform("amd64")), child(platform("arm64"))]),
+            ..Manifest::default()
+        };
+        blobert.meta_store.put_manifest(&app, &"multi".parse().unwrap(), &RawManifest::from(&index)).unwrap();
+
+        let app = init_service(App::new().app_data(blobert).configure(routes)).await;
+        let res = call_service(&app, TestRequest::get().uri("/ui/api/repositories/app").to_request()).await;
+        let repository: serde_json::Value = read_body_json(res).await;
+        let multi = &repository["tags"][0];
+        assert_eq!(multi["tag"], "multi");
+        assert_eq!(multi["index"], true);
+        assert_eq!(multi["platforms"], serde_json::json!(["linux/amd64", "linux/arm64"]));
+        assert_eq!(multi["media_type"], IMAGE_INDEX_MEDIA_TYPE);
+        assert_eq!(repository["tags"][1]["index"], false);
+    }
+
+    #[actix_web::test]
+    async fn it_deletes_tags_only_when_allowed() {
+        let app = init_service(App::new().app_data(blobert(false)).configure(routes)).await;
+        let delete = || TestRequest::delete().uri("/ui/api/repositories/app/tags/v1").to_request();
+        assert_eq!(call_service(&app, delete()).await.status(), 403);
+
+        let app = init_service(App::new().app_data(blobert(true)).configure(routes)).await;
+        assert_eq!(call_service(&app, delete()).await.status(), 202);
+        let res = call_service(&app, TestRequest::get().uri("/ui/api/repositories/app").to_request()).await;
+        let repository: serde_json::Value = read_body_json(res).await;
+        assert_eq!(repository["tags"], serde_json::json!([]));
+    }
+}
@@ -1,80 +1,218 @@
 use actix_web::web;
-use actix_web::{Responder, HttpRequest, HttpResponse};
+use actix_web::{HttpRequest, HttpResponse};
+use futures::future::{self, Either};
 use futures::StreamExt;
-use uuid::Uuid;
-use log::{debug, error};
-use std::io::Write;
+use log::debug;
 use serde::Deserialize;
 
+use std::time::{Duration, Instant};
+
 use crate::Blobert;
+use crate::error::{RegistryError, BLOB_UPLOAD_INVALID, DIGEST_INVALID, UNAVAILABLE, UNSUPPORTED};
 use crate::meta;
+use crate::notify::{Action, Target};
+use crate::trace;
+use crate::types::{Digest, RepositoryName, UploadId};
+use crate::util::{block, parse_param};
 
-pub async fn get_blob(req: HttpRequest) -> impl Responder {
-    let blobert: &Blobert = req.app_data().unwrap();
-    let id = req.match_info().get("id").unwrap();
+/// Describes a blob in registry events
+fn blob_target(blobert: &Blobert, namespace: &RepositoryName, digest: &Digest, size: u64) -> Target {
+    Target {
+        media_type: String::from("application/octet-stream"),
+        size: Some(size),
+        length: Some(size),
+        digest: digest.to_string(),
+        repository: namespace.to_string(),
+        url: format!("{}/v2/{}/blobs/{}", blobert.opts.get_server_url(), namespace, digest),
+        tag: None,
+    }
+}
 
-    debug!("Retrieving blob {}", id);
-    let stream = blobert.blob_store.get_blob(id).unwrap();
+/// The single byte range a `Range` header asks for, as start and length.
+/// Headers that can't be parsed or ask for several ranges are ignored, so
+/// the whole blob is sent. `Err` means the range lies outside the blob.
+fn parse_range(header: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let (first, last) = header.trim().strip_prefix("bytes=")?.split_once('-')?;
+    if last.contains(',') {
+        return None
+    }
+    let range = match (first.trim(), last.trim()) {
+        ("", suffix) => match suffix.parse::<u64>().ok()?.min(size) {
+            0 => Err(()),
+            len => Ok((size - len, len)),
+        },
+        (first, last) => {
+            let first = first.parse::<u64>().ok()?;
+            let last = match last {
+                "" => u64::MAX,
+                last => last.parse::<u64>().ok().filter(|last| *last >= first)?,
+            };
+            match first < size {
+                true => Ok((first, last.min(size - 1) - first + 1)),
+                false => Err(()),
+            }
+        },
+    };
+    Some(range)
+}
 
-    HttpResponse::Ok()
+#[tracing::instrument(skip_all)]
+pub async fn get_blob(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let digest: Digest = parse_param(&req, "id")?;
+
+    debug!("Retrieving blob {}", digest);
+    let (store, blob) = (blobert.blob_store.clone(), digest.clone());
+    let info = block(move || store.stat_blob(&blob)).await?;
+    if let Some(url) = blobert.blob_store.redirect_url(&digest) {
+        return Ok(HttpResponse::TemporaryRedirect()
+            .append_header(("Location", url))
+            .append_header(("Docker-Content-Digest", digest.as_str()))
+            .finish())
+    }
+    let range = req.headers().get("Range")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| parse_range(h, info.size));
+    let (store, blob) = (blobert.blob_store.clone(), digest.clone());
+    let (mut response, stream, len) = match range {
+        None => (HttpResponse::Ok(), block(move || store.get_blob(&blob)).await?, info.size),
+        Some(Ok((start, len))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.append_header(("Content-Range", format!("bytes {}-{}/{}", start, start + len - 1, info.size)));
+            (response, block(move || store.get_blob_range(&blob, start, len)).await?, len)
+        },
+        Some(Err(())) => return Ok(HttpResponse::RangeNotSatisfiable()
+            .append_header(("Content-Range", format!("bytes */{}", info.size)))
+            .finish()),
+    };
+    blobert.metrics.pulled(len);
+    blobert.notifier.notify(&req, Action::Pull, blob_target(blobert, &namespace, &digest, info.size));
+
+    Ok(response
         .append_header(("Content-Type", meta::IMAGE_LAYER_MEDIA_TYPE))
-        .append_header(("Docker-Content-Digest", id.clone()))
-        .streaming(stream)
+        .append_header(("Docker-Content-Digest", digest.as_str()))
+        .append_header(("Accept-Ranges", "bytes"))
+        .no_chunking(len)
+        .streaming(stream))
 }
 
-pub async fn start_blob_upload(req: HttpRequest) -> impl Responder {
-    let blobert: &Blobert = req.app_data().unwrap();
-    let id = Uuid::new_v4();
-    let namespace = req.match_info().get("namespace").unwrap();
+#[tracing::instrument(skip_all)]
+pub async fn start_blob_upload(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    blobert.shutdown.check_accepting()?;
+    let id = blobert.sessions.start(&namespace);
     let location = format!("{}/v2/{}/blobs/upload/{}", 
             blobert.opts.get_server_url(), namespace, id);
 
-    HttpResponse::Accepted()
+    Ok(HttpResponse::Accepted()
         .append_header(("Location", location))
         .append_header(("Docker-Upload-UUID", id.to_string()))
-        .finish()
+        .finish())
 }
 
-pub async fn patch_blob_data(req: HttpRequest, mut payload: web::Payload) -> impl Responder {
-    let blobert: &Blobert = req.app_data().unwrap();
-    let namespace = req.match_info().get("namespace").unwrap();
-    let id = req.match_info().get("id").unwrap();
+#[tracing::instrument(skip_all, fields(bytes, receive_ms, hash_ms, write_ms))]
+pub async fn patch_blob_data(req: HttpRequest, mut payload: web::Payload) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let id: UploadId = parse_param(&req, "id")?;
+    let offset = blobert.sessions.progress(&namespace, &id)?;
 
-    let mut blobfile = match blobert.blob_store.get_upload_file(id) {
-        Ok(f) => f,
-        Err(e) => {
-            error!("Error getting upload file: {}", e);
-            return HttpResponse::InternalServerError().finish()
-        },
+    // Where the time goes, to tell slow clients from slow disks
+    let (mut receiving, mut hashing, mut writing) = (Duration::ZERO, Duration::ZERO, Duration::ZERO);
+    let mut bytes = 0;
+    let store = blobert.blob_store.clone();
+    let mut upload = {
+        let (store, id) = (store.clone(), id.clone());
+        block(move || store.upload_writer(&id)).await?
     };
-
-    let mut written: usize = 0;
-
-    while let Some(chunk) = payload.next().await {
-        match chunk {
-            Ok(chunk) => {
-                match blobfile.write(&chunk) {
-                    Ok(size) => written += size,
-                    Err(e) => {
-                        error!("Error writing upload file: {}", e);
-                        return HttpResponse::InternalServerError().finish()
-                    }
-                }
+    let mut expired = blobert.shutdown.expired();
+    let mut interrupted = false;
+    let mut failed = None;
+    let mut waiting = Instant::now();
+    loop {
+        let chunk = match future::select(payload.next(), &mut expired).await {
+            Either::Left((Some(chunk), _)) => chunk,
+            Either::Left((None, _)) => break,
+            // Out of time before shutting down, keep what we have so the
+            // client can resume after the restart
+            Either::Right(_) => {
+                interrupted = true;
+                break
             },
+        };
+        receiving += waiting.elapsed();
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
             Err(e) => {
-                error!("Error getting chunk: {}", e);
-                return HttpResponse::InternalServerError().finish()
-            }
+                failed = Some(RegistryError::from_err(BLOB_UPLOAD_INVALID, Box::new(e)));
+                break
+            },
+        };
+        let len = chunk.len() as u64;
+        if let Err(e) = blobert.limits.check_blob_size(offset + bytes + len) {
+            blobert.sessions.finish(&namespace, &id)?;
+            let (store, id) = (store.clone(), id.clone());
+            block(move || store.cancel_upload(&id)).await?;
+            return Err(e)
         }
+        // The session only counts data the store took, so a failed write
+        // can be resumed from where the store stopped
+        let started = Instant::now();
+        let data = chunk.clone();
+        let (writer, wrote) = block(move || {
+            let wrote = upload.write(&data);
+            Ok((upload, wrote))
+        }).await?;
+        upload = writer;
+        writing += started.elapsed();
+        if let Err(e) = wrote {
+            failed = Some(e);
+            break
+        }
+        let started = Instant::now();
+        blobert.sessions.received(&id, &chunk)?;
+        hashing += started.elapsed();
+        bytes += len;
+        blobert.metrics.pushed(len);
+        waiting = Instant::now();
+    }
+    // What the store took is persisted however the PATCH ended, so it stays
+    // in step with the session and the client can resume from there
+    let written = match block(move || upload.finish()).await {
+        Ok(written) => written,
+        Err(e) => {
+            // The store may no longer hold what the session counted
+            blobert.sessions.finish(&namespace, &id)?;
+            let (store, id) = (store.clone(), id.clone());
+            block(move || store.cancel_upload(&id)).await?;
+            return Err(e)
+        },
+    };
+    let span = tracing::Span::current();
+    span.record("bytes", bytes);
+    span.record("receive_ms", trace::millis(receiving));
+    span.record("hash_ms", trace::millis(hashing));
+    span.record("write_ms", trace::millis(writing));
+    if let Some(e) = failed {
+        return Err(e)
+    }
+    if interrupted {
+        return Err(RegistryError::from(UNAVAILABLE).with_detail(serde_json::json!({
+            "reason": "the registry is shutting down, resume the upload once it is back",
+            "received": written,
+        })))
     }
+
     let location = format!("{}/v2/{}/blobs/upload/{}", 
             blobert.opts.get_server_url(), namespace, id);
-    HttpResponse::Accepted()
+    Ok(HttpResponse::Accepted()
         .append_header(("Location", location))
         .append_header(("Docker-Upload-UUID", id.to_string()))
         .append_header(("Content-Length", "0"))
-        .append_header(("Range", format!("0-{}", written)))
-        .finish()
+        .append_header(("Range", format!("0-{}", written.saturating_sub(1))))
+        .finish())
 }
 
 #[derive(Deserialize)]
@@ -82,29 +220,231 @@ pub struct PutDigest {
     digest: String
 }
 
-pub async fn put_blob_upload_complete(req: HttpRequest, info: web::Query<PutDigest>) -> impl Responder {
-    let blobert: &Blobert = req.app_data().unwrap();
-    let id = req.match_info().get("id").unwrap();
+#[tracing::instrument(skip_all)]
+pub async fn put_blob_upload_complete(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let id: UploadId = parse_param(&req, "id")?;
+    let info = web::Query::<PutDigest>::from_query(req.query_string())
+        .map_err(|e| RegistryError::from_err(DIGEST_INVALID, Box::new(e)))?;
+    let digest = Digest::parse(&info.digest)?;
 
-    match blobert.blob_store.commit(id, &info.digest) {
-        Ok(_) => HttpResponse::Created()
-            .append_header(("Content-Length", "0"))
-            .append_header(("Docker-Content-Digest", info.digest.clone()))
-            .finish(),
-        Err(e) => {
-            error!("Error getting chunk: {}", e);
-            HttpResponse::InternalServerError().finish()
-        }
+    let (size, actual) = blobert.sessions.digest(&namespace, &id, digest.algorithm())?;
+    // Storing data we can't check would let it be fetched under any digest.
+    // The session stays, so the client can still complete it with sha256.
+    let actual = actual.ok_or_else(|| RegistryError::from(UNSUPPORTED).with_detail(serde_json::json!({
+        "reason": format!("the upload was resumed without a {} hash, complete it with sha256", digest.algorithm().name()),
+    })))?;
+    let (store, upload, blob) = (blobert.blob_store.clone(), id.clone(), digest.clone());
+    if actual != digest {
+        blobert.sessions.finish(&namespace, &id)?;
+        block(move || store.cancel_upload(&upload)).await?;
+        return Err(RegistryError::from(DIGEST_INVALID)
+            .with_detail(serde_json::json!({ "digest": digest.as_str(), "received": actual.as_str() })))
     }
+    // The session stays until the blob is stored, so a failed commit can be
+    // retried. The blob is stored even if the session expired meanwhile.
+    block(move || store.commit(&upload, &blob)).await?;
+    blobert.sessions.finish(&namespace, &id).ok();
+    blobert.notifier.notify(&req, Action::Push, blob_target(blobert, &namespace, &digest, size));
+    Ok(HttpResponse::Created()
+        .append_header(("Content-Length", "0"))
+        .append_header(("Docker-Content-Digest", digest.as_str()))
+        .finish())
 }
 
-pub async fn blob_exists(req: HttpRequest) -> impl Responder {
-    let blobert: &Blobert = req.app_data().unwrap();
-    let digest = req.match_info().get("digest").unwrap();
-    match blobert.blob_store.blob_exists(digest) {
-        true => HttpResponse::Ok()
-            .append_header(("Docker-Content-Digest", digest))
-            .finish(),
-        false => HttpResponse::NotFound().finish()
+/// Reports how much of an upload was received, so clients can resume it
+#[tracing::instrument(skip_all)]
+pub async fn get_upload_status(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let id: UploadId = parse_param(&req, "id")?;
+    let received = blobert.sessions.progress(&namespace, &id)?;
+
+    let location = format!("{}/v2/{}/blobs/upload/{}",
+            blobert.opts.get_server_url(), namespace, id);
+    Ok(HttpResponse::NoContent()
+        .append_header(("Location", location))
+        .append_header(("Docker-Upload-UUID", id.to_string()))
+        .append_header(("Range", format!("0-{}", received.saturating_sub(1))))
+        .finish())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn cancel_blob_upload(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let namespace: RepositoryName = parse_param(&req, "namespace")?;
+    let id: UploadId = parse_param(&req, "id")?;
+
+    blobert.sessions.finish(&namespace, &id)?;
+    let store = blobert.blob_store.clone();
+    block(move || store.cancel_upload(&id)).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn blob_exists(req: HttpRequest) -> Result<HttpResponse, RegistryError> {
+    let blobert = Blobert::from_request(&req)?;
+    let digest: Digest = parse_param(&req, "digest")?;
+
+    let (store, blob) = (blobert.blob_store.clone(), digest.clone());
+    let info = block(move || store.stat_blob(&blob)).await?;
+    Ok(HttpResponse::Ok()
+        .append_header(("Docker-Content-Digest", digest.as_str()))
+        .append_header(("Content-Length", info.size.to_string()))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 100))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 100))));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Ok((900, 100))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 100))));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(Ok((0, 1000))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(parse_range("bytes=9-5", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+
+    // The S3 client panics in debug builds when it blocks inside the runtime
+    #[actix_web::test]
+    async fn it_keeps_s3_requests_off_the_workers() {
+        use crate::util::instance;
+        use actix_web::test::{call_service, read_body, TestRequest};
+
+        let endpoint = crate::blob::s3::stand_in::start();
+        let app = instance::app(&["--blob-backend", "s3", "--s3-endpoint", &endpoint,
+            "--s3-access-key", "key", "--s3-secret-key", "secret"]).await;
+
+        let data = b"stored in a bucket";
+        let digest = Digest::sha256(data);
+        let res = call_service(&app, TestRequest::post().uri("/v2/app/blobs/uploads/").to_request()).await;
+        let id = res.headers().get("Docker-Upload-UUID").unwrap().to_str().unwrap().to_string();
+        let res = call_service(&app, TestRequest::patch().uri(&format!("/v2/app/blobs/upload/{}", id))
+            .set_payload(&data[..]).to_request()).await;
+        assert_eq!(res.status(), 202);
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("/v2/app/blobs/upload/{}?digest={}", id, digest)).to_request()).await;
+        assert_eq!(res.status(), 201);
+
+        let blob = format!("/v2/app/blobs/{}", digest);
+        let res = call_service(&app, TestRequest::default().method(actix_web::http::Method::HEAD).uri(&blob).to_request()).await;
+        assert_eq!(res.headers().get("Content-Length").unwrap(), "18");
+        let res = call_service(&app, TestRequest::get().uri(&blob).to_request()).await;
+        assert_eq!(read_body(res).await, &data[..]);
+        let res = call_service(&app, TestRequest::get().uri(&blob)
+            .insert_header(("Range", "bytes=10-")).to_request()).await;
+        assert_eq!(read_body(res).await, &data[10..]);
+    }
+
+    #[actix_web::test]
+    async fn it_resumes_s3_uploads_after_an_aborted_patch() {
+        use crate::blob::s3::MIN_PART_SIZE;
+        use crate::util::instance;
+        use actix_web::dev::Payload;
+        use actix_web::error::PayloadError;
+        use actix_web::test::{call_service, TestRequest};
+        use actix_web::web::Bytes;
+        use futures::Stream;
+        use std::pin::Pin;
+
+        let endpoint = crate::blob::s3::stand_in::start();
+        let app = instance::app(&["--blob-backend", "s3", "--s3-endpoint", &endpoint,
+            "--s3-access-key", "key", "--s3-secret-key", "secret"]).await;
+
+        let data: Vec<u8> = (0..MIN_PART_SIZE * 2).map(|i| (i / 3) as u8).collect();
+        let third = MIN_PART_SIZE * 2 / 3;
+        let res = call_service(&app, TestRequest::post().uri("/v2/app/blobs/uploads/").to_request()).await;
+        let id = res.headers().get("Docker-Upload-UUID").unwrap().to_str().unwrap().to_string();
+        let upload = format!("/v2/app/blobs/upload/{}", id);
+        let res = call_service(&app, TestRequest::patch().uri(&upload)
+            .set_payload(data[..third].to_vec()).to_request()).await;
+        assert_eq!(res.status(), 202);
+
+        // The client goes away after a part was sent
+        let chunks: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(futures::stream::iter([
+            Ok(Bytes::from(data[third..third * 2].to_vec())),
+            Err(PayloadError::Incomplete(None)),
+        ]));
+        let (req, _) = TestRequest::patch().uri(&upload).to_request()
+            .replace_payload(Payload::Stream { payload: chunks });
+        assert!(!call_service(&app, req).await.status().is_success());
+
+        let res = call_service(&app, TestRequest::get().uri(&upload).to_request()).await;
+        assert_eq!(res.headers().get("Range").unwrap().to_str().unwrap(), format!("0-{}", third * 2 - 1));
+        let res = call_service(&app, TestRequest::patch().uri(&upload)
+            .set_payload(data[third * 2..].to_vec()).to_request()).await;
+        assert_eq!(res.status(), 202);
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("{}?digest={}", upload, Digest::sha256(&data))).to_request()).await;
+        assert_eq!(res.status(), 201);
+    }
+
+    #[actix_web::test]
+    async fn it_only_counts_data_the_store_took() {
+        use crate::util::instance;
+        use actix_web::test::{call_service, TestRequest};
+
+        let app = instance::app(&["--memory-limit", "16B"]).await;
+
+        let res = call_service(&app, TestRequest::post().uri("/v2/app/blobs/uploads/").to_request()).await;
+        let id = res.headers().get("Docker-Upload-UUID").unwrap().to_str().unwrap().to_string();
+        let upload = format!("/v2/app/blobs/upload/{}", id);
+        let patch = || TestRequest::patch().uri(&upload).set_payload(&b"0123456789"[..]).to_request();
+        assert_eq!(call_service(&app, patch()).await.status(), 202);
+        assert!(!call_service(&app, patch()).await.status().is_success());
+        let res = call_service(&app, TestRequest::get().uri(&upload).to_request()).await;
+        assert_eq!(res.headers().get("Range").unwrap(), "0-9");
+
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("{}?digest={}", upload, Digest::sha256(b"0123456789"))).to_request()).await;
+        assert_eq!(res.status(), 201);
+        let res = call_service(&app, TestRequest::get().uri(&upload).to_request()).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn it_completes_uploads_with_sha512_digests() {
+        use crate::types::Algorithm;
+        use crate::util::instance;
+        use actix_web::test::{call_service, read_body, TestRequest};
+
+        let app = instance::app(&[]).await;
+
+        let res = call_service(&app, TestRequest::post().uri("/v2/app/blobs/uploads/").to_request()).await;
+        let id = res.headers().get("Docker-Upload-UUID").unwrap().to_str().unwrap().to_string();
+        let upload = format!("/v2/app/blobs/upload/{}", id);
+        for part in [&b"da"[..], &b"ta"[..]] {
+            let res = call_service(&app, TestRequest::patch().uri(&upload).set_payload(part).to_request()).await;
+            assert_eq!(res.status(), 202);
+        }
+
+        let wrong = Digest::of(Algorithm::Sha512, b"other data");
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("{}?digest={}", upload, wrong)).to_request()).await;
+        assert_eq!(res.status(), 400);
+        let res = call_service(&app, TestRequest::default().method(actix_web::http::Method::HEAD)
+            .uri(&format!("/v2/app/blobs/{}", wrong)).to_request()).await;
+        assert_eq!(res.status(), 404);
+
+        let res = call_service(&app, TestRequest::post().uri("/v2/app/blobs/uploads/").to_request()).await;
+        let id = res.headers().get("Docker-Upload-UUID").unwrap().to_str().unwrap().to_string();
+        let upload = format!("/v2/app/blobs/upload/{}", id);
+        let res = call_service(&app, TestRequest::patch().uri(&upload).set_payload(&b"data"[..]).to_request()).await;
+        assert_eq!(res.status(), 202);
+        let digest = Digest::of(Algorithm::Sha512, b"data");
+        let res = call_service(&app, TestRequest::put()
+            .uri(&format!("{}?digest={}", upload, digest)).to_request()).await;
+        assert_eq!(res.status(), 201);
+        assert_eq!(res.headers().get("Docker-Content-Digest").unwrap().to_str().unwrap(), digest.as_str());
+        let res = call_service(&app, TestRequest::get().uri(&format!("/v2/app/blobs/{}", digest)).to_request()).await;
+        assert_eq!(read_body(res).await, &b"data"[..]);
     }
 }
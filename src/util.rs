@@ -1,17 +1,143 @@
-use sha2::Digest;
+use actix_web::{web, HttpRequest};
 
-/// Computes the SHA256 digest of a byte vector
-pub fn sha256_digest(bytes: &[u8]) -> String {
-    format!("sha256:{:x}", sha2::Sha256::digest(bytes))
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error;
+use crate::error::RegistryError;
+
+/// Gets a parameter of the matched route
+pub fn path_param<'a>(req: &'a HttpRequest, name: &str) -> Result<&'a str, RegistryError> {
+    req.match_info().get(name).ok_or_else(|| RegistryError::from_err(error::UNKNOWN_ERROR,
+        format!("route has no {} parameter", name).into()))
+}
+
+/// Gets a parameter of the matched route as one of the validated types
+pub fn parse_param<T: FromStr<Err = RegistryError>>(req: &HttpRequest, name: &str) -> Result<T, RegistryError> {
+    path_param(req, name)?.parse()
 }
 
+/// Seconds since the UNIX epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Locks a mutex even if a thread panicked while holding it. The code that
+/// holds our locks doesn't panic halfway through an update, so what they
+/// guard is still consistent after a panic.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs blocking work, such as calls to the stores, on the thread pool rather
+/// than on the worker. The work stays inside the caller's span.
+pub async fn block<T, F>(f: F) -> Result<T, RegistryError>
+where
+    F: FnOnce() -> Result<T, RegistryError> + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+    web::block(move || span.in_scope(f)).await
+        .map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))?
+}
+
+/// Serializes a value to JSON for a response body
+pub fn to_json<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, RegistryError> {
+    serde_json::to_vec(value).map_err(|e| RegistryError::from_err(error::UNKNOWN_ERROR, Box::new(e)))
+}
+
+/// Formats a UNIX timestamp as an RFC 3339 date in UTC, e.g. 2013-05-24T00:00:00Z
+pub fn rfc3339(secs: u64) -> String {
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let rem = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Stand-in HTTP servers for tests of code that calls other services
+#[cfg(test)]
+pub mod stand_in {
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use serde::de::DeserializeOwned;
+    use std::sync::{Arc, Mutex};
+
+    /// Serves the configured routes on a random port, returning the base URL.
+    /// The server runs on a thread of its own, so tests of blocking clients
+    /// can use it as well as async ones.
+    pub fn serve<F>(configure: F) -> String
+    where
+        F: Fn(&mut web::ServiceConfig) + Send + Clone + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            actix_web::rt::System::new().block_on(async move {
+                HttpServer::new(move || App::new().configure(configure.clone()))
+                    .workers(1)
+                    .listen(listener).unwrap()
+                    .run().await
+            })
+        });
+        url
+    }
+
+    /// Records the JSON body of every request posted to the path
+    pub fn recorder<T: DeserializeOwned + Send + 'static>(path: &'static str) -> (String, Arc<Mutex<Vec<T>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let state = received.clone();
+        let url = serve(move |cfg| {
+            let state = state.clone();
+            cfg.route(path, web::post().to(move |body: web::Bytes| {
+                state.lock().unwrap().push(serde_json::from_slice(&body).unwrap());
+                async { HttpResponse::Ok().finish() }
+            }));
+        });
+        (url, received)
+    }
+}
+
+/// Registries for tests of the handlers
 #[cfg(test)]
-mod tests {
-    use super::*;
+pub mod instance {
+    use crate::{routes, Blobert, Options};
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::test::init_service;
+    use actix_web::App;
+    use structopt::StructOpt;
+
+    /// Options with a data dir of their own and the memory backends, unless
+    /// the extra arguments pick other backends
+    pub fn options(args: &[&str]) -> Options {
+        let data_dir = format!("/tmp/blobert-test/{}", uuid::Uuid::new_v4());
+        let mut all = vec!["blobert", "--data-dir", &data_dir];
+        for flag in ["--meta-backend", "--blob-backend"] {
+            if !args.contains(&flag) {
+                all.extend([flag, "memory"]);
+            }
+        }
+        all.extend(args);
+        Options::from_iter(all)
+    }
+
+    /// A registry built from the test options
+    pub fn blobert(args: &[&str]) -> Blobert {
+        Blobert::new(options(args)).unwrap()
+    }
 
-    #[test]
-    fn test_sha256_digest() {
-        assert_eq!(sha256_digest("thisisatest\n".as_bytes()), 
-            "sha256:f4bb45533d30329f994c4462bb9d3662881836931ffdf4a418a4339e5a4d57ac")
+    /// Serves the routes of a new registry to test requests
+    pub async fn app(args: &[&str]) -> impl Service<actix_http::Request,
+        Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+        init_service(App::new().app_data(blobert(args)).configure(routes)).await
     }
 }